@@ -1,65 +1,319 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
-use std::io::{self, stdout};
-use std::path::PathBuf;
-use std::time::Duration;
+use std::io::{self, stdout, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::thread::sleep;
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
-    execute, terminal,
+    event::{
+        self, DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture, Event, KeyCode,
+        KeyEvent, KeyEventKind, KeyModifiers, MouseButton, MouseEvent, MouseEventKind,
+    },
+    execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
-    style::{Modifier, Style},
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
     text::{Line, Span, Text},
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap},
     Terminal,
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
 struct HostEntry {
     name: String,
     hostname: Option<String>,
     user: Option<String>,
     port: Option<String>,
-    identity_file: Option<String>,
+    /// Every `IdentityFile` line, in order; ssh tries them all.
+    identity_files: Vec<String>,
+    /// Jump host(s) from `ProxyJump`; a chain stays one comma-separated
+    /// string, e.g. `bastion1,bastion2`.
+    proxy_jump: Option<String>,
+    /// `ConnectTimeout` in seconds. When unset, connecting from putty
+    /// passes the `connect_timeout` setting instead.
+    connect_timeout: Option<String>,
+    /// The yes/no directives in `FLAG_DIRECTIVES`. Any other value (a
+    /// ForwardAgent socket path, say) stays in `raw_lines` instead.
+    forward_agent: Option<bool>,
+    forward_x11: Option<bool>,
+    compression: Option<bool>,
     password: Option<String>,
+    /// The password lives in the OS keyring and the config only has
+    /// `KEYRING_MARKER` in its place.
+    password_in_keyring: bool,
+    env: Option<Env>,
+    /// Section the host is listed under, from a `# Group: name` comment.
+    group: Option<String>,
+    /// Admin panel URLs from `# WebUI` comments; `%h`/`%p` expand per host.
+    web_ui: Vec<String>,
+    /// `Key=Value` options from `# SshOption` comments, passed to ssh as
+    /// `-o` when connecting from putty but invisible to plain ssh.
+    ssh_options: Vec<String>,
+    meta: HostMeta,
+    /// The `Include`d file this host was read from; `None` for the main config.
+    source: Option<PathBuf>,
+    /// Lines inside the block that aren't modelled above (other directives,
+    /// comments), kept verbatim so a rewrite doesn't drop them.
+    raw_lines: Vec<String>,
+    /// The whole block is commented out, so OpenSSH ignores it; see
+    /// `disabled_block_at`.
+    disabled: bool,
+    /// End-of-line comments on the Host line and modelled directives,
+    /// keyed by `comment_key`, so `Port 2222  # non-standard` is written
+    /// back with its note. A comment goes once its value changes.
+    trailing_comments: BTreeMap<String, String>,
+    /// A `Match` block rather than a `Host` one: `name` holds its criteria
+    /// and every line under it is in `raw_lines`, written back as is. It's
+    /// listed, but can't be connected to or edited here.
+    match_block: bool,
+}
+
+/// Directives modelled as yes/no switches, as spelled when written.
+const FLAG_DIRECTIVES: &[&str] = &["ForwardAgent", "ForwardX11", "Compression"];
+
+/// `yes`/`no` (any case) as a bool; anything else isn't a plain switch.
+fn parse_yes_no(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value { "yes" } else { "no" }
+}
+
+/// Tool-only metadata with no SSH config equivalent. It is only ever
+/// stored in the companion file, see `load_companion`.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct HostMeta {
+    notes: Option<String>,
+    tags: Vec<String>,
+    /// Any colour name or `#rrggbb` accepted by ratatui; overrides the Env colour.
+    color: Option<String>,
+}
+
+impl HostMeta {
+    fn is_empty(&self) -> bool {
+        *self == HostMeta::default()
+    }
+}
+
+/// Deployment environment from a `# Env` comment. The vocabulary is fixed
+/// so colouring and filtering behave the same across every host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Env {
+    Prod,
+    Staging,
+    Dev,
+}
+
+impl Env {
+    fn parse(value: &str) -> Option<Env> {
+        match value.to_ascii_lowercase().as_str() {
+            "prod" | "production" => Some(Env::Prod),
+            "staging" | "stage" => Some(Env::Staging),
+            "dev" | "development" => Some(Env::Dev),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Env::Prod => "prod",
+            Env::Staging => "staging",
+            Env::Dev => "dev",
+        }
+    }
+
+    /// Steps through prod → staging → dev → unset, used both by the edit
+    /// form and the list filter (where unset means "all").
+    fn cycle(current: Option<Env>) -> Option<Env> {
+        match current {
+            None => Some(Env::Prod),
+            Some(Env::Prod) => Some(Env::Staging),
+            Some(Env::Staging) => Some(Env::Dev),
+            Some(Env::Dev) => None,
+        }
+    }
+}
+
+/// Every style `draw_ui` uses, so the whole UI can suit a dark or a light
+/// terminal background. `T` switches between the two built-in themes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Theme {
+    /// Saved as the `theme` setting.
+    name: &'static str,
+    /// Pane and popup borders.
+    border: Style,
+    /// The highlighted row of a list.
+    selected: Style,
+    /// Group headers and help section titles.
+    heading: Style,
+    /// Field labels, ages and other secondary text.
+    label: Style,
+    /// The flash after a save, reachable hosts.
+    ok: Style,
+    error: Style,
+    warning: Style,
+    /// Disabled hosts and hosts not probed yet.
+    muted: Style,
+    prod: Color,
+    staging: Color,
+    dev: Color,
+}
+
+const DARK_THEME: Theme = Theme {
+    name: "dark",
+    border: Style::new(),
+    selected: Style::new().add_modifier(Modifier::REVERSED),
+    heading: Style::new().add_modifier(Modifier::BOLD),
+    label: Style::new().add_modifier(Modifier::DIM),
+    ok: Style::new().fg(Color::Green),
+    error: Style::new().fg(Color::Red),
+    warning: Style::new().fg(Color::Yellow),
+    muted: Style::new().fg(Color::DarkGray),
+    prod: Color::Red,
+    staging: Color::Yellow,
+    dev: Color::Green,
+};
+
+/// Darker colours that stay readable on white; yellow in particular
+/// all but disappears there.
+const LIGHT_THEME: Theme = Theme {
+    name: "light",
+    border: Style::new().fg(Color::Gray),
+    selected: Style::new().add_modifier(Modifier::REVERSED),
+    heading: Style::new().add_modifier(Modifier::BOLD),
+    label: Style::new().fg(Color::DarkGray),
+    ok: Style::new().fg(Color::Indexed(28)),
+    error: Style::new().fg(Color::Indexed(160)),
+    warning: Style::new().fg(Color::Indexed(130)),
+    muted: Style::new().fg(Color::Gray),
+    prod: Color::Indexed(160),
+    staging: Color::Indexed(130),
+    dev: Color::Indexed(28),
+};
+
+impl Theme {
+    fn named(name: &str) -> Option<Theme> {
+        [DARK_THEME, LIGHT_THEME].into_iter().find(|t| t.name.eq_ignore_ascii_case(name.trim()))
+    }
+
+    fn env_color(&self, env: Env) -> Color {
+        match env {
+            Env::Prod => self.prod,
+            Env::Staging => self.staging,
+            Env::Dev => self.dev,
+        }
+    }
+
+    /// A bordered block with `title`, as every pane and popup uses.
+    fn block<'a>(&self, title: impl Into<Line<'a>>) -> Block<'a> {
+        Block::default().borders(Borders::ALL).border_style(self.border).title(title)
+    }
 }
 
 impl HostEntry {
+    /// Reads the `Host` (and `Match`) blocks of config text, in file order. Pure: no file
+    /// or environment access, so callers do the I/O (`load_config_file`).
+    /// Expects the text after `split_preamble`; lines before the first
+    /// `Host` are dropped. Every line inside a block ends up in a field or
+    /// in `raw_lines`, so `serialize_hosts` can write it back.
     fn parse_ssh_config(file: &str) -> Vec<HostEntry> {
         let mut hosts = Vec::new();
         let mut current: Option<HostEntry> = None;
+        let lines: Vec<&str> = file.lines().collect();
 
-        for line in file.lines() {
+        for (i, &line) in lines.iter().enumerate() {
+            // Inside a disabled block every line is read with its `#` taken off.
+            let disabled = match &current {
+                Some(entry) if entry.disabled => line.starts_with('#'),
+                _ => disabled_block_at(&lines, i),
+            };
+            let line = if disabled { &line[1..] } else { line };
             let trimmed = line.trim();
-            if trimmed.starts_with("Host ") {
+            if !disabled && let Some(criteria) = match_line_criteria(trimmed) {
                 if let Some(entry) = current.take() {
                     hosts.push(entry);
                 }
-                let name = trimmed.strip_prefix("Host").unwrap().trim().to_string();
-                current = Some(HostEntry {
-                    name,
-                    hostname: None,
-                    user: None,
-                    port: None,
-                    identity_file: None,
-                    password: None,
-                });
+                let mut entry = HostEntry {
+                    name: criteria.to_string(),
+                    match_block: true,
+                    ..Default::default()
+                };
+                if let (_, Some(note)) = split_comment(split_directive(trimmed).1) {
+                    entry.trailing_comments.insert(comment_key("Match", criteria), note.to_string());
+                }
+                current = Some(entry);
+            } else if let Some(name) = host_line_patterns(trimmed) {
+                if let Some(entry) = current.take() {
+                    hosts.push(entry);
+                }
+                let mut entry = HostEntry {
+                    name: name.to_string(),
+                    disabled,
+                    ..Default::default()
+                };
+                if let (_, Some(note)) = split_comment(split_directive(trimmed).1) {
+                    entry.trailing_comments.insert(comment_key("Host", name), note.to_string());
+                }
+                current = Some(entry);
             } else if let Some(entry) = current.as_mut() {
-                if let Some(rest) = trimmed.strip_prefix("HostName") {
-                    entry.hostname = Some(rest.trim().to_string());
-                } else if let Some(rest) = trimmed.strip_prefix("User") {
-                    entry.user = Some(rest.trim().to_string());
-                } else if let Some(rest) = trimmed.strip_prefix("Port") {
-                    entry.port = Some(rest.trim().to_string());
-                } else if let Some(rest) = trimmed.strip_prefix("IdentityFile") {
-                    entry.identity_file = Some(rest.trim().to_string());
-                } else if let Some(rest) = trimmed.strip_prefix("Password") {
-                    entry.password = Some(rest.trim().to_string());
-                } else if let Some(rest) = trimmed.strip_prefix("# Password") {
-                    entry.password = Some(rest.trim().to_string());
+                if trimmed.is_empty() {
+                    continue;
+                }
+                if entry.match_block {
+                    entry.raw_lines.push(line.to_string());
+                    continue;
+                }
+                let (comment, directive) = match trimmed.strip_prefix('#') {
+                    Some(rest) => (true, rest.trim_start()),
+                    None => (false, trimmed),
+                };
+                let (keyword, value) = split_directive(directive);
+                // Our own `# Key value` lines are comments already; only a
+                // directive can carry a comment after its value.
+                let (value, note) = if comment { (value, None) } else { split_comment(value) };
+                let keep_note = |entry: &mut HostEntry, value: &str| {
+                    if let Some(note) = note {
+                        entry.trailing_comments.insert(comment_key(keyword, value), note.to_string());
+                    }
+                };
+                if !comment
+                    && let Some(flag) = parse_yes_no(value)
+                    && let Some(field) = entry.flag_mut(keyword)
+                {
+                    *field = Some(flag);
+                    keep_note(entry, yes_no(flag));
+                    continue;
+                }
+                let raw_before = entry.raw_lines.len();
+                // Keywords are case-insensitive in OpenSSH; so are ours.
+                match (comment, keyword.to_ascii_lowercase().as_str()) {
+                    (false, "hostname") => entry.hostname = Some(value.to_string()),
+                    (false, "user") => entry.user = Some(value.to_string()),
+                    (false, "port") => entry.port = Some(value.to_string()),
+                    (false, "identityfile") => entry.identity_files.push(unquote(value).to_string()),
+                    (false, "proxyjump") => entry.proxy_jump = Some(value.to_string()),
+                    (false, "connecttimeout") => entry.connect_timeout = Some(value.to_string()),
+                    (_, "password") if value == KEYRING_MARKER => entry.password_in_keyring = true,
+                    (_, "password") => entry.password = Some(decode_password(value)),
+                    (true, "env") => entry.env = Env::parse(value),
+                    (true, "webui") => entry.web_ui.push(value.to_string()),
+                    (true, "sshoption") => entry.ssh_options.push(value.to_string()),
+                    (true, "group" | "group:") => entry.group = (!value.is_empty()).then(|| value.to_string()),
+                    _ => entry.raw_lines.push(line.to_string()),
+                }
+                // A line kept verbatim in `raw_lines` has its comment already.
+                if entry.raw_lines.len() == raw_before {
+                    keep_note(entry, unquote(value));
                 }
             }
         }
@@ -71,433 +325,7911 @@ impl HostEntry {
         hosts
     }
 
-    fn write_ssh_config(hosts: &[HostEntry]) -> io::Result<()> {
-        let path = ssh_config_path();
-        let mut out = String::new();
-        for host in hosts {
-            out.push_str(&format!("Host {}\n", host.name));
-            if let Some(val) = &host.hostname {
-                out.push_str(&format!("    HostName {}\n", val));
+    /// Splits off everything before the first named host: top-level options
+    /// and any leading `Host *` blocks. That part is not edited in the UI and
+    /// is written back byte-for-byte.
+    fn split_preamble(file: &str) -> (&str, &str) {
+        let lines: Vec<&str> = file.lines().collect();
+        let mut offset = 0;
+        for (i, line) in file.split_inclusive('\n').enumerate() {
+            if let Some(patterns) = host_line_patterns(line.trim())
+                && patterns != "*"
+            {
+                return file.split_at(offset);
             }
-            if let Some(val) = &host.user {
-                out.push_str(&format!("    User {}\n", val));
+            if disabled_block_at(&lines, i) {
+                return file.split_at(offset);
             }
-            if let Some(val) = &host.port {
-                out.push_str(&format!("    Port {}\n", val));
-            }
-            if let Some(val) = &host.identity_file {
-                out.push_str(&format!("    IdentityFile {}\n", val));
+            offset += line.len();
+        }
+        (file, "")
+    }
+
+    /// Writes one config file, leaving it untouched if nothing in it changed;
+    /// returns whether it was written. The text comes from `serialize_hosts`; this only adds the file I/O.
+    /// With `tool_comments` off, the `# Env`, `# WebUI` and `# Group`
+    /// comments are left out because the companion file holds them.
+    fn write_ssh_config(
+        path: &Path,
+        preamble: &str,
+        hosts: &[&HostEntry],
+        tool_comments: bool,
+    ) -> io::Result<bool> {
+        if read_only() {
+            return Ok(false);
+        }
+        let hosts: Vec<HostEntry> = hosts.iter().map(|h| (*h).clone()).collect();
+        let contents = serialize_hosts(preamble, &hosts, tool_comments);
+        if fs::read_to_string(path).is_ok_and(|current| current == contents) {
+            return Ok(false);
+        }
+        // A visible `.bak` next to an included file would match `Include dir/*`.
+        let hidden_backup = path != ssh_config_path();
+        write_config_atomic(path, &contents, hidden_backup).map(|()| true)
+    }
+
+    /// The `HostName` value split on commas, as emitted by some round-robin
+    /// tooling. Falls back to the alias itself, like OpenSSH does.
+    fn hostname_candidates(&self) -> Vec<&str> {
+        let candidates: Vec<&str> = self
+            .hostname
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .collect();
+        if candidates.is_empty() {
+            vec![self.alias()]
+        } else {
+            candidates
+        }
+    }
+
+    /// The address used for display and probing: the first `HostName` entry.
+    fn primary_hostname(&self) -> &str {
+        self.hostname_candidates()[0]
+    }
+
+    /// Expands a `# WebUI` entry into a URL. `%h` is the resolved hostname,
+    /// `%p` the SSH port and `%%` a literal percent. An entry without a
+    /// scheme, like `:8443/admin`, is taken relative to `https://%h`.
+    fn web_ui_url(&self, template: &str) -> String {
+        let template = if template.contains("://") {
+            template.to_string()
+        } else {
+            format!("https://%h{}", template)
+        };
+        let mut out = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
             }
-            if let Some(val) = &host.password {
-                out.push_str(&format!("    # Password {}\n", val));
+            match chars.next() {
+                Some('h') => out.push_str(self.primary_hostname()),
+                Some('p') => out.push_str(self.port.as_deref().unwrap_or("22")),
+                Some('%') => out.push('%'),
+                Some(other) => {
+                    out.push('%');
+                    out.push(other);
+                }
+                None => out.push('%'),
             }
-            out.push('\n');
         }
-        fs::write(path, out)
+        out
+    }
+
+    /// Fuzzy match of `query` (already lowercase) against the alias and
+    /// the HostName, whichever scores better; the alias wins a tie.
+    fn search_match(&self, query: &str) -> Option<SearchMatch> {
+        let name = fuzzy_match(query, &self.name).map(|(score, positions)| SearchMatch {
+            score,
+            in_hostname: false,
+            positions,
+        });
+        let hostname = self.hostname.as_deref().and_then(|h| fuzzy_match(query, h)).map(|(score, positions)| {
+            SearchMatch { score, in_hostname: true, positions }
+        });
+        match (name, hostname) {
+            (Some(n), Some(h)) if h.score > n.score => Some(h),
+            (n, h) => n.or(h),
+        }
+    }
+
+    /// Wildcard/negated entries match by pattern, so their position in the
+    /// file matters to OpenSSH.
+    fn is_pattern(&self) -> bool {
+        self.match_block || self.name.contains(['*', '?', '!'])
+    }
+
+    /// The patterns on the `Host` line. OpenSSH allows several, as in
+    /// `Host web1 web2 *.example.com`; `name` keeps the line as written.
+    fn patterns(&self) -> Vec<&str> {
+        if self.match_block {
+            return Vec::new();
+        }
+        self.name.split_whitespace().collect()
+    }
+
+    /// Patterns without wildcards or negation: names ssh can connect to.
+    fn concrete_patterns(&self) -> Vec<&str> {
+        self.patterns().into_iter().filter(|p| !p.contains(['*', '?', '!'])).collect()
+    }
+
+    /// Whether the block names at least one host rather than only patterns.
+    fn is_connectable(&self) -> bool {
+        !self.concrete_patterns().is_empty()
+    }
+
+    /// The field behind one of `FLAG_DIRECTIVES`, matched case-insensitively;
+    /// `None` for any other directive.
+    fn flag(&self, directive: &str) -> Option<Option<bool>> {
+        match directive.to_ascii_lowercase().as_str() {
+            "forwardagent" => Some(self.forward_agent),
+            "forwardx11" => Some(self.forward_x11),
+            "compression" => Some(self.compression),
+            _ => None,
+        }
+    }
+
+    fn flag_mut(&mut self, directive: &str) -> Option<&mut Option<bool>> {
+        match directive.to_ascii_lowercase().as_str() {
+            "forwardagent" => Some(&mut self.forward_agent),
+            "forwardx11" => Some(&mut self.forward_x11),
+            "compression" => Some(&mut self.compression),
+            _ => None,
+        }
+    }
+
+    /// What to hand ssh: the first concrete pattern, which still makes
+    /// OpenSSH apply the whole block. Falls back to the line as written.
+    fn alias(&self) -> &str {
+        self.concrete_patterns().first().copied().unwrap_or(&self.name)
     }
 }
 
-struct AppState {
-    hosts: Vec<HostEntry>,
-    selected: usize,
-    last_key: Option<KeyCode>,
-    last_key_time: Option<std::time::Instant>,
-    edit_mode: Option<EditState>,
-    status_message: Option<String>,
+/// Replaces `path` with `contents` without ever leaving it half-written:
+/// the previous file is copied to `<name>.bak` (`.<name>.bak` with
+/// `hidden_backup`), the new contents go to a temporary file in the same
+/// directory, and that is renamed into place.
+fn write_config_atomic(path: &Path, contents: &str, hidden_backup: bool) -> io::Result<()> {
+    let context = |what: &str, target: &Path, e: io::Error| {
+        io::Error::new(e.kind(), format!("{} {}: {}", what, target.display(), e))
+    };
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "config".to_string());
+    let backup = path.with_file_name(format!("{}{}.bak", if hidden_backup { "." } else { "" }, name));
+    let tmp = path.with_file_name(format!(".{}.tmp", name));
+
+    let existing = match fs::metadata(path) {
+        Ok(meta) => Some(meta),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(context("reading", path, e)),
+    };
+    if existing.is_some() {
+        fs::copy(path, &backup).map_err(|e| context("backing up to", &backup, e))?;
+    }
+    fs::write(&tmp, contents).map_err(|e| context("writing", &tmp, e))?;
+    if let Some(meta) = existing {
+        // Keep whatever permissions the user gave the original.
+        fs::set_permissions(&tmp, meta.permissions()).map_err(|e| context("setting permissions on", &tmp, e))?;
+    }
+    fs::rename(&tmp, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        context("replacing", path, e)
+    })
 }
 
-#[derive(Debug, Clone)]
-struct EditState {
-    host: HostEntry,
-    field_index: usize,
-    field_values: Vec<String>,
+/// The config text for `preamble` followed by `hosts`, the inverse of
+/// `split_preamble` plus `parse_ssh_config`: parsing the result gives the
+/// same hosts back. Pure, so it can be tested without touching a file.
+fn serialize_hosts(preamble: &str, hosts: &[HostEntry], tool_comments: bool) -> String {
+    let mut out = preamble.to_string();
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    for host in hosts {
+        let block = serialize_block(host, tool_comments);
+        if host.disabled {
+            for line in block.lines() {
+                out.push('#');
+                out.push_str(line);
+                out.push('\n');
+            }
+        } else {
+            out.push_str(&block);
+        }
+        out.push('\n');
+    }
+    out
 }
 
-impl AppState {
-    fn new(hosts: Vec<HostEntry>) -> Self {
-        Self {
-            hosts,
-            selected: 0,
-            last_key: None,
-            last_key_time: None,
-            edit_mode: None,
-            status_message: None,
+/// Whether line `i` starts a commented-out block: a `#Host` line at the
+/// start of the line, with nothing but comments (or blank lines) after it
+/// until the next real `Host`. Anything else among those lines belongs to
+/// the block above as far as OpenSSH is concerned, so it isn't ours to
+/// treat as disabled.
+fn disabled_block_at(lines: &[&str], i: usize) -> bool {
+    let Some(first) = lines[i].strip_prefix('#') else {
+        return false;
+    };
+    host_line_patterns(first.trim()).is_some()
+        && lines[i + 1..]
+            .iter()
+            .map(|l| l.trim())
+            .take_while(|l| host_line_patterns(l).is_none() && match_line_criteria(l).is_none())
+            .all(|l| l.is_empty() || l.starts_with('#'))
+}
+
+/// One host's block as it appears in the config, without the blank line
+/// that follows it.
+fn serialize_block(host: &HostEntry, tool_comments: bool) -> String {
+    let mut out = String::new();
+    // `value` as stored; `written` is how it goes in the file.
+    let mut line = |indent: &str, keyword: &str, value: &str, written: &str| {
+        out.push_str(&format!("{}{} {}", indent, keyword, written));
+        if let Some(note) = host.trailing_comments.get(&comment_key(keyword, value)) {
+            out.push_str(&format!("  {}", note));
+        }
+        out.push('\n');
+    };
+    if host.match_block {
+        line("", "Match", &host.name, &host.name);
+        for line in &host.raw_lines {
+            out.push_str(line);
+            out.push('\n');
+        }
+        return out;
+    }
+    line("", "Host", &host.name, &host.name);
+    if let Some(val) = &host.hostname {
+        line("    ", "HostName", val, val);
+    }
+    if let Some(val) = &host.user {
+        line("    ", "User", val, val);
+    }
+    if let Some(val) = &host.port {
+        line("    ", "Port", val, val);
+    }
+    for val in &host.identity_files {
+        line("    ", "IdentityFile", val, &quote_if_spaced(val));
+    }
+    if let Some(val) = &host.proxy_jump {
+        line("    ", "ProxyJump", val, val);
+    }
+    if let Some(val) = &host.connect_timeout {
+        line("    ", "ConnectTimeout", val, val);
+    }
+    for directive in FLAG_DIRECTIVES {
+        if let Some(Some(flag)) = host.flag(directive) {
+            line("    ", directive, yes_no(flag), yes_no(flag));
         }
     }
-    fn update_selection(&mut self) {
-        // No-op, kept for compatibility if needed
+    if host.password_in_keyring {
+        out.push_str(&format!("    # Password {}\n", KEYRING_MARKER));
+    } else if let Some(val) = &host.password {
+        out.push_str(&format!("    # Password {}\n", encode_password(val)));
     }
+    if tool_comments {
+        if let Some(env) = host.env {
+            out.push_str(&format!("    # Env {}\n", env.as_str()));
+        }
+        for url in &host.web_ui {
+            out.push_str(&format!("    # WebUI {}\n", url));
+        }
+        for option in &host.ssh_options {
+            out.push_str(&format!("    # SshOption {}\n", option));
+        }
+        if let Some(group) = &host.group {
+            out.push_str(&format!("    # Group: {}\n", group));
+        }
+    }
+    for line in &host.raw_lines {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
 }
 
-fn load_config_file() -> io::Result<Vec<HostEntry>> {
-    let path = ssh_config_path();
-    let contents = fs::read_to_string(path)?;
-    Ok(HostEntry::parse_ssh_config(&contents))
+/// Splits a config line into its keyword and the (trimmed) rest. Like
+/// OpenSSH, the two may be separated by whitespace, a single `=`, or both.
+fn split_directive(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    let end = line.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(line.len());
+    let (keyword, rest) = line.split_at(end);
+    let rest = rest.trim_start();
+    let rest = rest.strip_prefix('=').unwrap_or(rest);
+    (keyword, rest.trim())
 }
 
-fn ssh_config_path() -> PathBuf {
-    let home = std::env::var("USERPROFILE").unwrap_or_else(|_| ".".to_string());
-    PathBuf::from(format!("{}\\.ssh\\config", home))
+/// A directive value without the double quotes OpenSSH lets you put
+/// around one with spaces, like `IdentityFile "C:\My Keys\id_rsa"`.
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
 }
 
-fn draw_ui(
-    f: &mut ratatui::Frame,
-    app: &AppState,
-    config_path_str: &str,
-) {
-    let area = f.area();
-    let chunks = Layout::default()
-        .direction(Direction::Vertical)
-        .margin(1)
-        .constraints([
-            Constraint::Min(3),
-            Constraint::Length(3),
-        ])
-        .split(area);
-
-    if let Some(edit) = &app.edit_mode {
-        // Edit mode UI
-        let fields = [
-            ("Host", edit.host.name.clone()),
-            ("HostName", edit.host.hostname.clone().unwrap_or_default()),
-            ("User", edit.host.user.clone().unwrap_or_default()),
-            ("Port", edit.host.port.clone().unwrap_or_default()),
-            ("IdentityFile", edit.host.identity_file.clone().unwrap_or_default()),
-            ("Password", edit.host.password.clone().unwrap_or_default()),
-        ];
-        let items: Vec<ListItem> = fields.iter().enumerate().map(|(i, (label, value))| {
-            let mut line = format!("{}: {}", label, value);
-            if i == edit.field_index {
-                line.push_str(" <");
-            }
-            let mut item = ListItem::new(line);
-            if i == edit.field_index {
-                item = item.style(Style::default().add_modifier(Modifier::REVERSED));
-            }
-            item
-        }).collect();
-        let list = List::new(items)
-            .block(Block::default().borders(Borders::ALL).title("Edit Host"))
-            .highlight_symbol("→ ");
-        f.render_widget(list, chunks[0]);
-        let edit = Paragraph::new("[Enter] Save  [Esc] Cancel  [Tab/Up/Down] Move  Type to edit")
-            .block(Block::default().borders(Borders::ALL).title("Editing"));
-        f.render_widget(edit, chunks[1]);
+/// Quotes a value with whitespace so OpenSSH reads it as one argument.
+fn quote_if_spaced(value: &str) -> String {
+    if value.contains(char::is_whitespace) {
+        format!("\"{}\"", value)
     } else {
-        let items: Vec<ListItem> = if app.hosts.is_empty() {
-            vec![ListItem::new("No hosts found.")]
-        } else {
-            app.hosts
-                .iter()
-                .enumerate()
-                .map(|(i, h)| {
-                    let mut label = h.name.clone();
-                    if let Some(ip) = &h.hostname {
-                        label.push_str(&format!(" ({})", ip));
-                    }
-                    let mut item = ListItem::new(Text::from(Line::from(Span::raw(label))));
-                    if i == app.selected {
-                        item = item.style(Style::default().add_modifier(Modifier::REVERSED));
-                    }
-                    item
-                })
-                .collect()
-        };
+        value.to_string()
+    }
+}
 
-        let list = List::new(items)
-            .block(Block::default().borders(Borders::ALL).title(format!("SSH Hosts ({})", config_path_str)))
-            .highlight_symbol("→ ");
+/// The patterns of a `Host` line, whatever its case or separator, without
+/// any comment after them.
+fn host_line_patterns(line: &str) -> Option<&str> {
+    let (keyword, value) = split_directive(line);
+    let (value, _) = split_comment(value);
+    (keyword.eq_ignore_ascii_case("host") && !value.is_empty()).then_some(value)
+}
 
-        f.render_widget(list, chunks[0]);
+/// The criteria of a `Match` line (`host web user root`), like
+/// `host_line_patterns`.
+fn match_line_criteria(line: &str) -> Option<&str> {
+    let (keyword, value) = split_directive(line);
+    let (value, _) = split_comment(value);
+    (keyword.eq_ignore_ascii_case("match") && !value.is_empty()).then_some(value)
+}
 
-        let edit = Paragraph::new("Press [e] to edit a host, [n] to add new host, [k] to secure keyfile, [Shift+Up/Down] move host, [q] to quit")
-            .block(Block::default().borders(Borders::ALL).title("Controls"));
-        f.render_widget(edit, chunks[1]);
+/// Splits an end-of-line comment off a directive's value the way OpenSSH
+/// does: a `#` that starts a word ends the line, unless it's inside double
+/// quotes. `2222  # non-standard` gives `("2222", Some("# non-standard"))`;
+/// `pass#word` and `"C:\#keys\id"` are left whole.
+fn split_comment(value: &str) -> (&str, Option<&str>) {
+    let mut quoted = false;
+    let mut word_start = true;
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted && word_start => return (value[..i].trim_end(), Some(&value[i..])),
+            _ => {}
+        }
+        word_start = c.is_whitespace();
+    }
+    (value, None)
+}
+
+/// The `HostEntry::trailing_comments` key for the comment on a line.
+fn comment_key(keyword: &str, value: &str) -> String {
+    format!("{} {}", keyword.to_ascii_lowercase(), value)
+}
 
-        if let Some(msg) = &app.status_message {
-            let popup = Paragraph::new(msg.clone())
-                .block(Block::default().borders(Borders::ALL).title("Status"));
-            f.render_widget(popup, centered_rect(60, 20, f.size()));
+/// Written as the `# Password` value when the real one is in the keyring.
+/// `encode_password` escapes spaces, so no stored password can look like it.
+const KEYRING_MARKER: &str = "(in keyring)";
+
+/// Percent-encodes the characters that would break a `# Password` comment
+/// line: `%` itself, `#`, `=` (which could pass for a separator),
+/// whitespace (including newlines) and other control characters.
+fn encode_password(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '%' | '#' | '=') || c.is_whitespace() || c.is_control() {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                out.push_str(&format!("%{:02X}", b));
+            }
+        } else {
+            out.push(c);
         }
     }
+    out
 }
 
-fn centered_rect(percent_x: u16, percent_y: u16, area: ratatui::layout::Rect) -> ratatui::layout::Rect {
-    let popup_layout = Layout::default()
-        .direction(Direction::Vertical)
-        .constraints([
-            Constraint::Percentage((100 - percent_y) / 2),
-            Constraint::Percentage(percent_y),
-            Constraint::Percentage((100 - percent_y) / 2),
-        ])
-        .split(area);
+/// Reverses `encode_password`. A `%` not followed by two hex digits is kept
+/// literally, so passwords written before encoding was introduced still load.
+fn decode_password(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(hex) = value.get(i + 1..i + 3)
+            && hex.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            out.push(u8::from_str_radix(hex, 16).unwrap());
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
 
-    Layout::default()
-        .direction(Direction::Horizontal)
-        .constraints([
-            Constraint::Percentage((100 - percent_x) / 2),
-            Constraint::Percentage(percent_x),
-            Constraint::Percentage((100 - percent_x) / 2),
-        ])
-        .split(popup_layout[1])[1]
+/// Returns `hosts` in the order they should be written to disk. With
+/// `sort_on_save`, concrete hosts are sorted by name within each run between
+/// pattern entries, so no host moves across a wildcard and first-match
+/// semantics are unchanged.
+fn hosts_for_save(hosts: &[HostEntry], settings: &Settings) -> Vec<HostEntry> {
+    let mut ordered = hosts.to_vec();
+    if settings.sort_on_save {
+        for run in ordered.split_mut(|h| h.is_pattern()) {
+            run.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+    }
+    ordered
 }
 
+#[derive(Debug, Clone, Default)]
+struct Settings {
+    sort_on_save: bool,
+    /// Keep passwords in the OS keyring instead of `# Password` comments.
+    keyring: bool,
+    /// Log in with the host's password through `sshpass` when connecting.
+    sshpass: bool,
+    /// Check every host's port this often in the background; off when unset.
+    probe_interval: Option<Duration>,
+    /// Up/Down stop at the first and last host instead of wrapping around
+    /// (`wrap_around = false`).
+    stop_at_ends: bool,
+    /// Template for the Enter command, e.g. `kitty +kitten ssh {host}`;
+    /// `PUTTY_CONNECT_COMMAND` takes precedence. See `expand_template`.
+    connect_command: Option<String>,
+    /// Colours for groups and tags from `color.<name> = <colour>` lines,
+    /// keyed by lowercase name.
+    colors: BTreeMap<String, Color>,
+    /// `theme = light` for light terminal backgrounds; dark otherwise.
+    theme: Option<Theme>,
+    /// Pressing a host's number connects straight away instead of only
+    /// selecting it.
+    digit_connects: bool,
+    /// Leave connections out of the connect log (`connect_log = false`).
+    no_connect_log: bool,
+    /// j/k/g/G navigate the list as in vim; see `vim_key`.
+    vim_keys: bool,
+    /// `connect_timeout = N`: seconds ssh waits for a host that doesn't set
+    /// its own ConnectTimeout. `DEFAULT_CONNECT_TIMEOUT` when unset; 0
+    /// leaves it to ssh. See `connect_options`.
+    connect_timeout: Option<u64>,
+    /// `connection_attempts = N`: how many times ssh tries before giving up.
+    connection_attempts: Option<u64>,
+}
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    enable_raw_mode()?;
-    let mut stdout = stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
+impl Settings {
+    fn load() -> Self {
+        fs::read_to_string(settings_path())
+            .map(|contents| Self::parse(&contents))
+            .unwrap_or_default()
+    }
 
-    let hosts = load_config_file().unwrap_or_default();
-    let config_path = ssh_config_path();
-    let config_path_str = config_path.display().to_string();
-    let mut app = AppState::new(hosts);
+    /// Parses `key = value` lines; blank lines and `#` comments are skipped
+    /// and unknown keys are ignored.
+    fn parse(contents: &str) -> Self {
+        let mut settings = Self::default();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "sort_on_save" => settings.sort_on_save = parse_bool(value),
+                "keyring" => settings.keyring = parse_bool(value),
+                "sshpass" => settings.sshpass = parse_bool(value),
+                "wrap_around" => settings.stop_at_ends = !parse_bool(value),
+                "theme" => settings.theme = Theme::named(value),
+                "digit_connects" => settings.digit_connects = parse_bool(value),
+                "vim_keys" => settings.vim_keys = parse_bool(value),
+                "connect_log" => settings.no_connect_log = !parse_bool(value),
+                "connect_timeout" => settings.connect_timeout = value.parse().ok(),
+                "connection_attempts" => settings.connection_attempts = value.parse().ok().filter(|&n| n > 0),
+                "connect_command" => settings.connect_command = (!value.is_empty()).then(|| value.to_string()),
+                "probe_interval" => {
+                    settings.probe_interval = value.parse().ok().filter(|&s| s > 0).map(Duration::from_secs);
+                }
+                key => {
+                    if let Some(name) = key.strip_prefix("color.")
+                        && let Ok(color) = value.parse::<Color>()
+                    {
+                        settings.colors.insert(name.trim().to_lowercase(), color);
+                    }
+                }
+            }
+        }
+        settings
+    }
 
-    // Initial draw before flushing events
-    terminal.draw(|f| draw_ui(f, &app, &config_path_str))?;
+    /// The colour for hosts in group or with tag `name`: a `color.` setting,
+    /// or else the Env colour when the name is an environment (a group
+    /// called `prod` is red).
+    fn color_for(&self, name: &str, theme: &Theme) -> Option<Color> {
+        self.colors.get(&name.to_lowercase()).copied().or_else(|| Env::parse(name).map(|env| theme.env_color(env)))
+    }
 
-    // Give terminal time to settle
-    sleep(Duration::from_millis(100));
+    /// The connect template in effect, split into arguments and checked, or
+    /// `None` for plain `ssh <host>`.
+    fn connect_template(&self) -> Result<Option<Vec<String>>, String> {
+        let template = std::env::var(CONNECT_ENV).ok().filter(|v| !v.trim().is_empty());
+        let Some(template) = template.or_else(|| self.connect_command.clone()) else {
+            return Ok(None);
+        };
+        let args = split_args(&template)?;
+        if args.is_empty() {
+            return Err("the template is empty".to_string());
+        }
+        for arg in &args {
+            expand_template(arg, |name| PLACEHOLDERS.contains(&name).then(Vec::new))?;
+        }
+        Ok(Some(args))
+    }
+}
 
-    // Flush any leftover events (e.g. Enter from cargo run)
-    while event::poll(Duration::from_millis(0))? {
-        let _ = event::read();
+/// Environment variable that overrides the `connect_command` setting.
+const CONNECT_ENV: &str = "PUTTY_CONNECT_COMMAND";
+
+/// What a connect template can refer to; `ssh_command` fills them in.
+const PLACEHOLDERS: [&str; 5] = ["cmd", "host", "user", "port", "identity"];
+
+/// Fills in the `{name}` placeholders of one template argument. `lookup`
+/// gives a placeholder's value, as several arguments in the case of
+/// `{cmd}`, or `None` if there is no such placeholder. `{{` and `}}` are
+/// literal braces. An argument that is exactly one placeholder becomes
+/// that placeholder's arguments; elsewhere they are joined with spaces.
+fn expand_template(arg: &str, lookup: impl Fn(&str) -> Option<Vec<String>>) -> Result<Vec<String>, String> {
+    let mut out = String::new();
+    let mut whole = None;
+    let mut chars = arg.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(format!("unclosed {{ in {}", arg)),
+                    }
+                }
+                let value = lookup(&name).ok_or_else(|| {
+                    let known = PLACEHOLDERS.map(|p| format!("{{{}}}", p)).join(", ");
+                    format!("unknown placeholder {{{}}} (known: {})", name, known)
+                })?;
+                if arg == format!("{{{}}}", name) {
+                    whole = Some(value);
+                } else {
+                    out.push_str(&value.join(" "));
+                }
+            }
+            '}' => return Err(format!("stray }} in {}", arg)),
+            c => out.push(c),
+        }
     }
+    Ok(whole.unwrap_or_else(|| vec![out]))
+}
 
-    loop {
-        terminal.draw(|f| draw_ui(f, &app, &config_path_str))?;
+/// Sets `key = value` in the settings file at `path`, replacing the
+/// key's line if there is one and leaving every other line as it was.
+fn save_setting(path: &Path, key: &str, value: &str) -> io::Result<()> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let line = format!("{} = {}", key, value);
+    let mut replaced = false;
+    let mut lines: Vec<String> = contents
+        .lines()
+        .map(|l| match l.split_once('=') {
+            Some((k, _)) if k.trim() == key && !l.trim_start().starts_with('#') && !replaced => {
+                replaced = true;
+                line.clone()
+            }
+            _ => l.to_string(),
+        })
+        .collect();
+    if !replaced {
+        lines.push(line);
+    }
+    fs::write(path, lines.join("\n") + "\n")
+}
 
-        if let Event::Key(key) = event::read()? {
-            let now = std::time::Instant::now();
-            let allow = match (app.last_key, app.last_key_time) {
-                (Some(prev), Some(t)) if prev == key.code && now.duration_since(t) < Duration::from_millis(50) => false,
-                _ => true,
-            };
-            if allow {
-                if let Some(edit) = &mut app.edit_mode {
-                    // Edit mode key handling
-                    match key.code {
-                        KeyCode::Esc => {
-                            app.edit_mode = None;
-                        }
-                        KeyCode::Enter => {
-                            // Save changes
-                            if app.selected < app.hosts.len() {
-                                app.hosts[app.selected] = edit.host.clone();
-                                let _ = HostEntry::write_ssh_config(&app.hosts); // Save to file
-                            } else {
-                                // Adding new host
-                                app.hosts.push(edit.host.clone());
-                                app.selected = app.hosts.len() - 1;
-                                let _ = HostEntry::write_ssh_config(&app.hosts);
-                            }
-                            app.edit_mode = None;
-                        }
-                        KeyCode::Tab | KeyCode::Down => {
-                            edit.field_index = (edit.field_index + 1) % 6;
-                        }
-                        KeyCode::Up => {
-                            if edit.field_index == 0 {
-                                edit.field_index = 5;
-                            } else {
-                                edit.field_index -= 1;
-                            }
-                        }
-                        KeyCode::Backspace => {
-                            let field = get_edit_field_mut(&mut edit.host, edit.field_index);
-                            if let Some(val) = field {
-                                val.pop();
-                            }
-                        }
-                        KeyCode::Char(c) => {
-                            let field = get_edit_field_mut(&mut edit.host, edit.field_index);
-                            if let Some(val) = field {
-                                val.push(c);
-                            }
-                        }
-                        _ => {}
-                    }
-                } else {
-                    match key.code {
-                        KeyCode::Enter => {
-                            if !app.hosts.is_empty() {
-                                let host_name = &app.hosts[app.selected].name;
-                                disable_raw_mode().ok();
-                                execute!(
-                                    terminal.backend_mut(),
-                                    LeaveAlternateScreen,
-                                    DisableMouseCapture
-                                ).ok();
-                                terminal.show_cursor().ok();
-                                println!("Connecting to {}...", host_name);
-
-                                std::process::Command::new("ssh")
-                                    .arg(host_name)
-                                    .status()
-                                    .expect("Failed to launch ssh");
-
-                                return Ok(()); // Quit the app after SSH exits
-                            }
-                        }
-                        KeyCode::Char('q') => break,
-                        KeyCode::Char('e') => {
-                            if !app.hosts.is_empty() {
-                                let host = app.hosts[app.selected].clone();
-                                app.edit_mode = Some(EditState {
-                                    host,
-                                    field_index: 0,
-                                    field_values: vec![], // unused for now
-                                });
-                            }
-                        }
-                        KeyCode::Char('n') => {
-                            // Add new host
-                            let new_host = HostEntry {
-                                name: String::from("new-host"),
-                                hostname: None,
-                                user: None,
-                                port: None,
-                                identity_file: None,
-                                password: None,
-                            };
-                            app.edit_mode = Some(EditState {
-                                host: new_host,
-                                field_index: 0,
-                                field_values: vec![],
-                            });
-                        }
-                        KeyCode::Char('k') => {
-                            if !app.hosts.is_empty() {
-                                let host = &app.hosts[app.selected];
-                                if let Some(identity_file) = &host.identity_file {
-                                    let username = std::env::var("USERNAME").unwrap_or_else(|_| "User".to_string());
-                                    let grant_arg = format!("{}:R", username);
-
-                                    let cmds = [
-                                        vec!["/reset"],
-                                        vec!["/inheritance:r"],
-                                        vec!["/remove", "NT AUTHORITY\\Authenticated Users"],
-                                        vec!["/remove", "BUILTIN\\Users"],
-                                        vec!["/remove", "Everyone"],
-                                        vec!["/grant:r", &grant_arg],
-                                    ];
-
-                                    let mut full_output = String::new();
-                                    let mut failed = None;
-
-                                    for args in cmds {
-                                        let output = std::process::Command::new("icacls")
-                                            .arg(identity_file)
-                                            .args(&args)
-                                            .output();
-
-                                        match output {
-                                            Ok(out) => {
-                                                let stdout = String::from_utf8_lossy(&out.stdout);
-                                                let stderr = String::from_utf8_lossy(&out.stderr);
-                                                full_output.push_str(&format!("> icacls {:?}\n", args));
-                                                if !stdout.is_empty() {
-                                                    full_output.push_str(&format!("stdout:\n{}\n", stdout));
-                                                }
-                                                if !stderr.is_empty() {
-                                                    full_output.push_str(&format!("stderr:\n{}\n", stderr));
-                                                }
-                                                if !out.status.success() {
-                                                    failed = Some(format!(
-                                                        "❌ icacls {:?} failed with code {}\n{}",
-                                                        args,
-                                                        out.status.code().unwrap_or(-1),
-                                                        full_output
-                                                    ));
-                                                    break;
-                                                }
-                                            }
-                                            Err(e) => {
-                                                failed = Some(format!("❌ Failed to run icacls {:?}: {}\n{}", args, e, full_output));
-                                                break;
-                                            }
-                                        }
-                                    }
+fn parse_bool(value: &str) -> bool {
+    matches!(value.to_ascii_lowercase().as_str(), "true" | "yes" | "on" | "1")
+}
 
-                                    app.status_message = Some(match failed {
-                                        Some(msg) => msg,
-                                        None => format!("✔ Permissions fixed for {}\n{}", identity_file, full_output),
-                                    });
-                                }
-                            }
-                        }
-                        KeyCode::Up => {
-                            if key.modifiers.contains(crossterm::event::KeyModifiers::SHIFT) {
-                                // Move host up
-                                if app.selected > 0 {
-                                    app.hosts.swap(app.selected, app.selected - 1);
-                                    app.selected -= 1;
-                                    let _ = HostEntry::write_ssh_config(&app.hosts);
-                                }
-                            } else if !app.hosts.is_empty() {
-                                if app.selected == 0 {
-                                    app.selected = app.hosts.len() - 1;
-                                } else {
-                                    app.selected -= 1;
-                                }
-                            }
-                        }
-                        KeyCode::Down => {
-                            if key.modifiers.contains(crossterm::event::KeyModifiers::SHIFT) {
-                                // Move host down
-                                if app.selected + 1 < app.hosts.len() {
-                                    app.hosts.swap(app.selected, app.selected + 1);
-                                    app.selected += 1;
-                                    let _ = HostEntry::write_ssh_config(&app.hosts);
-                                }
-                            } else if !app.hosts.is_empty() {
-                                app.selected = (app.selected + 1) % app.hosts.len();
-                            }
-                        }
-                        _ => {}
-                    }
+struct AppState {
+    hosts: Vec<HostEntry>,
+    selected: usize,
+    theme: Theme,
+    last_key: Option<KeyCode>,
+    last_key_time: Option<std::time::Instant>,
+    edit_mode: Option<EditState>,
+    /// Popup message; cleared by the next keypress or after `STATUS_DURATION`.
+    status_message: Option<(String, Instant)>,
+    /// Lines scrolled off the top of a status message too long for its
+    /// popup. `None` while it fits; one that doesn't fit stays up until
+    /// dismissed rather than timing out.
+    status_scroll: Option<usize>,
+    settings: Settings,
+    /// Host names marked with Space, in the order they were marked.
+    marked: Vec<String>,
+    /// Short-lived confirmation shown after a successful save.
+    flash: Option<(String, Instant)>,
+    /// Last save failure; stays up until a later save succeeds.
+    save_error: Option<String>,
+    /// Changes in memory that aren't on disk yet, because the last save
+    /// failed. An open, modified edit form counts too; see `has_unsaved_changes`.
+    dirty: bool,
+    /// Set once the user has chosen to quit.
+    quit: bool,
+    /// Only hosts in this environment are listed when set.
+    env_filter: Option<Env>,
+    /// Only hosts in this `# Group` are listed when set (`g`).
+    group_filter: Option<String>,
+    /// Groups folded down to their header.
+    collapsed: BTreeSet<String>,
+    /// IdentityFile values whose file others can read, as last checked by
+    /// `check_keys`; kept so a redraw doesn't stat every key.
+    exposed_keys: BTreeSet<String>,
+    sort: SortOrder,
+    /// Whether the `?` overlay is open.
+    show_help: bool,
+    /// The connect log viewer (`L`): the tail of the log, oldest first.
+    log_view: Option<Vec<String>>,
+    /// Open when the selected host has several `# WebUI` entries.
+    web_picker: Option<WebPicker>,
+    prompt: Option<Prompt>,
+    confirm: Option<Confirm>,
+    /// Whether tool metadata is saved to the companion file instead of
+    /// config comments. Set when a readable companion file was loaded.
+    companion: bool,
+    /// Global options ahead of the first named host, kept verbatim.
+    preamble: String,
+    /// Files pulled in by `Include`, in the order they were read.
+    included: Vec<IncludedFile>,
+    /// Query typed after `/`; `Some` while the search bar is open.
+    search: Option<String>,
+    /// Unix time of the last session per host name, from `putty_meta.json`.
+    last_connected: BTreeMap<String, u64>,
+    /// Host lists from before each change, newest last; see `checkpoint`.
+    undo: Vec<Vec<HostEntry>>,
+    /// Host lists undone since the last change, newest last.
+    redo: Vec<Vec<HostEntry>>,
+    /// First host row shown in the list, kept by `draw_ui`.
+    scroll_offset: usize,
+    /// Where `draw_ui` last drew the host list, borders included.
+    list_area: Rect,
+    /// Host and time of the last click, to spot a double-click.
+    last_click: Option<(usize, Instant)>,
+    /// Background reachability checks, when `probe_interval` is set.
+    prober: Option<Prober>,
+    /// Latest background check result per host name.
+    reachable: BTreeMap<String, bool>,
+    /// Modification times of `watched_files` as of the last load or save.
+    disk_mtimes: Vec<Option<SystemTime>>,
+    /// A session waiting for the main loop to hand it the terminal.
+    pending_launch: Option<Launch>,
+    /// An `ssh-keygen` run waiting for the terminal, for its passphrase prompt.
+    pending_keygen: Option<KeyGen>,
+    /// `--read-only`: keys that would change the config are refused, and
+    /// nothing is saved.
+    read_only: bool,
+    /// Printed once the terminal is restored on quit: values that couldn't
+    /// go on the clipboard (`h`).
+    print_on_exit: Vec<String>,
+}
+
+/// Where and how well a host matched the search.
+#[derive(Debug, Clone, PartialEq)]
+struct SearchMatch {
+    score: i64,
+    /// Matched the HostName rather than the alias.
+    in_hostname: bool,
+    /// Char indices of the matched characters, for highlighting.
+    positions: Vec<usize>,
+}
+
+/// Scores `text` against `query` (already lowercase), case-insensitively.
+/// Every query character has to appear in order. A plain substring beats
+/// any scattered match, earlier ones first; otherwise runs of adjacent
+/// characters and word starts score higher, so `prdweb` finds `prod-web-01`.
+/// Returns the score and the char indices matched.
+fn fuzzy_match(query: &str, text: &str) -> Option<(i64, Vec<usize>)> {
+    let text: Vec<char> = text.chars().map(|c| c.to_lowercase().next().unwrap_or(c)).collect();
+    let query: Vec<char> = query.chars().collect();
+    if query.len() > text.len() {
+        return None;
+    }
+    if let Some(start) = text.windows(query.len()).position(|w| w == query.as_slice()) {
+        return Some(((1 << 32) - start as i64, (start..start + query.len()).collect()));
+    }
+    let mut positions: Vec<usize> = Vec::with_capacity(query.len());
+    let mut score = 0;
+    for &wanted in &query {
+        let from = positions.last().map_or(0, |&p| p + 1);
+        let pos = (from..text.len()).find(|&i| text[i] == wanted)?;
+        score += 1;
+        if positions.last().is_some_and(|&p| p + 1 == pos) {
+            score += 5;
+        }
+        if pos == 0 || !text[pos - 1].is_alphanumeric() {
+            score += 3;
+        }
+        positions.push(pos);
+    }
+    Some((score, positions))
+}
+
+/// `text` as spans, with the chars at `positions` picked out.
+fn highlighted(text: &str, positions: &[usize]) -> Vec<Span<'static>> {
+    let strong = Style::default().add_modifier(Modifier::BOLD | Modifier::UNDERLINED);
+    let mut spans: Vec<Span> = Vec::new();
+    let mut run = String::new();
+    let mut run_matched = false;
+    for (i, c) in text.chars().enumerate() {
+        let matched = positions.contains(&i);
+        if matched != run_matched && !run.is_empty() {
+            let style = if run_matched { strong } else { Style::default() };
+            spans.push(Span::styled(std::mem::take(&mut run), style));
+        }
+        run_matched = matched;
+        run.push(c);
+    }
+    if !run.is_empty() {
+        spans.push(Span::styled(run, if run_matched { strong } else { Style::default() }));
+    }
+    spans
+}
+
+/// The host's name for the list, highlighted like `highlighted`. With
+/// several patterns on the `Host` line, the ones that aren't its ssh alias
+/// are dimmed so it's clear which name a connection uses.
+fn name_spans(host: &HostEntry, positions: &[usize]) -> Vec<Span<'static>> {
+    if host.match_block {
+        let mut spans = vec![Span::styled("Match ", Style::default().add_modifier(Modifier::DIM))];
+        spans.extend(highlighted(&host.name, positions));
+        return spans;
+    }
+    if host.patterns().len() < 2 {
+        return highlighted(&host.name, positions);
+    }
+    let alias = host.alias();
+    let mut spans = Vec::new();
+    let mut start = 0;
+    let mut alias_seen = false;
+    for part in host.name.split_inclusive(char::is_whitespace) {
+        let len = part.chars().count();
+        let here: Vec<usize> = positions.iter().filter(|&&p| p >= start && p < start + len).map(|p| p - start).collect();
+        let mut part_spans = highlighted(part, &here);
+        if !alias_seen && part.trim() == alias {
+            alias_seen = true;
+        } else {
+            for span in &mut part_spans {
+                span.style = span.style.add_modifier(Modifier::DIM);
+            }
+        }
+        spans.extend(part_spans);
+        start += len;
+    }
+    spans
+}
+
+/// Section for hosts without a `# Group` comment.
+const UNGROUPED: &str = "Ungrouped";
+
+/// One line of the host list; see `AppState::list_rows`.
+#[derive(Debug, Clone, PartialEq)]
+enum ListRow {
+    /// An expanded group's header; the cursor skips it.
+    Header { group: String, count: usize },
+    /// A collapsed group, selected through its first host.
+    Collapsed { group: String, count: usize, first: usize },
+    Host(usize),
+}
+
+impl ListRow {
+    /// The host the cursor lands on for this row, if it can.
+    fn host(&self) -> Option<usize> {
+        match *self {
+            ListRow::Header { .. } => None,
+            ListRow::Collapsed { first, .. } => Some(first),
+            ListRow::Host(i) => Some(i),
+        }
+    }
+}
+
+/// A foreground program to run against a host, e.g. `ssh web`.
+#[derive(Debug, Clone, PartialEq)]
+struct Launch {
+    /// Index into `hosts` of the host it connects to.
+    index: usize,
+    /// Recorded in the connect log, e.g. `connect` or `mosh`.
+    action: &'static str,
+    command: Vec<String>,
+}
+
+/// A new key to generate for a host (`G`).
+#[derive(Debug, Clone, PartialEq)]
+struct KeyGen {
+    /// Index into `hosts` of the host that gets the key.
+    index: usize,
+    /// The key file as typed, e.g. `~/.ssh/id_ed25519_web`; the config
+    /// gets it in this form.
+    path: String,
+}
+
+/// A yes/no question guarding a destructive action. Only `y` runs
+/// `on_yes`; anything that dismisses it leaves the state untouched.
+struct Confirm {
+    prompt: String,
+    on_yes: Action,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Action {
+    /// Throw away the changes in the open edit form.
+    DiscardEdit,
+    /// Remove the host at this index and save.
+    DeleteHost(usize),
+    /// Remove every marked host and save.
+    DeleteMarked,
+    /// Hand the terminal to this program.
+    Launch(Launch),
+    /// Leave the app, dropping anything unsaved.
+    Quit,
+    /// Read the config again after it changed on disk.
+    Reload,
+    /// Lock down the key files of the host at this index (`k`).
+    SecureKeys(usize),
+    /// Generate a key over an existing file.
+    GenerateKey(KeyGen),
+    /// Lock down every host's key files (`K`), or only the marked hosts'.
+    SecureKeyFiles { marked_only: bool },
+}
+
+/// A single-line input that replaces the Controls bar while it is open.
+struct Prompt {
+    kind: PromptKind,
+    input: String,
+    /// Char position of the cursor in `input`, as in `EditState`; anything
+    /// past the end means the end.
+    cursor: usize,
+    /// Validation message from the last rejected submit.
+    error: Option<String>,
+}
+
+impl Prompt {
+    /// Types `c` at the cursor.
+    fn insert_char(&mut self, c: char) {
+        let cursor = self.cursor();
+        self.input.insert(byte_offset(&self.input, cursor), c);
+        self.cursor = cursor + 1;
+    }
+
+    /// Inserts pasted text at the cursor as if typed, without line breaks.
+    fn paste(&mut self, text: &str) {
+        for c in text.chars().filter(|c| !matches!(c, '\n' | '\r')) {
+            self.insert_char(c);
+        }
+    }
+
+    /// Where typing goes in the input, as a char index.
+    fn cursor(&self) -> usize {
+        self.cursor.min(self.input.chars().count())
+    }
+
+    /// Moves the cursor `delta` chars, stopping at either end of the input.
+    fn move_cursor(&mut self, delta: isize) {
+        self.cursor = self.cursor().saturating_add_signed(delta).min(self.input.chars().count());
+    }
+
+    /// Deletes the char before the cursor (Backspace) or under it (Delete).
+    fn delete_char(&mut self, before: bool) {
+        let cursor = self.cursor();
+        if let Some(at) = delete_at(&mut self.input, cursor, before) {
+            self.cursor = at;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PromptKind {
+    /// Quick edit of the selected host's HostName.
+    Hostname,
+    /// Extra `ssh` arguments for a one-off connection.
+    SshArgs,
+    /// Path of an ssh_config file to merge hosts from.
+    Import,
+    /// Where `ssh-keygen` should write a new key for the selected host.
+    KeyPath,
+    /// A command to run on the selected host instead of a login shell.
+    RemoteCommand,
+}
+
+impl PromptKind {
+    fn label(self) -> &'static str {
+        match self {
+            PromptKind::Hostname => "HostName",
+            PromptKind::SshArgs => "Extra ssh arguments",
+            PromptKind::Import => "Import hosts from file",
+            PromptKind::KeyPath => "New ed25519 key file",
+            PromptKind::RemoteCommand => "Command to run on the host",
+        }
+    }
+}
+
+/// Display order of the host list. Only `File` matches the config on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum SortOrder {
+    #[default]
+    File,
+    Name,
+    Recent,
+}
+
+impl SortOrder {
+    fn next(self) -> SortOrder {
+        match self {
+            SortOrder::File => SortOrder::Name,
+            SortOrder::Name => SortOrder::Recent,
+            SortOrder::Recent => SortOrder::File,
+        }
+    }
+
+    /// The name used in the state file.
+    fn as_str(self) -> &'static str {
+        match self {
+            SortOrder::File => "file",
+            SortOrder::Name => "name",
+            SortOrder::Recent => "recent",
+        }
+    }
+
+    fn parse(value: &str) -> Option<SortOrder> {
+        [SortOrder::File, SortOrder::Name, SortOrder::Recent].into_iter().find(|s| s.as_str() == value)
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            SortOrder::File => "file order",
+            SortOrder::Name => "name",
+            SortOrder::Recent => "recently used",
+        }
+    }
+}
+
+struct WebPicker {
+    urls: Vec<String>,
+    selected: usize,
+}
+
+const FLASH_DURATION: Duration = Duration::from_secs(2);
+const STATUS_DURATION: Duration = Duration::from_secs(5);
+/// Lines PageUp/PageDown move a long status message.
+const STATUS_PAGE: usize = 10;
+/// How many changes `u` can take back.
+const UNDO_LIMIT: usize = 20;
+/// Two clicks on the same host this close together connect to it.
+const DOUBLE_CLICK: Duration = Duration::from_millis(400);
+/// How long `p` waits for a host to accept a connection.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Hosts checked at once by the background prober.
+const PROBE_WORKERS: usize = 8;
+/// Longest the main loop waits for input before redrawing.
+const TICK: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone)]
+struct EditState {
+    /// The entry as it was when the form opened. Metadata and lines the form
+    /// doesn't show are carried over from it, and it is how changes are told.
+    original: HostEntry,
+    /// Position in `hosts` being edited, or `None` for a new host.
+    index: Option<usize>,
+    rows: Vec<EditRow>,
+    /// Position in `rows` of the highlighted row.
+    field_index: usize,
+    /// Why the last keypress was rejected, shown until the next one.
+    warning: Option<String>,
+    /// Show the Password row as typed instead of as asterisks.
+    reveal_password: bool,
+    /// Char position of the cursor in the highlighted row; anything past
+    /// the end (`usize::MAX` after changing rows) means the end.
+    cursor: usize,
+    /// Paths Tab is cycling through in an IdentityFile row, and which one
+    /// is in the field now.
+    path_completion: Option<(Vec<String>, usize)>,
+}
+
+/// Directives the edit form always offers, in order, after the Host row.
+/// Any other directive in the block gets a row of its own after these.
+/// Password and Env are our comment fields rather than ssh directives.
+const FORM_DIRECTIVES: &[&str] = &[
+    "HostName",
+    "User",
+    "Port",
+    "IdentityFile",
+    "ProxyJump",
+    "ConnectTimeout",
+    "ForwardAgent",
+    "ForwardX11",
+    "Compression",
+    "Password",
+    "Env",
+    "SshOption",
+];
+
+/// One row of the edit form: a directive and its value as typed.
+#[derive(Debug, Clone, PartialEq)]
+struct EditRow {
+    /// Empty for a row added with Ctrl+A, whose value is typed as
+    /// `Keyword value`.
+    directive: String,
+    value: String,
+    /// The line of `raw_lines` this row was read from, for directives that
+    /// `HostEntry` doesn't model.
+    raw: Option<usize>,
+}
+
+impl EditRow {
+    fn new(directive: &str, value: impl Into<String>, raw: Option<usize>) -> Self {
+        Self {
+            directive: directive.to_string(),
+            value: value.into(),
+            raw,
+        }
+    }
+
+    fn label(&self) -> &str {
+        if self.directive.is_empty() {
+            "New directive"
+        } else {
+            &self.directive
+        }
+    }
+
+    fn is(&self, directive: &str) -> bool {
+        self.directive.eq_ignore_ascii_case(directive)
+    }
+
+    /// Env and the yes/no switches are picked with Space rather than typed.
+    /// A switch read with some other value (`ForwardAgent $SOCK`) is
+    /// left as text.
+    fn is_choice(&self) -> bool {
+        self.is("Env") || (self.raw.is_none() && FLAG_DIRECTIVES.iter().any(|d| self.is(d)))
+    }
+}
+
+impl EditState {
+    fn new(host: HostEntry, index: Option<usize>) -> Self {
+        Self {
+            rows: edit_rows(&host),
+            original: host,
+            index,
+            field_index: 0,
+            warning: None,
+            reveal_password: false,
+            cursor: usize::MAX,
+            path_completion: None,
+        }
+    }
+
+    fn current_row(&self) -> &EditRow {
+        &self.rows[self.field_index]
+    }
+
+    /// The text of the highlighted row; choice rows (Env, yes/no
+    /// switches) have none.
+    fn current_value_mut(&mut self) -> Option<&mut String> {
+        let row = &mut self.rows[self.field_index];
+        (!row.is_choice()).then_some(&mut row.value)
+    }
+
+    /// Types `c` into the highlighted field. Port only takes digits up to
+    /// 65535; empty is still fine and means the default.
+    fn insert_char(&mut self, c: char) {
+        self.warning = None;
+        let cursor = self.cursor();
+        let mut typed = self.current_row().value.clone();
+        typed.insert(byte_offset(&typed, cursor), c);
+        if self.current_row().is("Port") {
+            if !c.is_ascii_digit() {
+                self.warning = Some("Port must be a number".to_string());
+                return;
+            }
+            if typed.parse::<u32>().is_ok_and(|p| p > 65535) {
+                self.warning = Some("Port can't be above 65535".to_string());
+                return;
+            }
+        }
+        if self.current_row().is("ProxyJump") && c.is_whitespace() {
+            self.warning = Some("Separate ProxyJump hops with commas, not spaces".to_string());
+            return;
+        }
+        if let Some(val) = self.current_value_mut() {
+            *val = typed;
+            self.cursor = cursor + 1;
+        }
+    }
+
+    /// Inserts pasted text at the cursor as if typed. Config values are
+    /// single-line, so line breaks are dropped.
+    fn paste(&mut self, text: &str) {
+        for c in text.chars().filter(|c| !matches!(c, '\n' | '\r')) {
+            self.insert_char(c);
+        }
+    }
+
+    /// Where typing goes in the highlighted row, as a char index.
+    fn cursor(&self) -> usize {
+        self.cursor.min(self.current_row().value.chars().count())
+    }
+
+    /// Moves the cursor `delta` chars, stopping at either end of the value.
+    fn move_cursor(&mut self, delta: isize) {
+        self.cursor = self.cursor().saturating_add_signed(delta).min(self.current_row().value.chars().count());
+    }
+
+    /// Deletes the char before the cursor (Backspace) or under it (Delete).
+    fn delete_char(&mut self, before: bool) {
+        let cursor = self.cursor();
+        if let Some(val) = self.current_value_mut()
+            && let Some(at) = delete_at(val, cursor, before)
+        {
+            self.cursor = at;
+        }
+    }
+
+    /// Tab in an IdentityFile row: fills in the file or directory the typed
+    /// path starts, or on a repeated Tab the next one. Returns false, so
+    /// Tab moves on, when the field is empty or already names the only
+    /// match.
+    fn complete_path(&mut self) -> bool {
+        if !self.current_row().is("IdentityFile") {
+            return false;
+        }
+        let value = self.current_row().value.clone();
+        let cycling = match &mut self.path_completion {
+            Some((candidates, index)) if candidates.get(*index) == Some(&value) && candidates.len() > 1 => {
+                *index = (*index + 1) % candidates.len();
+                Some(candidates[*index].clone())
+            }
+            _ => None,
+        };
+        let next = match cycling {
+            Some(next) => next,
+            None => {
+                let candidates = path_candidates(&value);
+                if value.is_empty() || candidates.is_empty() || candidates == [value.clone()] {
+                    self.path_completion = None;
+                    return false;
                 }
-                app.last_key = Some(key.code);
-                app.last_key_time = Some(now);
+                let first = candidates[0].clone();
+                self.path_completion = Some((candidates, 0));
+                first
+            }
+        };
+        self.rows[self.field_index].value = next;
+        self.cursor = usize::MAX;
+        true
+    }
+
+    /// The paths being cycled through while the field still shows one of
+    /// them, with the index of that one, for the popup.
+    fn path_choices(&self) -> Option<(&[String], usize)> {
+        let (candidates, index) = self.path_completion.as_ref()?;
+        (candidates.len() > 1 && candidates.get(*index) == Some(&self.current_row().value))
+            .then_some((candidates.as_slice(), *index))
+    }
+
+    /// Steps a choice row to its next value: Env through the environments,
+    /// a switch through unset, yes and no.
+    fn cycle_choice(&mut self) {
+        let row = &mut self.rows[self.field_index];
+        row.value = if row.is("Env") {
+            Env::cycle(Env::parse(&row.value)).map(Env::as_str).unwrap_or_default().to_string()
+        } else {
+            match parse_yes_no(&row.value) {
+                None => "yes",
+                Some(true) => "no",
+                Some(false) => "",
             }
+            .to_string()
+        };
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.finished_host() != self.original
+    }
+
+    /// Moves the highlight by `delta` rows, wrapping at the ends.
+    fn move_field(&mut self, delta: isize) {
+        self.warning = None;
+        let len = self.rows.len() as isize;
+        self.field_index = (self.field_index as isize + delta).rem_euclid(len) as usize;
+        self.cursor = usize::MAX;
+    }
+
+    /// Inserts an empty IdentityFile row below the highlighted one.
+    fn add_identity_file(&mut self) {
+        let directive = self.current_row().directive.clone();
+        if self.current_row().is("IdentityFile") || self.current_row().is("SshOption") {
+            self.field_index += 1;
+            self.rows.insert(self.field_index, EditRow::new(&directive, "", None));
+            self.cursor = usize::MAX;
         }
     }
 
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
-    Ok(())
+    /// Appends a row for a directive the form doesn't list and highlights it.
+    fn add_directive(&mut self) {
+        self.rows.push(EditRow::new("", "", None));
+        self.field_index = self.rows.len() - 1;
+        self.cursor = usize::MAX;
+    }
+
+    /// Drops the highlighted row if the host can do without it: an extra
+    /// IdentityFile or a directive outside `FORM_DIRECTIVES`. Rows the form
+    /// always shows are just cleared.
+    fn remove_row(&mut self) {
+        let row = self.current_row();
+        let fixed = row.is("Host") || FORM_DIRECTIVES.iter().any(|d| row.is(d));
+        let spare = row.is("IdentityFile") && self.rows.iter().filter(|r| r.is("IdentityFile")).count() > 1;
+        self.cursor = usize::MAX;
+        if fixed && !spare {
+            self.rows[self.field_index].value.clear();
+            return;
+        }
+        self.rows.remove(self.field_index);
+        // Stay within the IdentityFile rows after removing the last of them.
+        if spare && self.rows.get(self.field_index).is_none_or(|next| !next.is("IdentityFile")) {
+            self.field_index -= 1;
+        }
+        self.field_index = self.field_index.min(self.rows.len() - 1);
+    }
+
+    /// The host as it would be saved. Fields left blank are dropped rather
+    /// than written as empty directives, so an empty Port means the default.
+    /// Unchanged directive lines keep their original formatting.
+    fn finished_host(&self) -> HostEntry {
+        let mut host = HostEntry {
+            name: String::new(),
+            raw_lines: Vec::new(),
+            identity_files: Vec::new(),
+            hostname: None,
+            user: None,
+            port: None,
+            proxy_jump: None,
+            connect_timeout: None,
+            forward_agent: None,
+            forward_x11: None,
+            compression: None,
+            password: None,
+            env: None,
+            ssh_options: Vec::new(),
+            ..self.original.clone()
+        };
+        let non_empty = |v: &str| (!v.is_empty()).then(|| v.to_string());
+
+        let by_line: BTreeMap<usize, &EditRow> = self.rows.iter().filter_map(|r| r.raw.map(|i| (i, r))).collect();
+        let mut lines: Vec<Option<String>> = self.original.raw_lines.iter().cloned().map(Some).collect();
+        for (i, keyword, value) in raw_directives(&self.original) {
+            lines[i] = match by_line.get(&i) {
+                Some(row) if row.value == value => continue,
+                Some(row) if !row.value.is_empty() => {
+                    let note = split_comment(split_directive(&self.original.raw_lines[i]).1).1;
+                    Some(format!("    {} {}{}", keyword, row.value, note.map(|n| format!("  {}", n)).unwrap_or_default()))
+                }
+                _ => None,
+            };
+        }
+
+        let mut added = Vec::new();
+        for row in self.rows.iter().filter(|r| r.raw.is_none()) {
+            let (directive, value) = if row.directive.is_empty() {
+                split_directive(&row.value)
+            } else {
+                (row.directive.as_str(), row.value.as_str())
+            };
+            if let Some(flag) = parse_yes_no(value)
+                && let Some(field) = host.flag_mut(directive)
+            {
+                *field = Some(flag);
+                continue;
+            }
+            match directive.to_ascii_lowercase().as_str() {
+                "host" => host.name = value.to_string(),
+                "hostname" => host.hostname = non_empty(value),
+                "user" => host.user = non_empty(value),
+                "port" => host.port = non_empty(value),
+                "identityfile" => host.identity_files.extend(non_empty(unquote(value))),
+                "proxyjump" => host.proxy_jump = non_empty(value),
+                "connecttimeout" => host.connect_timeout = non_empty(value),
+                "password" => host.password = non_empty(value),
+                "env" => host.env = Env::parse(value),
+                "sshoption" => host.ssh_options.extend(non_empty(value)),
+                _ if !directive.is_empty() && !value.is_empty() => {
+                    added.push(format!("    {} {}", directive, value));
+                }
+                _ => {}
+            }
+        }
+        host.raw_lines = lines.into_iter().flatten().chain(added).collect();
+        // The keyring entry is keyed by name, so a rename or a new password
+        // has to be stored again.
+        host.password_in_keyring = self.original.password_in_keyring
+            && host.password == self.original.password
+            && host.name == self.original.name;
+        host
+    }
 }
 
-fn get_edit_field_mut<'a>(host: &'a mut HostEntry, idx: usize) -> Option<&'a mut String> {
-    match idx {
-        0 => Some(&mut host.name),
-        1 => {
-            if host.hostname.is_none() { host.hostname = Some(String::new()); }
-            host.hostname.as_mut()
-        },
-        2 => {
-            if host.user.is_none() { host.user = Some(String::new()); }
-            host.user.as_mut()
-        },
-        3 => {
-            if host.port.is_none() { host.port = Some(String::new()); }
-            host.port.as_mut()
-        },
-        4 => {
-            if host.identity_file.is_none() { host.identity_file = Some(String::new()); }
-            host.identity_file.as_mut()
-        },
-        5 => {
-            if host.password.is_none() { host.password = Some(String::new()); }
-            host.password.as_mut()
-        },
-        _ => None,
+/// Byte index of char `index` in `s`, or its length past the end.
+fn byte_offset(s: &str, index: usize) -> usize {
+    s.char_indices().nth(index).map_or(s.len(), |(i, _)| i)
+}
+
+/// Removes the char before char index `cursor` in `value` (`before`, for
+/// Backspace) or at it (Delete). Returns where the cursor ends up, or
+/// `None` if there was nothing to delete.
+fn delete_at(value: &mut String, cursor: usize, before: bool) -> Option<usize> {
+    let at = if before { cursor.checked_sub(1)? } else { cursor };
+    (at < value.chars().count()).then(|| {
+        value.remove(byte_offset(value, at));
+        at
+    })
+}
+
+/// The edit form for `host`: the Host row, one or more rows for each of
+/// `FORM_DIRECTIVES`, then the block's other directives.
+fn edit_rows(host: &HostEntry) -> Vec<EditRow> {
+    let mut rows = vec![EditRow::new("Host", host.name.clone(), None)];
+    let mut shown = BTreeSet::new();
+    for &directive in FORM_DIRECTIVES {
+        let modelled = |value: &Option<String>| value.iter().map(|v| (v.clone(), None)).collect::<Vec<_>>();
+        let values: Vec<(String, Option<usize>)> = match directive {
+            "HostName" => modelled(&host.hostname),
+            "User" => modelled(&host.user),
+            "Port" => modelled(&host.port),
+            "ProxyJump" => modelled(&host.proxy_jump),
+            "ConnectTimeout" => modelled(&host.connect_timeout),
+            // Unset switches fall through to any line with another value.
+            _ if host.flag(directive).flatten().is_some() => {
+                modelled(&host.flag(directive).flatten().map(|flag| yes_no(flag).to_string()))
+            }
+            "Password" => modelled(&host.password),
+            "Env" => modelled(&host.env.map(|e| e.as_str().to_string())),
+            "IdentityFile" => host.identity_files.iter().map(|v| (v.clone(), None)).collect(),
+            "SshOption" => host.ssh_options.iter().map(|v| (v.clone(), None)).collect(),
+            _ => raw_directives(host)
+                .filter(|(_, keyword, _)| keyword.eq_ignore_ascii_case(directive))
+                .map(|(i, _, value)| (value, Some(i)))
+                .collect(),
+        };
+        if values.is_empty() {
+            rows.push(EditRow::new(directive, "", None));
+        }
+        for (value, raw) in values {
+            shown.extend(raw);
+            rows.push(EditRow::new(directive, value, raw));
+        }
+    }
+    for (i, keyword, value) in raw_directives(host) {
+        if !shown.contains(&i) {
+            rows.push(EditRow::new(&keyword, value, Some(i)));
+        }
+    }
+    rows
+}
+
+/// The directive lines among a host's `raw_lines` as (line, keyword, value),
+/// skipping comments.
+fn raw_directives(host: &HostEntry) -> impl Iterator<Item = (usize, String, String)> + '_ {
+    host.raw_lines.iter().enumerate().filter_map(|(i, line)| {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return None;
+        }
+        let (keyword, value) = split_directive(trimmed);
+        Some((i, keyword.to_string(), split_comment(value).0.to_string()))
+    })
+}
+
+impl AppState {
+    fn new(hosts: Vec<HostEntry>, settings: Settings) -> Self {
+        Self {
+            hosts,
+            selected: 0,
+            theme: settings.theme.unwrap_or(DARK_THEME),
+            last_key: None,
+            last_key_time: None,
+            edit_mode: None,
+            status_message: None,
+            status_scroll: None,
+            settings,
+            marked: Vec::new(),
+            flash: None,
+            save_error: None,
+            dirty: false,
+            quit: false,
+            env_filter: None,
+            group_filter: None,
+            collapsed: BTreeSet::new(),
+            exposed_keys: BTreeSet::new(),
+            sort: SortOrder::File,
+            show_help: false,
+            log_view: None,
+            web_picker: None,
+            prompt: None,
+            confirm: None,
+            companion: false,
+            preamble: String::new(),
+            included: Vec::new(),
+            search: None,
+            pending_launch: None,
+            pending_keygen: None,
+            scroll_offset: 0,
+            list_area: Rect::default(),
+            last_click: None,
+            prober: None,
+            reachable: BTreeMap::new(),
+            disk_mtimes: Vec::new(),
+            last_connected: BTreeMap::new(),
+            undo: Vec::new(),
+            redo: Vec::new(),
+            read_only: read_only(),
+            print_on_exit: Vec::new(),
+        }
+    }
+
+    fn ask(&mut self, prompt: String, on_yes: Action) {
+        self.confirm = Some(Confirm { prompt, on_yes });
+    }
+
+    /// Closes the open confirmation, running its action if the answer was yes.
+    fn answer_confirm(&mut self, yes: bool) {
+        if let Some(confirm) = self.confirm.take()
+            && yes
+        {
+            self.perform(confirm.on_yes);
+        }
+    }
+
+    fn perform(&mut self, action: Action) {
+        match action {
+            Action::DiscardEdit => self.edit_mode = None,
+            Action::DeleteHost(index) => {
+                self.checkpoint();
+                let removed = self.hosts.remove(index);
+                self.marked.retain(|name| *name != removed.name);
+                if self.selected > index {
+                    self.selected -= 1;
+                }
+                self.clamp_selection();
+                self.snap_selection();
+                self.save();
+            }
+            Action::Launch(launch) => self.pending_launch = Some(launch),
+            Action::Quit => self.quit = true,
+            Action::Reload => self.reload(),
+            Action::SecureKeys(index) => {
+                let report: Vec<String> = self.hosts[index].identity_files.iter().map(|f| secure_keyfile(f)).collect();
+                self.set_status(report.join("\n\n"));
+                self.check_keys();
+            }
+            Action::GenerateKey(keygen) => self.pending_keygen = Some(keygen),
+            Action::DeleteMarked => self.delete_marked(),
+            Action::SecureKeyFiles { marked_only } => self.secure_key_files(marked_only),
+        }
+    }
+
+    /// `d`: asks before deleting the marked hosts, or the selected one if
+    /// none are marked.
+    fn ask_delete(&mut self) {
+        if self.marked.is_empty() {
+            let prompt = format!("Delete host {}?", self.hosts[self.selected].name);
+            return self.ask(prompt, Action::DeleteHost(self.selected));
+        }
+        let prompt = format!("Delete these {} marked hosts?\n\n{}", self.marked.len(), self.marked.join("\n"));
+        self.ask(prompt, Action::DeleteMarked);
+    }
+
+    /// Removes the marked hosts in one undo step, keeping the selection on
+    /// the same host if it survives.
+    fn delete_marked(&mut self) {
+        self.checkpoint();
+        let selected = self.hosts.get(self.selected).map(|h| h.name.clone());
+        let count = self.marked.len();
+        self.hosts.retain(|h| !self.marked.contains(&h.name));
+        self.marked.clear();
+        self.selected = selected.and_then(|name| self.hosts.iter().position(|h| h.name == name)).unwrap_or(0);
+        self.clamp_selection();
+        self.snap_selection();
+        self.save();
+        if self.save_error.is_none() {
+            self.show_flash(format!("✔ Deleted {} hosts.", count));
+        }
+    }
+
+    /// Copies the selected host to just below it under a fresh name, saves,
+    /// and opens the copy for editing.
+    fn duplicate_selected(&mut self) {
+        let mut copy = self.hosts[self.selected].clone();
+        copy.name = unique_copy_name(&self.hosts, &copy.name);
+        copy.password_in_keyring = false;
+        self.checkpoint();
+        let index = self.selected + 1;
+        self.hosts.insert(index, copy.clone());
+        self.selected = index;
+        self.save();
+        self.edit_mode = Some(EditState::new(copy, Some(index)));
+    }
+
+    /// Every IdentityFile in the config (or of the marked hosts) once, even
+    /// if several hosts (or spellings, like `~/.ssh/id` and the full path)
+    /// share it, with the names of the hosts that use it.
+    fn key_files(&self, marked_only: bool) -> Vec<(String, Vec<&str>)> {
+        let mut files: Vec<(PathBuf, String, Vec<&str>)> = Vec::new();
+        for host in self.hosts.iter().filter(|h| !marked_only || self.marked.contains(&h.name)) {
+            for file in &host.identity_files {
+                let path = expand_path(file);
+                match files.iter_mut().find(|(seen, _, _)| *seen == path) {
+                    Some((_, _, users)) => users.push(&host.name),
+                    None => files.push((path, file.clone(), vec![&host.name])),
+                }
+            }
+        }
+        files.into_iter().map(|(_, file, users)| (file, users)).collect()
+    }
+
+    /// `K`, or `k` with hosts marked: lists the key files and asks before
+    /// fixing them all.
+    fn ask_secure_key_files(&mut self, marked_only: bool) {
+        let files = self.key_files(marked_only);
+        if files.is_empty() {
+            let which = if marked_only { "marked host" } else { "host" };
+            return self.set_status(format!("No {} has an IdentityFile.", which));
+        }
+        let list: Vec<String> = files.iter().map(|(file, users)| format!("{} ({})", file, users.join(", "))).collect();
+        let prompt = format!(
+            "Restrict these {} key files to your user, as k does?\n\n{}",
+            files.len(),
+            list.join("\n")
+        );
+        self.ask(prompt, Action::SecureKeyFiles { marked_only });
+    }
+
+    /// Runs `k`'s fix on each of `key_files` and reports which worked:
+    /// the failures in full, then the files that were fixed.
+    fn secure_key_files(&mut self, marked_only: bool) {
+        let files = self.key_files(marked_only);
+        let (mut fixed, mut failed) = (Vec::new(), Vec::new());
+        for (file, _) in &files {
+            let report = secure_keyfile(file);
+            if report.starts_with('✔') {
+                fixed.push(format!("  {}", file));
+            } else {
+                failed.push(report.trim_end().to_string());
+            }
+        }
+        let mut report = if failed.is_empty() {
+            format!("✔ Fixed all {} key files.", files.len())
+        } else {
+            format!("⚠ Fixed {} of {} key files; {} failed.", fixed.len(), files.len(), failed.len())
+        };
+        if !failed.is_empty() {
+            report.push_str(&format!("\n\nFailed:\n{}", failed.join("\n\n")));
+        }
+        if !fixed.is_empty() {
+            report.push_str(&format!("\n\nFixed:\n{}", fixed.join("\n")));
+        }
+        self.set_status(report);
+        self.check_keys();
+    }
+
+    /// Lists the commands `k` would run on the selected host's key files
+    /// and asks before running any of them. With hosts marked it asks about
+    /// all of theirs instead.
+    fn ask_secure_keys(&mut self) {
+        if !self.marked.is_empty() {
+            return self.ask_secure_key_files(true);
+        }
+        let identity_files = &self.hosts[self.selected].identity_files;
+        if !identity_files.is_empty() {
+            let commands: Vec<String> = identity_files.iter().flat_map(|f| keyfile_commands(&expand_path(f))).collect();
+            let prompt = format!("Run these to restrict the key files?\n\n{}", commands.join("\n"));
+            self.ask(prompt, Action::SecureKeys(self.selected));
+        } else {
+            self.set_status(no_identity_file(&self.hosts[self.selected]));
+        }
+    }
+
+    /// Switches between the dark and light themes and remembers the choice
+    /// in the settings file.
+    fn toggle_theme(&mut self) {
+        self.theme = if self.theme == DARK_THEME { LIGHT_THEME } else { DARK_THEME };
+        self.settings.theme = Some(self.theme);
+        match save_setting(&settings_path(), "theme", self.theme.name) {
+            Ok(()) => self.show_flash(format!("✔ {} theme.", self.theme.name)),
+            Err(e) => self.set_status(format!("❌ Couldn't save the theme to {}: {}", settings_path().display(), e)),
+        }
+    }
+
+    /// Comments the selected host's block out of the config, or back in.
+    /// Its settings stay in the file either way.
+    fn toggle_disabled(&mut self) {
+        self.checkpoint();
+        let host = &mut self.hosts[self.selected];
+        host.disabled = !host.disabled;
+        let msg = format!("✔ {} {}.", host.name, if host.disabled { "disabled" } else { "enabled" });
+        self.save();
+        if self.save_error.is_none() {
+            self.show_flash(msg);
+        }
+    }
+
+    /// The marked hosts, or else the selected one, as ssh_config text.
+    /// Passwords are left out unless `with_passwords` is set.
+    fn export_text(&self, with_passwords: bool) -> String {
+        let mut hosts: Vec<HostEntry> = if self.marked.is_empty() {
+            self.selected_host().into_iter().cloned().collect()
+        } else {
+            self.marked_hosts().into_iter().cloned().collect()
+        };
+        for host in &mut hosts {
+            // Never write the keyring marker; the other side has no entry.
+            host.password_in_keyring = false;
+            if !with_passwords {
+                host.password = None;
+            }
+        }
+        serialize_hosts("", &hosts, !self.companion)
+    }
+
+    /// Copies `export_text` to the clipboard, or shows it if that fails.
+    fn export(&mut self, with_passwords: bool) {
+        let text = self.export_text(with_passwords);
+        if text.is_empty() {
+            return;
+        }
+        let count = self.marked_hosts().len().max(1);
+        match copy_to_clipboard(&text) {
+            Ok(()) => self.show_flash(format!("Copied {} host(s) to the clipboard.", count)),
+            Err(e) => self.set_status(format!("❌ Couldn't copy to the clipboard ({}). Here it is:\n\n{}", e, text)),
+        }
+    }
+
+    /// Copies the selected host's address (its first HostName, or the alias
+    /// without one) to the clipboard. Without a clipboard it's printed on
+    /// quit instead, where it can be copied from the terminal.
+    fn copy_hostname(&mut self) {
+        let Some(host) = self.selected_host() else {
+            return;
+        };
+        let value = host.primary_hostname().to_string();
+        match copy_to_clipboard(&value) {
+            Ok(()) => self.show_flash(format!("Copied {}", value)),
+            Err(e) => {
+                self.set_status(format!(
+                    "❌ Couldn't copy to the clipboard ({}). {} will be printed when you quit.",
+                    e, value
+                ));
+                self.print_on_exit.push(value);
+            }
+        }
+    }
+
+    /// Merges the hosts in ssh_config `text` into the list and saves.
+    /// Returns the summary to show.
+    fn import(&mut self, text: &str) -> Result<String, String> {
+        let imported = HostEntry::parse_ssh_config(text);
+        if imported.is_empty() {
+            return Err("no Host blocks found".to_string());
+        }
+        let mut hosts = self.hosts.clone();
+        let summary = merge_imported(&mut hosts, imported);
+        let mut seen = BTreeSet::new();
+        if let Some(dup) = hosts.iter().filter(|h| summary.added.contains(&h.name)).find(|h| !seen.insert(&h.name)) {
+            return Err(format!("{} would be defined twice", dup.name));
+        }
+        if !summary.added.is_empty() {
+            self.checkpoint();
+            self.hosts = hosts;
+            self.save();
+        }
+        Ok(summary.to_string())
+    }
+
+    /// Queues `ssh` to the selected host, through `sshpass` if the settings
+    /// ask for it and the host has a password.
+    fn connect_selected(&mut self) {
+        let host = self.hosts[self.selected].clone();
+        if host.match_block {
+            self.show_flash(MATCH_READ_ONLY.to_string());
+            return;
+        }
+        let mut command = ssh_command(&host, &self.settings, &self.inherited_directives(self.selected));
+        let unset = unset_template_fields(&host, &self.settings);
+        if !unset.is_empty() {
+            let msg = format!("⚠ {} has no {}, so the connect command ran without it.", host.name, unset.join(" or "));
+            self.set_status(msg);
+        }
+        if self.settings.sshpass && host.password.is_some() {
+            if on_path("sshpass") {
+                command = sshpass_command(command);
+            } else {
+                self.set_status("⚠ sshpass isn't installed, so this used plain ssh; type the password when asked.".to_string());
+            }
+        }
+        self.pending_launch = Some(Launch { index: self.selected, action: "connect", command });
+    }
+
+    fn open_prompt(&mut self, kind: PromptKind, initial: String) {
+        self.prompt = Some(Prompt {
+            kind,
+            input: initial,
+            cursor: usize::MAX,
+            error: None,
+        });
+    }
+
+    /// Applies a submitted prompt. Returns an error to show in the prompt
+    /// (keeping it open) when the input is rejected.
+    fn submit_prompt(&mut self, kind: PromptKind, input: String) -> Result<(), String> {
+        match kind {
+            PromptKind::Hostname => {
+                let value = input.trim();
+                if value.is_empty() {
+                    return Err("HostName cannot be empty".to_string());
+                }
+                if value.contains(char::is_whitespace) {
+                    return Err("HostName can't contain spaces; ssh would reject it".to_string());
+                }
+                self.checkpoint();
+                self.hosts[self.selected].hostname = Some(value.to_string());
+                self.save();
+            }
+            PromptKind::SshArgs => {
+                // Typed options go first so they win over the host's, and
+                // all of them before the destination or ssh takes them for
+                // the remote command.
+                let mut options = split_args(&input)?;
+                options.extend(self.launch_options(self.selected));
+                let mut command = with_options("ssh", &options);
+                command.push(self.hosts[self.selected].alias().to_string());
+                let prompt = format!("Run {}?", join_args(&command));
+                self.ask(prompt, Action::Launch(Launch { index: self.selected, action: "connect", command }));
+            }
+            PromptKind::Import => {
+                let path = expand_path(input.trim());
+                let text = fs::read_to_string(&path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+                let summary = self.import(&text)?;
+                self.set_status(summary);
+            }
+            PromptKind::RemoteCommand => {
+                let remote = input.trim();
+                if remote.is_empty() {
+                    return Err("Type a command to run".to_string());
+                }
+                self.pending_launch = Some(Launch {
+                    index: self.selected,
+                    action: "command",
+                    command: remote_command(&self.hosts[self.selected], remote, &self.launch_options(self.selected)),
+                });
+            }
+            PromptKind::KeyPath => {
+                let path = input.trim();
+                if path.is_empty() {
+                    return Err("Key file cannot be empty".to_string());
+                }
+                let keygen = KeyGen { index: self.selected, path: path.to_string() };
+                if expand_path(path).exists() {
+                    let prompt = format!("{} already exists. Replace it with a new key? The old key is lost.", path);
+                    self.ask(prompt, Action::GenerateKey(keygen));
+                } else {
+                    self.pending_keygen = Some(keygen);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes in what `ssh-keygen` did for `keygen`. On success the key
+    /// becomes the host's first IdentityFile and gets the same permission
+    /// fix as `k`; either way its output ends up in the status popup.
+    fn key_generated(&mut self, keygen: KeyGen, result: io::Result<std::process::Output>) {
+        let output = match result {
+            Ok(output) => output,
+            Err(e) => return self.set_status(launch_error("ssh-keygen", &e)),
+        };
+        let text = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+        if !output.status.success() {
+            let code = output.status.code().unwrap_or(-1);
+            return self.set_status(format!("❌ ssh-keygen failed with code {}\n\n{}", code, text.trim_end()));
+        }
+        let files = &self.hosts[keygen.index].identity_files;
+        if files.first() != Some(&keygen.path) {
+            self.checkpoint();
+            let host = &mut self.hosts[keygen.index];
+            host.identity_files.retain(|f| *f != keygen.path);
+            host.identity_files.insert(0, keygen.path.clone());
+            self.save();
+        }
+        let secured = secure_keyfile(&keygen.path);
+        self.check_keys();
+        self.set_status(format!("{}\n\n{}", text.trim_end(), secured));
+    }
+
+    /// Opens the viewer on the last `LOG_VIEW_LINES` connect log entries.
+    fn open_log(&mut self) {
+        match fs::read_to_string(connect_log_path()) {
+            Ok(text) if !text.trim().is_empty() => {
+                let lines: Vec<&str> = text.lines().filter(|l| !l.trim().is_empty()).collect();
+                let tail = &lines[lines.len().saturating_sub(LOG_VIEW_LINES)..];
+                self.log_view = Some(tail.iter().map(|l| describe_log_line(l)).collect());
+            }
+            Ok(_) => self.set_status("No connections logged yet.".to_string()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.set_status("No connections logged yet.".to_string()),
+            Err(e) => self.set_status(format!("❌ Can't read {}: {}", connect_log_path().display(), e)),
+        }
+    }
+
+    fn show_flash(&mut self, msg: String) {
+        self.flash = Some((msg, Instant::now()));
+    }
+
+    fn set_status(&mut self, msg: String) {
+        self.status_message = Some((msg, Instant::now()));
+        self.status_scroll = None;
+    }
+
+    fn open_url(&mut self, url: &str) {
+        match open_in_browser(url) {
+            Ok(()) => self.show_flash(format!("Opened {}", url)),
+            Err(e) => self.set_status(format!("❌ Failed to open {}: {}", url, e)),
+        }
+    }
+
+    /// Indices into `hosts` that the list currently shows, in display order.
+    fn visible_indices(&self) -> Vec<usize> {
+        self.list_rows().iter().filter_map(ListRow::host).collect()
+    }
+
+    /// The hosts numbered 1–9 in the list for quick selection: the first
+    /// nine host rows, skipping group headers.
+    fn quick_hosts(&self) -> Vec<usize> {
+        self.list_rows()
+            .iter()
+            .filter_map(|row| match row {
+                ListRow::Host(i) => Some(*i),
+                _ => None,
+            })
+            .take(9)
+            .collect()
+    }
+
+    /// Digit `n` selects the host numbered `n`, and with `digit_connects`
+    /// set also connects to it.
+    fn quick_select(&mut self, n: usize) {
+        let Some(index) = n.checked_sub(1).and_then(|i| self.quick_hosts().get(i).copied()) else {
+            return;
+        };
+        self.selected = index;
+        if self.settings.digit_connects {
+            self.connect_selected();
+        }
+    }
+
+    /// The rows of the host list. Once any host has a group, hosts are
+    /// listed under a header per group, ungrouped ones last; a collapsed
+    /// group shows only its header.
+    fn list_rows(&self) -> Vec<ListRow> {
+        let hosts = self.filtered_indices();
+        if !self.hosts.iter().any(|h| h.group.is_some()) {
+            return hosts.into_iter().map(ListRow::Host).collect();
+        }
+        let mut groups: Vec<(&str, Vec<usize>)> = Vec::new();
+        for i in hosts {
+            let name = self.hosts[i].group.as_deref().unwrap_or(UNGROUPED);
+            match groups.iter_mut().find(|(g, _)| *g == name) {
+                Some((_, members)) => members.push(i),
+                None => groups.push((name, vec![i])),
+            }
+        }
+        // Stable, so the groups otherwise keep the order they first appear in.
+        groups.sort_by_key(|(name, _)| *name == UNGROUPED);
+        let mut rows = Vec::new();
+        for (name, members) in groups {
+            let (group, count) = (name.to_string(), members.len());
+            if self.collapsed.contains(name) {
+                rows.push(ListRow::Collapsed { group, count, first: members[0] });
+            } else {
+                rows.push(ListRow::Header { group, count });
+                rows.extend(members.into_iter().map(ListRow::Host));
+            }
+        }
+        rows
+    }
+
+    /// The view to come back to next time, as `key = value` lines for
+    /// `state_path`: the selected host by name, the sort order and filters.
+    fn state_text(&self) -> String {
+        let mut out = String::new();
+        if let Some(host) = self.selected_host() {
+            out.push_str(&format!("selected = {}\n", host.name));
+        }
+        out.push_str(&format!("sort = {}\n", self.sort.as_str()));
+        if let Some(env) = self.env_filter {
+            out.push_str(&format!("env_filter = {}\n", env.as_str()));
+        }
+        if let Some(group) = &self.group_filter {
+            out.push_str(&format!("group_filter = {}\n", group));
+        }
+        out
+    }
+
+    /// Puts back a view saved by `state_text`. The host is found by name,
+    /// so reordering the config doesn't matter. If it's gone the first host
+    /// is selected instead, or the first the filters let through.
+    fn restore_state(&mut self, text: &str) {
+        let mut selected = None;
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "selected" => selected = Some(value),
+                "sort" => self.sort = SortOrder::parse(value).unwrap_or_default(),
+                "env_filter" => self.env_filter = Env::parse(value),
+                "group_filter" => self.group_filter = (!value.is_empty()).then(|| value.to_string()),
+                _ => {}
+            }
+        }
+        self.selected = selected.and_then(|name| self.hosts.iter().position(|h| h.name == name)).unwrap_or(0);
+        self.snap_selection();
+    }
+
+    /// Moves the group filter on to the next group in file order, and from
+    /// the last back to showing every group.
+    fn cycle_group_filter(&mut self) {
+        let mut groups: Vec<&String> = Vec::new();
+        for group in self.hosts.iter().filter_map(|h| h.group.as_ref()) {
+            if !groups.contains(&group) {
+                groups.push(group);
+            }
+        }
+        if groups.is_empty() {
+            self.set_status("No hosts have a # Group: line to filter by.".to_string());
+            return;
+        }
+        let next = match self.group_filter.as_ref().and_then(|current| groups.iter().position(|g| *g == current)) {
+            Some(i) => groups.get(i + 1).map(|g| g.to_string()),
+            None if self.group_filter.is_some() => None,
+            None => Some(groups[0].clone()),
+        };
+        self.group_filter = next;
+        self.snap_selection();
+    }
+
+    /// Hosts passing the env and group filters and search, in display order.
+    fn filtered_indices(&self) -> Vec<usize> {
+        let query = self.search.as_deref().unwrap_or_default().to_lowercase();
+        let mut scores = BTreeMap::new();
+        let mut visible: Vec<usize> = (0..self.hosts.len())
+            .filter(|&i| self.env_filter.is_none_or(|env| self.hosts[i].env == Some(env)))
+            .filter(|&i| self.group_filter.is_none() || self.hosts[i].group == self.group_filter)
+            .filter(|&i| {
+                if query.is_empty() {
+                    return true;
+                }
+                let found = self.hosts[i].search_match(&query);
+                scores.extend(found.as_ref().map(|m| (i, m.score)));
+                found.is_some()
+            })
+            .collect();
+        // Stable sorts, so ties keep their file order.
+        match self.sort {
+            SortOrder::File => {}
+            SortOrder::Name => visible.sort_by_cached_key(|&i| self.hosts[i].name.to_lowercase()),
+            SortOrder::Recent => visible.sort_by_key(|&i| {
+                std::cmp::Reverse(self.last_connected.get(&self.hosts[i].name).copied())
+            }),
+        }
+        // Best matches first while searching; ties keep the order above.
+        if !query.is_empty() {
+            visible.sort_by_key(|i| std::cmp::Reverse(scores[i]));
+        }
+        visible
+    }
+
+    /// Feeds a key to the open search bar. Returns false for keys it leaves
+    /// to the list (navigation, Enter to connect).
+    fn handle_search_key(&mut self, key: KeyEvent) -> bool {
+        let Some(query) = self.search.as_mut() else {
+            return false;
+        };
+        match key.code {
+            KeyCode::Esc => self.search = None,
+            KeyCode::Backspace => {
+                query.pop();
+            }
+            KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => query.push(c),
+            _ => return false,
+        }
+        self.snap_selection();
+        true
+    }
+
+    /// The list colour for `host` and what it stands for in the legend. A
+    /// host's own `color` wins, then its Env, then a colour for its group,
+    /// then one for any of its tags; the legend leaves out per-host colours.
+    fn host_color(&self, host: &HostEntry) -> Option<(Color, Option<String>)> {
+        if let Some(color) = host.meta.color.as_deref().and_then(|c| c.parse::<Color>().ok()) {
+            return Some((color, None));
+        }
+        if let Some(env) = host.env {
+            return Some((self.theme.env_color(env), Some(env.as_str().to_string())));
+        }
+        host.group
+            .iter()
+            .chain(&host.meta.tags)
+            .find_map(|name| self.settings.color_for(name, &self.theme).map(|c| (c, Some(name.clone()))))
+    }
+
+    /// `● name` for each colour in use among the listed hosts, in order.
+    fn color_legend(&self) -> Vec<Span<'static>> {
+        let mut seen = BTreeSet::new();
+        let mut spans = Vec::new();
+        for i in self.visible_indices() {
+            if let Some((color, Some(label))) = self.host_color(&self.hosts[i])
+                && seen.insert(label.clone())
+            {
+                spans.push(Span::styled(format!(" ● {} ", label), Style::default().fg(color)));
+            }
+        }
+        spans
+    }
+
+    /// The highlighted host, if it is part of the current view. `None` on
+    /// a collapsed group, which stands in for its first host.
+    fn selected_host(&self) -> Option<&HostEntry> {
+        self.list_rows()
+            .contains(&ListRow::Host(self.selected))
+            .then(|| &self.hosts[self.selected])
+    }
+
+    /// The collapsed group whose header is highlighted, if any.
+    fn selected_collapsed_group(&self) -> Option<String> {
+        self.list_rows().into_iter().find_map(|row| match row {
+            ListRow::Collapsed { group, first, .. } if first == self.selected => Some(group),
+            _ => None,
+        })
+    }
+
+    /// Folds the selected host's group away, leaving its header selected.
+    fn collapse_selected_group(&mut self) {
+        if self.selected_host().is_none() || !self.hosts.iter().any(|h| h.group.is_some()) {
+            return;
+        }
+        let group = self.hosts[self.selected].group.clone().unwrap_or_else(|| UNGROUPED.to_string());
+        self.collapsed.insert(group.clone());
+        if let Some(ListRow::Collapsed { first, .. }) =
+            self.list_rows().into_iter().find(|row| matches!(row, ListRow::Collapsed { group: g, .. } if *g == group))
+        {
+            self.selected = first;
+        }
+    }
+
+    fn expand_selected_group(&mut self) {
+        if let Some(group) = self.selected_collapsed_group() {
+            self.collapsed.remove(&group);
+        }
+    }
+
+    /// The host list's checks ahead of its key `match`, given the key as
+    /// pressed and as `vim_key` reads it: with `vim_keys`, g and G jump to
+    /// the ends, and keys that don't apply in read-only mode or on a Match
+    /// block are refused. Returns whether the key was dealt with here.
+    fn intercept_list_key(&mut self, key: KeyEvent, list_key: KeyEvent) -> bool {
+        if self.settings.vim_keys
+            && key.modifiers.difference(KeyModifiers::SHIFT).is_empty()
+            && let KeyCode::Char(c @ ('g' | 'G')) = key.code
+        {
+            self.select_end(c == 'G');
+        } else if self.read_only && changes_config(list_key) {
+            self.show_flash("Read-only mode: the config can't be changed.".to_string());
+        } else if self.selected_host().is_some_and(|h| h.match_block) && uses_host(list_key) {
+            self.show_flash(MATCH_READ_ONLY.to_string());
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// `connect_options` for the host at `index`, for the launch paths
+    /// other than plain Enter to hand `with_options`.
+    fn launch_options(&self, index: usize) -> Vec<String> {
+        connect_options(&self.hosts[index], &self.settings, &self.inherited_directives(index))
+    }
+
+    /// `inherited_directives` for the host at `index`, from this config.
+    fn inherited_directives(&self, index: usize) -> BTreeSet<String> {
+        let mut preambles = vec![self.preamble.as_str()];
+        preambles.extend(self.included.iter().map(|f| f.preamble.as_str()));
+        inherited_directives(&preambles, &self.hosts, &self.hosts[index])
+    }
+
+    /// Selects the first host in the view, or the last with `last`.
+    fn select_end(&mut self, last: bool) {
+        let visible = self.visible_indices();
+        let end = if last { visible.last() } else { visible.first() };
+        if let Some(&index) = end {
+            self.selected = index;
+        }
+    }
+
+    /// Moves the highlight by `delta` rows within the view, wrapping at the
+    /// ends unless the settings say to stop there.
+    fn move_selection(&mut self, delta: isize) {
+        let visible = self.visible_indices();
+        if visible.is_empty() {
+            return;
+        }
+        let pos = visible.iter().position(|&i| i == self.selected).unwrap_or(0) as isize;
+        let len = visible.len() as isize;
+        let pos = if self.settings.stop_at_ends {
+            (pos + delta).clamp(0, len - 1)
+        } else {
+            (pos + delta).rem_euclid(len)
+        };
+        self.selected = visible[pos as usize];
+    }
+
+    /// Moves the selected host just past its neighbour in the view, `delta`
+    /// rows away, keeping it selected. Hosts a filter hides in between stay
+    /// where they are rather than trading places with anything. Stops at the
+    /// ends instead of wrapping. Returns whether anything moved.
+    fn move_host(&mut self, delta: isize) -> bool {
+        if self.selected_host().is_none() {
+            return false;
+        }
+        let visible = self.visible_indices();
+        let Some(pos) = visible.iter().position(|&i| i == self.selected) else {
+            return false;
+        };
+        let Some(&target) = pos.checked_add_signed(delta).and_then(|p| visible.get(p)) else {
+            return false;
+        };
+        if self.hosts[target].group != self.hosts[self.selected].group {
+            return false;
+        }
+        self.checkpoint();
+        // After the removal `target` is just past the neighbour either way.
+        let host = self.hosts.remove(self.selected);
+        self.hosts.insert(target, host);
+        self.selected = target;
+        true
+    }
+
+    /// Tab in the edit form: completes a key file path, or takes the
+    /// `edit_completion` suggestion, or else moves to the next field.
+    fn edit_tab(&mut self) {
+        let Some(edit) = self.edit_mode.as_mut() else {
+            return;
+        };
+        if edit.complete_path() {
+            // Filled in a key file path.
+        } else if let Some(full) = edit_completion(&self.hosts, edit) {
+            if let Some(val) = edit.current_value_mut() {
+                *val = full;
+            }
+            edit.cursor = usize::MAX;
+        } else {
+            edit.move_field(1);
+        }
+    }
+
+    /// Saves the edit form into `hosts` and closes it. If another block
+    /// already has exactly this name the form stays open with a warning:
+    /// ssh would only ever use the first, and the list would select the
+    /// wrong one. Repeated wildcard patterns are legitimate, so those only
+    /// get a warning after saving. An empty name, or one with spaces that
+    /// ssh would split into several patterns, is refused the same way.
+    fn finish_edit(&mut self) {
+        let Some(edit) = self.edit_mode.as_mut() else {
+            return;
+        };
+        let (index, mut host, changed) = (edit.index, edit.finished_host(), edit.is_dirty());
+        host.name = host.name.trim().to_string();
+        if host.name.is_empty() {
+            edit.warning = Some("Host name can't be empty".to_string());
+            return;
+        }
+        // A block that already had several patterns keeps them; anywhere
+        // else a space is more likely a typo than a second pattern.
+        if host.name.contains(char::is_whitespace) && edit.original.patterns().len() < 2 {
+            edit.warning = Some("Host name can't contain spaces; ssh would read each word as a pattern".to_string());
+            return;
+        }
+        if let Some(bad) = host.ssh_options.iter().find(|o| !o.contains(['=', ' '])) {
+            edit.warning = Some(format!("SshOption {} needs a value, as in ServerAliveInterval=30", bad));
+            return;
+        }
+        if host.connect_timeout.as_ref().is_some_and(|t| t.parse::<u32>().is_err()) {
+            edit.warning = Some("ConnectTimeout is a whole number of seconds".to_string());
+            return;
+        }
+        let others = || self.hosts.iter().enumerate().filter(|&(i, _)| Some(i) != index).map(|(_, h)| h);
+        let taken = host.concrete_patterns().into_iter().find(|p| others().any(|h| h.concrete_patterns().contains(p)));
+        if let Some(taken) = taken {
+            edit.warning = Some(format!("There is already a host named {}", taken));
+            return;
+        }
+        let clash = others().any(|h| h.name == host.name);
+        if changed {
+            self.checkpoint();
+        }
+        let name = host.name.clone();
+        if let Some(index) = index {
+            self.hosts[index] = host;
+        } else {
+            // Adding new host
+            self.hosts.push(host);
+            self.selected = self.hosts.len() - 1;
+        }
+        self.save();
+        self.edit_mode = None;
+        if clash {
+            self.set_status(format!("⚠ Host {} appears more than once; ssh applies each block in file order.", name));
+        }
+    }
+
+    /// Remembers the host list as it is now so `undo` can come back to it.
+    /// Call before changing `hosts`; a new change forgets anything undone.
+    fn checkpoint(&mut self) {
+        if self.undo.len() == UNDO_LIMIT {
+            self.undo.remove(0);
+        }
+        self.undo.push(self.hosts.clone());
+        self.redo.clear();
+    }
+
+    /// Puts back the host list from before the last change and saves it.
+    fn undo(&mut self) {
+        match self.undo.pop() {
+            Some(hosts) => {
+                let current = std::mem::replace(&mut self.hosts, hosts);
+                self.redo.push(current);
+                self.restored("Undone.");
+            }
+            None => self.show_flash("Nothing to undo.".to_string()),
+        }
+    }
+
+    /// Reapplies the last change `undo` took back.
+    fn redo(&mut self) {
+        match self.redo.pop() {
+            Some(hosts) => {
+                let current = std::mem::replace(&mut self.hosts, hosts);
+                self.undo.push(current);
+                self.restored("Redone.");
+            }
+            None => self.show_flash("Nothing to redo.".to_string()),
+        }
+    }
+
+    fn restored(&mut self, message: &str) {
+        self.clamp_selection();
+        self.snap_selection();
+        self.save();
+        if !self.dirty {
+            self.show_flash(message.to_string());
+        }
+    }
+
+    /// Clicking a host selects it and double-clicking connects; the wheel
+    /// moves the selection. Ignored while anything covers the host list.
+    fn handle_mouse(&mut self, mouse: MouseEvent) {
+        if self.edit_mode.is_some()
+            || self.confirm.is_some()
+            || self.show_help
+            || self.log_view.is_some()
+            || self.prompt.is_some()
+            || self.web_picker.is_some()
+            || self.status_message.is_some()
+        {
+            return;
+        }
+        match mouse.kind {
+            MouseEventKind::ScrollUp => self.move_selection(-1),
+            MouseEventKind::ScrollDown => self.move_selection(1),
+            MouseEventKind::Down(MouseButton::Left) => {
+                let Some(index) = self.host_at(mouse.column, mouse.row) else {
+                    return;
+                };
+                let now = Instant::now();
+                let double = self
+                    .last_click
+                    .is_some_and(|(prev, at)| prev == index && now.duration_since(at) < DOUBLE_CLICK);
+                self.selected = index;
+                if double {
+                    self.last_click = None;
+                    if self.selected_host().is_some() {
+                        self.connect_selected();
+                    } else {
+                        self.expand_selected_group();
+                    }
+                } else {
+                    self.last_click = Some((index, now));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The host drawn at screen cell (`column`, `row`), if any.
+    fn host_at(&self, column: u16, row: u16) -> Option<usize> {
+        let area = self.list_area;
+        let inside = |pos: u16, start: u16, len: u16| pos > start && pos + 1 < start + len;
+        if !inside(column, area.x, area.width) || !inside(row, area.y, area.height) {
+            return None;
+        }
+        let line = (row - area.y - 1) as usize + self.scroll_offset;
+        self.list_rows().get(line).and_then(ListRow::host)
+    }
+
+    /// Moves the highlight onto the view if a filter change hid it.
+    fn snap_selection(&mut self) {
+        let visible = self.visible_indices();
+        if !visible.contains(&self.selected) && let Some(&first) = visible.first() {
+            self.selected = first;
+        }
+    }
+
+    fn toggle_mark(&mut self) {
+        let name = self.hosts[self.selected].name.clone();
+        if let Some(pos) = self.marked.iter().position(|n| *n == name) {
+            self.marked.remove(pos);
+        } else {
+            self.marked.push(name);
+        }
+    }
+
+    /// Marked hosts in mark order, skipping any that were renamed away.
+    fn marked_hosts(&self) -> Vec<&HostEntry> {
+        self.marked
+            .iter()
+            .filter_map(|name| self.hosts.iter().find(|h| h.name == *name))
+            .collect()
+    }
+
+    /// Keeps `selected` inside the host list after it shrinks or reflows.
+    fn clamp_selection(&mut self) {
+        self.selected = self.selected.min(self.hosts.len().saturating_sub(1));
+    }
+
+    fn save(&mut self) {
+        if self.read_only {
+            return;
+        }
+        if self.settings.keyring {
+            self.move_passwords_to_keyring();
+        }
+        let hosts = hosts_for_save(&self.hosts, &self.settings);
+        let from = |source: Option<&Path>| -> Vec<&HostEntry> {
+            hosts.iter().filter(|h| h.source.as_deref() == source).collect()
+        };
+        let tool_comments = !self.companion;
+        let mut written = Vec::new();
+        let mut write = |path: &Path, preamble: &str, hosts: &[&HostEntry]| {
+            if HostEntry::write_ssh_config(path, preamble, hosts, tool_comments)? {
+                written.push(path.display().to_string());
+            }
+            Ok::<(), io::Error>(())
+        };
+        let mut result = write(&ssh_config_path(), &self.preamble, &from(None));
+        for file in &self.included {
+            result = result.and_then(|()| write(&file.path, &file.preamble, &from(Some(&file.path))));
+        }
+        let result = result.and_then(|()| {
+            let contents = serialize_companion(&hosts);
+            if self.companion && fs::read_to_string(companion_path()).ok() != Some(contents.clone()) {
+                fs::write(companion_path(), contents)?;
+                written.push(companion_path().display().to_string());
+            }
+            Ok(())
+        });
+        // Whatever got written is ours, not an outside change.
+        self.disk_mtimes = self.disk_state();
+        self.check_keys();
+        match result {
+            Ok(()) => {
+                self.dirty = false;
+                self.save_error = None;
+                if written.is_empty() {
+                    self.show_flash("✔ Nothing changed; the config already says this.".to_string());
+                } else {
+                    self.show_flash(format!("✔ Saved to {}", written.join(", ")));
+                }
+            }
+            Err(e) => {
+                self.dirty = true;
+                self.flash = None;
+                self.save_error = Some(format!("❌ Failed to save: {}", e));
+                self.set_status(format!("❌ Failed to save config: {}", e));
+            }
+        }
+    }
+
+    /// Stores every plaintext password in the keyring so the next write
+    /// leaves only the marker. A host whose password can't be stored keeps
+    /// it in the config and is retried on the next save. Returns how many
+    /// were moved.
+    fn move_passwords_to_keyring(&mut self) -> usize {
+        let mut moved = 0;
+        let mut errors = Vec::new();
+        for host in self.hosts.iter_mut().filter(|h| !h.password_in_keyring) {
+            let Some(password) = &host.password else {
+                continue;
+            };
+            match keyring_set(&host.name, password) {
+                Ok(()) => {
+                    host.password_in_keyring = true;
+                    moved += 1;
+                }
+                Err(e) => errors.push(format!("{}: {}", host.name, e)),
+            }
+        }
+        if !errors.is_empty() {
+            self.set_status(format!("❌ Couldn't store passwords in the keyring:\n{}", errors.join("\n")));
+        }
+        moved
+    }
+
+    /// Replaces the hosts with `loaded`, layering on the companion file and
+    /// keyring passwords.
+    fn install_config(&mut self, loaded: LoadedConfig) {
+        let LoadedConfig { preamble, mut hosts, included } = loaded;
+        let companion = load_companion(&mut hosts);
+        let keyring_errors = load_keyring_passwords(&mut hosts);
+        self.hosts = hosts;
+        self.preamble = preamble;
+        self.included = included;
+        self.companion = false;
+        match companion {
+            Ok(found) => self.companion = found,
+            // Leave a broken file alone rather than overwrite it on the next save.
+            Err(e) => self.set_status(format!("❌ Ignoring {}: {}", companion_path().display(), e)),
+        }
+        if !keyring_errors.is_empty() {
+            self.set_status(format!("❌ Couldn't read passwords from the keyring:\n{}", keyring_errors.join("\n")));
+        }
+        self.disk_mtimes = self.disk_state();
+        self.check_keys();
+    }
+
+    /// Looks at every host's key files again for `exposed_keys`.
+    fn check_keys(&mut self) {
+        let files: BTreeSet<&String> = self.hosts.iter().flat_map(|h| &h.identity_files).collect();
+        self.exposed_keys = files.into_iter().filter(|f| key_is_exposed(&expand_path(f))).cloned().collect();
+    }
+
+    /// Whether any of `host`'s key files can be read by other users.
+    fn has_exposed_key(&self, host: &HostEntry) -> bool {
+        host.identity_files.iter().any(|f| self.exposed_keys.contains(f))
+    }
+
+    /// The files a save writes.
+    fn watched_files(&self) -> Vec<PathBuf> {
+        let mut files = vec![ssh_config_path()];
+        files.extend(self.included.iter().map(|f| f.path.clone()));
+        if self.companion {
+            files.push(companion_path());
+        }
+        files
+    }
+
+    fn disk_state(&self) -> Vec<Option<SystemTime>> {
+        self.watched_files()
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// Picks up changes another program made to the config: reloads right
+    /// away, or asks first if that would throw away unsaved changes. Each
+    /// outside change is only acted on once, so declining keeps our version
+    /// for the next save to write.
+    fn check_disk(&mut self) {
+        if self.confirm.is_some() {
+            return;
+        }
+        let now = self.disk_state();
+        if now == self.disk_mtimes {
+            return;
+        }
+        self.disk_mtimes = now;
+        if self.has_unsaved_changes() {
+            let prompt = "The config changed on disk. Reload it and discard your changes?".to_string();
+            self.ask(prompt, Action::Reload);
+        } else {
+            self.reload();
+        }
+    }
+
+    /// Reads the config from disk again, keeping the selection on the same
+    /// host by name. `u` takes the reload back.
+    fn reload(&mut self) {
+        let loaded = match load_config_file() {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                self.set_status(format!("❌ Couldn't reload {}: {}", ssh_config_path().display(), e));
+                return;
+            }
+        };
+        let selected = self.hosts.get(self.selected).map(|h| h.name.clone());
+        self.checkpoint();
+        self.install_config(loaded);
+        self.edit_mode = None;
+        self.dirty = false;
+        self.save_error = None;
+        self.selected = selected
+            .and_then(|name| self.hosts.iter().position(|h| h.name == name))
+            .unwrap_or(self.selected);
+        self.clamp_selection();
+        self.snap_selection();
+        self.set_status(format!("↻ Reloaded {}: it changed on disk.", ssh_config_path().display()));
+    }
+
+    fn has_unsaved_changes(&self) -> bool {
+        self.dirty || self.edit_mode.as_ref().is_some_and(EditState::is_dirty)
+    }
+
+    /// The Controls bar title in the list: how many hosts there are and
+    /// how many the filters and search leave, the sort order, and a
+    /// warning while changes are unsaved.
+    fn status_line(&self) -> Line<'static> {
+        let shown = self.filtered_indices().len();
+        let total = self.hosts.len();
+        let mut text = format!("{} host{}", total, if total == 1 { "" } else { "s" });
+        if shown != total {
+            text.push_str(&format!(", {} shown", shown));
+        }
+        text.push_str(&format!(" · sort: {}", self.sort.label()));
+        if self.read_only {
+            text.push_str(" · read-only mode");
+        }
+        let mut spans = vec![Span::raw(text)];
+        if self.has_unsaved_changes() {
+            spans.push(Span::raw(" · "));
+            spans.push(Span::styled("unsaved changes", self.theme.warning));
+        }
+        Line::from(spans)
+    }
+
+    /// A key while the status popup is up: Up/Down and PageUp/PageDown
+    /// scroll a long message (`draw_status` keeps it in range), and
+    /// anything else closes it.
+    fn status_key(&mut self, code: KeyCode) {
+        let Some(scroll) = &mut self.status_scroll else {
+            self.status_message = None;
+            return;
+        };
+        match code {
+            KeyCode::Up => *scroll = scroll.saturating_sub(1),
+            KeyCode::Down => *scroll += 1,
+            KeyCode::PageUp => *scroll = scroll.saturating_sub(STATUS_PAGE),
+            KeyCode::PageDown => *scroll += STATUS_PAGE,
+            KeyCode::Home => *scroll = 0,
+            KeyCode::End => *scroll = usize::MAX,
+            _ => {
+                self.status_message = None;
+                self.status_scroll = None;
+            }
+        }
+    }
+
+    /// Quits straight away, or asks first if that would lose changes.
+    fn request_quit(&mut self) {
+        if self.has_unsaved_changes() {
+            self.ask("Quit without saving your changes?".to_string(), Action::Quit);
+        } else {
+            self.quit = true;
+        }
+    }
+
+    /// Ctrl+C, which raw mode delivers as a key rather than SIGINT. Works
+    /// from anywhere, asking first like q; a second Ctrl+C answers yes.
+    fn interrupt(&mut self) {
+        if self.confirm.as_ref().is_some_and(|c| c.on_yes == Action::Quit) {
+            self.quit = true;
+        } else {
+            self.request_quit();
+        }
+    }
+
+    /// Time until the next flash or status message is due to disappear.
+    fn next_expiry(&self) -> Option<Duration> {
+        let flash = self.flash.as_ref().map(|(_, shown)| FLASH_DURATION.saturating_sub(shown.elapsed()));
+        let status = self
+            .status_message
+            .as_ref()
+            .filter(|_| self.status_scroll.is_none())
+            .map(|(_, shown)| STATUS_DURATION.saturating_sub(shown.elapsed()));
+        flash.into_iter().chain(status).min()
+    }
+
+    /// Drops the flash and status message once they have been up long enough.
+    fn expire_messages(&mut self) {
+        if self.flash.as_ref().is_some_and(|(_, shown)| shown.elapsed() >= FLASH_DURATION) {
+            self.flash = None;
+        }
+        if self.status_scroll.is_none()
+            && self.status_message.as_ref().is_some_and(|(_, shown)| shown.elapsed() >= STATUS_DURATION)
+        {
+            self.status_message = None;
+        }
+    }
+}
+
+/// A file reached through `Include`. Its hosts carry its path as `source`.
+struct IncludedFile {
+    path: PathBuf,
+    preamble: String,
+}
+
+/// The main config and everything it includes.
+#[derive(Default)]
+struct LoadedConfig {
+    preamble: String,
+    hosts: Vec<HostEntry>,
+    included: Vec<IncludedFile>,
+}
+
+/// Loads the config as its verbatim preamble and the host blocks after it,
+/// following `Include` lines into other files.
+fn load_config_file() -> io::Result<LoadedConfig> {
+    let path = ssh_config_path();
+    let contents = fs::read_to_string(&path)?;
+    let (preamble, rest) = HostEntry::split_preamble(&contents);
+    let mut loaded = LoadedConfig {
+        preamble: preamble.to_string(),
+        ..Default::default()
+    };
+    let mut visited = BTreeSet::new();
+    visited.insert(fs::canonicalize(&path).unwrap_or(path));
+    load_hosts(preamble, rest, None, &mut visited, &mut loaded);
+    Ok(loaded)
+}
+
+/// Adds the hosts of one file to `loaded`, reading included files at the
+/// point where their `Include` line appears.
+fn load_hosts(
+    preamble: &str,
+    body: &str,
+    source: Option<&Path>,
+    visited: &mut BTreeSet<PathBuf>,
+    loaded: &mut LoadedConfig,
+) {
+    load_includes(preamble.lines(), visited, loaded);
+    for mut host in HostEntry::parse_ssh_config(body) {
+        host.source = source.map(Path::to_path_buf);
+        let lines = host.raw_lines.clone();
+        loaded.hosts.push(host);
+        load_includes(lines.iter().map(String::as_str), visited, loaded);
+    }
+}
+
+/// Follows any `Include` among `lines`. Files already in `visited` are
+/// skipped, which breaks include cycles; unreadable ones are ignored like
+/// OpenSSH does.
+fn load_includes<'a>(
+    lines: impl Iterator<Item = &'a str>,
+    visited: &mut BTreeSet<PathBuf>,
+    loaded: &mut LoadedConfig,
+) {
+    for line in lines {
+        let (keyword, value) = split_directive(line);
+        if !keyword.eq_ignore_ascii_case("include") {
+            continue;
+        }
+        let (value, _) = split_comment(value);
+        for path in value.split_whitespace().flat_map(expand_include) {
+            if !visited.insert(fs::canonicalize(&path).unwrap_or(path.clone())) {
+                continue;
+            }
+            let Ok(contents) = fs::read_to_string(&path) else {
+                continue;
+            };
+            let (preamble, rest) = HostEntry::split_preamble(&contents);
+            loaded.included.push(IncludedFile {
+                path: path.clone(),
+                preamble: preamble.to_string(),
+            });
+            load_hosts(preamble, rest, Some(&path), visited, loaded);
+        }
+    }
+}
+
+/// Resolves one `Include` argument to the files it names. Relative paths
+/// start from `~/.ssh`, and `*`/`?` match within a path component, skipping
+/// dotfiles unless the pattern itself starts with a dot. Matches are sorted.
+fn expand_include(pattern: &str) -> Vec<PathBuf> {
+    let path = expand_path(pattern);
+    let path = if path.is_absolute() { path } else { ssh_dir().join(path) };
+    let mut matches = vec![PathBuf::new()];
+    for component in path.components() {
+        let part = component.as_os_str().to_string_lossy();
+        if !part.contains(['*', '?']) {
+            for m in &mut matches {
+                m.push(component);
+            }
+            continue;
+        }
+        matches = matches
+            .into_iter()
+            .flat_map(|dir| {
+                let mut found: Vec<PathBuf> = fs::read_dir(&dir)
+                    .into_iter()
+                    .flatten()
+                    .flatten()
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    .filter(|name| (!name.starts_with('.') || part.starts_with('.')) && glob_match(&part, name))
+                    .map(|name| dir.join(name))
+                    .collect();
+                found.sort();
+                found
+            })
+            .collect();
+    }
+    matches.into_iter().filter(|p| p.is_file()).collect()
+}
+
+/// Shell-style wildcard match: `*` is any run of characters, `?` one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// What `merge_imported` did with each imported host.
+#[derive(Debug, Default, PartialEq)]
+struct ImportSummary {
+    /// Names as added, after any renaming.
+    added: Vec<String>,
+    /// `(imported name, name it was added under)`.
+    renamed: Vec<(String, String)>,
+    /// Hosts already present, or patterns that clash with one.
+    skipped: Vec<String>,
+}
+
+impl std::fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Imported {} host(s), skipped {}.", self.added.len(), self.skipped.len())?;
+        for (from, to) in &self.renamed {
+            write!(f, "\n{} was already taken, added as {}", from, to)?;
+        }
+        if !self.skipped.is_empty() {
+            write!(f, "\nAlready there: {}", self.skipped.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Appends `imported` to `hosts`. A host identical to one already there is
+/// skipped; one that only shares its name is added under a `-copy` name.
+/// Patterns are never renamed, since that would change what they match.
+fn merge_imported(hosts: &mut Vec<HostEntry>, imported: Vec<HostEntry>) -> ImportSummary {
+    let mut summary = ImportSummary::default();
+    for mut host in imported {
+        host.source = None;
+        host.password_in_keyring = false;
+        match hosts.iter().find(|h| h.name == host.name && h.match_block == host.match_block) {
+            None => {}
+            Some(existing) if existing.is_pattern() || *existing == HostEntry { source: existing.source.clone(), ..host.clone() } => {
+                summary.skipped.push(host.name);
+                continue;
+            }
+            Some(_) => {
+                let name = unique_copy_name(hosts, &host.name);
+                summary.renamed.push((host.name.clone(), name.clone()));
+                host.name = name;
+            }
+        }
+        summary.added.push(host.name.clone());
+        hosts.push(host);
+    }
+    summary
+}
+
+/// `<name>-copy`, or `<name>-copy-2` and so on if that is taken.
+fn unique_copy_name(hosts: &[HostEntry], name: &str) -> String {
+    let base = format!("{}-copy", name);
+    let taken = |candidate: &str| hosts.iter().any(|h| h.name == candidate);
+    if !taken(&base) {
+        return base;
+    }
+    (2..)
+        .map(|n| format!("{}-{}", base, n))
+        .find(|candidate| !taken(candidate))
+        .unwrap()
+}
+
+/// The user's home directory: `USERPROFILE` on Windows, `HOME` elsewhere.
+fn home_dir() -> PathBuf {
+    let var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    std::env::var_os(var)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+fn ssh_dir() -> PathBuf {
+    home_dir().join(".ssh")
+}
+
+/// The config given with `--config`, if any; set once at startup.
+static CONFIG_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set by `--read-only`; see `read_only`.
+static READ_ONLY: OnceLock<bool> = OnceLock::new();
+
+/// Whether this run must leave the config alone. `write_ssh_config` checks
+/// it too, so nothing gets written even if a mutating path is missed.
+fn read_only() -> bool {
+    READ_ONLY.get().copied().unwrap_or(false)
+}
+
+/// Environment variable naming the config to manage when there's no
+/// `--config`, e.g. one kept under `$XDG_CONFIG_HOME`.
+const CONFIG_ENV: &str = "PUTTY_SSH_CONFIG";
+
+/// The config to use instead of OpenSSH's default: `--config` wins over
+/// `PUTTY_SSH_CONFIG`, and an empty variable counts as unset.
+fn config_override() -> Option<PathBuf> {
+    pick_config_override(CONFIG_OVERRIDE.get().cloned(), std::env::var(CONFIG_ENV).ok())
+}
+
+fn pick_config_override(flag: Option<PathBuf>, env: Option<String>) -> Option<PathBuf> {
+    flag.or_else(|| env.filter(|v| !v.trim().is_empty()).map(|v| expand_path(v.trim())))
+}
+
+fn ssh_config_path() -> PathBuf {
+    config_override().unwrap_or_else(|| ssh_dir().join("config"))
+}
+
+fn settings_path() -> PathBuf {
+    ssh_dir().join("putty_settings")
+}
+
+fn companion_path() -> PathBuf {
+    ssh_dir().join("putty_hosts.yaml")
+}
+
+/// A value in the companion file: the small YAML subset it uses has only
+/// strings and lists of strings.
+#[derive(Debug, Clone, PartialEq)]
+enum YamlValue {
+    Scalar(String),
+    List(Vec<String>),
+}
+
+impl YamlValue {
+    fn into_list(self) -> Vec<String> {
+        match self {
+            YamlValue::Scalar(s) => vec![s],
+            YamlValue::List(items) => items,
+        }
+    }
+
+    fn into_scalar(self) -> String {
+        match self {
+            YamlValue::Scalar(s) => s,
+            YamlValue::List(items) => items.join(", "),
+        }
+    }
+}
+
+/// Merges `~/.ssh/putty_hosts.yaml` onto `hosts`. Returns `Ok(false)` if
+/// there is no companion file, and an error describing the problem if it
+/// cannot be parsed (in which case nothing is merged).
+fn load_companion(hosts: &mut [HostEntry]) -> Result<bool, String> {
+    let contents = match fs::read_to_string(companion_path()) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.to_string()),
+    };
+    merge_companion(hosts, &parse_companion(&contents)?);
+    Ok(true)
+}
+
+fn merge_companion(hosts: &mut [HostEntry], entries: &BTreeMap<String, Vec<(String, YamlValue)>>) {
+    for host in hosts.iter_mut() {
+        let Some(fields) = entries.get(&host.name) else {
+            continue;
+        };
+        for (key, value) in fields.clone() {
+            match key.as_str() {
+                "env" => host.env = Env::parse(&value.into_scalar()),
+                "group" => host.group = Some(value.into_scalar()),
+                "web_ui" => host.web_ui = value.into_list(),
+                "ssh_options" => host.ssh_options = value.into_list(),
+                "notes" => host.meta.notes = Some(value.into_scalar()),
+                "tags" => host.meta.tags = value.into_list(),
+                "color" => host.meta.color = Some(value.into_scalar()),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Parses the companion file: top-level host names, each with indented
+/// `key: value` pairs whose values are scalars, `[a, b]` or `- item` lists.
+fn parse_companion(contents: &str) -> Result<BTreeMap<String, Vec<(String, YamlValue)>>, String> {
+    let mut entries: BTreeMap<String, Vec<(String, YamlValue)>> = BTreeMap::new();
+    let mut host: Option<String> = None;
+    for (n, raw) in contents.lines().enumerate() {
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = |what: &str| format!("line {}: {}", n + 1, what);
+        if !raw.starts_with([' ', '\t']) {
+            let key = line.strip_suffix(':').ok_or_else(|| err("expected `host:`"))?;
+            let name = yaml_unquote(key.trim());
+            entries.entry(name.clone()).or_default();
+            host = Some(name);
+            continue;
+        }
+        let fields = host
+            .as_ref()
+            .and_then(|h| entries.get_mut(h))
+            .ok_or_else(|| err("field outside of a host"))?;
+        if let Some(item) = line.strip_prefix('-') {
+            match fields.last_mut() {
+                Some((_, YamlValue::List(items))) => items.push(yaml_unquote(item.trim())),
+                _ => return Err(err("list item without a list key")),
+            }
+            continue;
+        }
+        let (key, value) = line.split_once(':').ok_or_else(|| err("expected `key: value`"))?;
+        let value = value.trim();
+        let value = if value.is_empty() {
+            YamlValue::List(Vec::new())
+        } else if let Some(inner) = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+            YamlValue::List(
+                inner
+                    .split(',')
+                    .map(|item| yaml_unquote(item.trim()))
+                    .filter(|item| !item.is_empty())
+                    .collect(),
+            )
+        } else {
+            YamlValue::Scalar(yaml_unquote(value))
+        };
+        fields.push((key.trim().to_string(), value));
+    }
+    Ok(entries)
+}
+
+fn serialize_companion(hosts: &[HostEntry]) -> String {
+    let mut out = String::from("# putty host metadata, kept out of the SSH config.\n");
+    for host in hosts {
+        if host.env.is_none()
+            && host.group.is_none()
+            && host.web_ui.is_empty()
+            && host.ssh_options.is_empty()
+            && host.meta.is_empty()
+        {
+            continue;
+        }
+        out.push_str(&format!("{}:\n", yaml_quote(&host.name)));
+        if let Some(env) = host.env {
+            out.push_str(&format!("  env: {}\n", env.as_str()));
+        }
+        if let Some(group) = &host.group {
+            out.push_str(&format!("  group: {}\n", yaml_quote(group)));
+        }
+        if let Some(color) = &host.meta.color {
+            out.push_str(&format!("  color: {}\n", yaml_quote(color)));
+        }
+        if let Some(notes) = &host.meta.notes {
+            out.push_str(&format!("  notes: {}\n", yaml_quote(notes)));
+        }
+        for (key, items) in [("tags", &host.meta.tags), ("web_ui", &host.web_ui), ("ssh_options", &host.ssh_options)] {
+            if !items.is_empty() {
+                out.push_str(&format!("  {}:\n", key));
+                for item in items {
+                    out.push_str(&format!("    - {}\n", yaml_quote(item)));
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Quotes a string only when the plain YAML form would be misread.
+fn yaml_quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value.trim() == value
+        && !value.starts_with(['-', '?', ':', ',', '[', ']', '{', '}', '#', '&', '*', '!', '|', '>', '\'', '"', '%', '@', '`'])
+        && !value.contains(": ")
+        && !value.contains(" #")
+        && !value.ends_with(':')
+        && !value.contains(['\n', '\t']);
+    if plain {
+        return value.to_string();
+    }
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    format!("\"{}\"", escaped)
+}
+
+fn yaml_unquote(value: &str) -> String {
+    if let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        let mut out = String::new();
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            }
+        }
+        out
+    } else if let Some(inner) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        inner.replace("''", "'")
+    } else {
+        value.to_string()
+    }
+}
+
+fn connect_log_path() -> PathBuf {
+    ssh_dir().join("putty_connect.log")
+}
+
+/// The plain-text session log: the same entries as the connect log, one
+/// `describe_log_line` per line, for reading or `tail -f` without a JSON
+/// tool.
+fn session_log_path() -> PathBuf {
+    ssh_dir().join("putty.log")
+}
+
+/// Appends one JSON object per line to the connect log, and the readable
+/// form of it to the session log. This is an audit trail of every
+/// connection attempt, not debug output, so it records the resolved target
+/// and the exact command that was run. Both files are tried even if one
+/// can't be written.
+fn append_connect_log(action: &str, host: &HostEntry, command: &[String], exit_code: Option<i32>) -> io::Result<()> {
+    let secs = unix_now();
+    let hostname = host.primary_hostname();
+    let port = host.port.as_deref().and_then(|p| p.parse::<u16>().ok()).unwrap_or(22);
+    let user = host.user.as_deref().map(json_string).unwrap_or_else(|| "null".to_string());
+    let command = command.iter().map(|arg| json_string(arg)).collect::<Vec<_>>().join(",");
+    let exit_code = exit_code.map(|c| c.to_string()).unwrap_or_else(|| "null".to_string());
+    let line = format!(
+        "{{\"timestamp\":{},\"action\":{},\"host\":{},\"hostname\":{},\"user\":{},\"port\":{},\"command\":[{}],\"exit_code\":{}}}\n",
+        json_string(&format_timestamp(secs)),
+        json_string(action),
+        json_string(&host.name),
+        json_string(hostname),
+        user,
+        port,
+        command,
+        exit_code,
+    );
+    let append = |path: PathBuf, text: &str| {
+        fs::OpenOptions::new().create(true).append(true).open(path)?.write_all(text.as_bytes())
+    };
+    let audit = append(connect_log_path(), &line);
+    let session = append(session_log_path(), &format!("{}\n", describe_log_line(line.trim_end())));
+    audit.and(session)
+}
+
+/// How many connect log entries `L` shows.
+const LOG_VIEW_LINES: usize = 200;
+
+/// One connect log entry as a line for the viewer, e.g.
+/// `2024-05-01 12:00:00 UTC  connect  web (10.0.0.5)  exit 0`. A line that
+/// isn't one of ours is shown as it is.
+fn describe_log_line(line: &str) -> String {
+    // The log is putty's own output, so a field's value is either a JSON
+    // string or runs to the next comma.
+    let field = |key: &str| -> Option<String> {
+        let start = line.find(&format!("\"{}\":", key))? + key.len() + 3;
+        let rest = &line[start..];
+        match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let mut out = String::new();
+                let mut chars = quoted.chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => return Some(out),
+                        '\\' => out.push(chars.next()?),
+                        c => out.push(c),
+                    }
+                }
+                None
+            }
+            None => Some(rest.split([',', '}']).next()?.to_string()),
+        }
+    };
+    let (Some(timestamp), Some(host)) = (field("timestamp"), field("host")) else {
+        return line.to_string();
+    };
+    let action = field("action").unwrap_or_default();
+    let hostname = field("hostname").unwrap_or_default();
+    let outcome = match field("exit_code").as_deref() {
+        Some("0") if action == "probe" => "reachable".to_string(),
+        Some(_) if action == "probe" => "unreachable".to_string(),
+        Some("null") | None => "didn't start".to_string(),
+        Some(code) => format!("exit {}", code),
+    };
+    format!("{}  {:<7}  {} ({})  {}", timestamp.replace('T', " ").replace('Z', " UTC"), action, host, hostname, outcome)
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Where the selection, sort and filters are kept between runs; see
+/// `AppState::state_text`.
+fn state_path() -> PathBuf {
+    ssh_dir().join("putty_state")
+}
+
+fn meta_path() -> PathBuf {
+    ssh_dir().join("putty_meta.json")
+}
+
+/// Reads the last-connected times, keyed by host name. A missing file is
+/// simply empty.
+fn load_last_connected() -> Result<BTreeMap<String, u64>, String> {
+    match fs::read_to_string(meta_path()) {
+        Ok(text) => parse_last_connected(&text),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn save_last_connected(times: &BTreeMap<String, u64>) -> io::Result<()> {
+    let entries: Vec<String> = times
+        .iter()
+        .map(|(name, secs)| format!("  {}: {}", json_string(name), secs))
+        .collect();
+    fs::write(meta_path(), format!("{{\n{}\n}}\n", entries.join(",\n")))
+}
+
+/// Parses the flat `{"host": epoch_seconds, ...}` object written by
+/// `save_last_connected`.
+fn parse_last_connected(text: &str) -> Result<BTreeMap<String, u64>, String> {
+    fn skip_ws(chars: &mut std::iter::Peekable<std::str::Chars>) {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+    let mut chars = text.chars().peekable();
+    let mut times = BTreeMap::new();
+    skip_ws(&mut chars);
+    if chars.next() != Some('{') {
+        return Err("expected an object".to_string());
+    }
+    skip_ws(&mut chars);
+    if chars.next_if_eq(&'}').is_some() {
+        return Ok(times);
+    }
+    loop {
+        skip_ws(&mut chars);
+        if chars.next() != Some('"') {
+            return Err("expected a host name".to_string());
+        }
+        let mut name = String::new();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some('n') => name.push('\n'),
+                    Some('r') => name.push('\r'),
+                    Some('t') => name.push('\t'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                        name.push(c.ok_or("bad \\u escape")?);
+                    }
+                    Some(c) => name.push(c),
+                    None => return Err("unterminated string".to_string()),
+                },
+                Some(c) => name.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+        skip_ws(&mut chars);
+        if chars.next() != Some(':') {
+            return Err(format!("expected ':' after {}", json_string(&name)));
+        }
+        skip_ws(&mut chars);
+        let mut digits = String::new();
+        while let Some(d) = chars.next_if(char::is_ascii_digit) {
+            digits.push(d);
+        }
+        let secs = digits
+            .parse()
+            .map_err(|_| format!("expected a timestamp for {}", json_string(&name)))?;
+        times.insert(name, secs);
+        skip_ws(&mut chars);
+        match chars.next() {
+            Some(',') => continue,
+            Some('}') => break,
+            _ => return Err("expected ',' or '}'".to_string()),
+        }
+    }
+    skip_ws(&mut chars);
+    match chars.next() {
+        None => Ok(times),
+        Some(_) => Err("trailing data after the object".to_string()),
+    }
+}
+
+/// Terminals narrower than this get the host list alone.
+const DETAIL_MIN_WIDTH: u16 = 100;
+
+/// The detail pane's lines for the selected host. The password is only
+/// ever shown as set or not.
+fn host_details(app: &AppState, now: u64) -> Vec<Line<'static>> {
+    let theme = app.theme;
+    let Some(host) = app.selected_host() else {
+        return match app.selected_collapsed_group() {
+            Some(group) => vec![Line::from(format!("Group {} is collapsed; press [Right] to open it.", group))],
+            None => Vec::new(),
+        };
+    };
+    let mut fields: Vec<(String, String)> = Vec::new();
+    let mut add = |label: &str, value: &str| fields.push((label.to_string(), value.to_string()));
+    if host.match_block {
+        add("Match", &host.name);
+        add("State", "kept as written; edit it in the config file");
+    } else {
+        add("Host", &host.name);
+    }
+    if host.disabled {
+        add("State", "disabled (commented out)");
+    }
+    host.hostname.iter().for_each(|v| add("HostName", v));
+    host.user.iter().for_each(|v| add("User", v));
+    host.port.iter().for_each(|v| add("Port", v));
+    for file in &host.identity_files {
+        if app.exposed_keys.contains(file) {
+            add("IdentityFile", &format!("{} ⚠ readable by other users; press [k] to fix", file));
+        } else {
+            add("IdentityFile", file);
+        }
+    }
+    if let Some(jump) = &host.proxy_jump {
+        match jump.split(',').count() {
+            1 => add("ProxyJump", jump),
+            hops => add("ProxyJump", &format!("{} ({} hops)", jump, hops)),
+        }
+    }
+    host.connect_timeout.iter().for_each(|v| add("ConnectTimeout", &format!("{}s", v)));
+    for directive in FLAG_DIRECTIVES {
+        if let Some(Some(flag)) = host.flag(directive) {
+            add(directive, yes_no(flag));
+        }
+    }
+    if host.match_block {
+        for (_, keyword, value) in raw_directives(host) {
+            add(&keyword, &value);
+        }
+    }
+    if host.password_in_keyring {
+        add("Password", "in keyring");
+    } else if host.password.is_some() {
+        add("Password", "set");
+    }
+    host.group.iter().for_each(|v| add("Group", v));
+    host.env.iter().for_each(|e| add("Env", e.as_str()));
+    host.web_ui.iter().for_each(|v| add("WebUI", v));
+    // The catch-all for options putty has no field for: the directives in
+    // the block, which ssh applies itself, then the `-o`s putty adds.
+    if !host.match_block {
+        for (_, keyword, value) in raw_directives(host) {
+            add("SshOption", &format!("{} {} (in config)", keyword, value));
+        }
+    }
+    host.ssh_options.iter().for_each(|v| add("SshOption", &format!("-o {}", v)));
+    if !host.meta.tags.is_empty() {
+        add("Tags", &host.meta.tags.join(", "));
+    }
+    host.meta.notes.iter().for_each(|v| add("Notes", v));
+    match app.last_connected.get(&host.name) {
+        _ if host.match_block => {}
+        Some(&at) => add("Last connected", &format_ago(now.saturating_sub(at))),
+        None => add("Last connected", "never"),
+    }
+    if let Some(source) = &host.source {
+        add("File", &source.display().to_string());
+    }
+    fields
+        .into_iter()
+        .map(|(label, value)| {
+            Line::from(vec![
+                Span::styled(format!("{}: ", label), theme.label),
+                Span::raw(value),
+            ])
+        })
+        .collect()
+}
+
+/// A short age like `5m ago` for the host list.
+fn format_ago(secs: u64) -> String {
+    match secs {
+        0..60 => "just now".to_string(),
+        60..3_600 => format!("{}m ago", secs / 60),
+        3_600..86_400 => format!("{}h ago", secs / 3_600),
+        _ => format!("{}d ago", secs / 86_400),
+    }
+}
+
+/// Formats seconds since the Unix epoch as an RFC 3339 UTC timestamp.
+fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // Civil-from-days conversion, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60
+    )
+}
+
+fn draw_ui(
+    f: &mut ratatui::Frame,
+    app: &mut AppState,
+    config_path_str: &str,
+) {
+    let theme = app.theme;
+    let area = f.area();
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .margin(1)
+        .constraints([
+            Constraint::Min(3),
+            Constraint::Length(3),
+        ])
+        .split(area);
+
+    if let Some(edit) = &app.edit_mode {
+        // Edit mode UI
+        let suggestion = edit_completion(&app.hosts, edit);
+        let items: Vec<ListItem> = edit.rows.iter().enumerate().map(|(i, row)| {
+            let value = &row.value;
+            let shown = if row.is("Password") && !edit.reveal_password {
+                "*".repeat(value.chars().count())
+            } else {
+                value.clone()
+            };
+            let mut spans = vec![Span::raw(format!("{}: {}", row.label(), shown))];
+            if i == edit.field_index {
+                if let Some(full) = &suggestion {
+                    spans.push(Span::styled(
+                        full[value.len()..].to_string(),
+                        theme.label,
+                    ));
+                }
+                spans.push(Span::raw(" <"));
+            }
+            ListItem::new(Line::from(spans))
+        }).collect();
+        let title = match &edit.original.source {
+            Some(path) => format!("Edit Host ({})", path.display()),
+            None => "Edit Host".to_string(),
+        };
+        let list = List::new(items)
+            .block(theme.block(title))
+            .highlight_style(theme.selected)
+            .highlight_symbol("→ ");
+        let mut form_state = ListState::default().with_selected(Some(edit.field_index));
+        f.render_stateful_widget(list, chunks[0], &mut form_state);
+        // Past the border, the "→ " marker and the "Label: " prefix.
+        let prefix = 2 + edit.current_row().label().chars().count() + 2;
+        let row_y = chunks[0].y + 1 + (edit.field_index - form_state.offset()) as u16;
+        if !edit.current_row().is_choice() {
+            f.set_cursor_position((chunks[0].x + 1 + (prefix + edit.cursor()) as u16, row_y));
+        }
+        if let Some((choices, current)) = edit.path_choices() {
+            draw_path_choices(f, choices, current, (chunks[0].x + 1 + prefix as u16, row_y + 1), &theme);
+        }
+        let title = match &edit.warning {
+            Some(warning) => Line::from(Span::styled(format!("⚠ {}", warning), theme.warning)),
+            None if edit.is_dirty() => Line::from(vec![
+                Span::raw("Editing · "),
+                Span::styled("modified", theme.warning),
+            ]),
+            None => Line::from("Editing"),
+        };
+        let edit = Paragraph::new(key_summary(EDIT_KEYS, false))
+            .block(theme.block(title));
+        f.render_widget(edit, chunks[1]);
+    } else {
+        let rows = app.list_rows();
+        let grouped = rows.first().is_some_and(|row| !matches!(row, ListRow::Host(_)));
+        let now = unix_now();
+        let items: Vec<ListItem> = if app.hosts.is_empty() {
+            vec![ListItem::new("No hosts found.")]
+        } else if rows.is_empty() {
+            vec![ListItem::new("No hosts match the filter.")]
+        } else {
+            let quick = app.quick_hosts();
+            rows
+                .iter()
+                .map(|row| {
+                    let i = match *row {
+                        ListRow::Host(i) => i,
+                        ListRow::Header { ref group, count } | ListRow::Collapsed { ref group, count, .. } => {
+                            let arrow = if matches!(row, ListRow::Header { .. }) { "▾" } else { "▸" };
+                            return ListItem::new(format!("{} {} ({})", arrow, group, count))
+                                .style(theme.heading);
+                        }
+                    };
+                    let h = &app.hosts[i];
+                    let found = app
+                        .search
+                        .as_deref()
+                        .filter(|q| !q.is_empty())
+                        .and_then(|q| h.search_match(&q.to_lowercase()));
+                    let matched = |in_hostname: bool| match &found {
+                        Some(m) if m.in_hostname == in_hostname => m.positions.as_slice(),
+                        _ => &[],
+                    };
+                    let mut label = if grouped { "  ".to_string() } else { String::new() };
+                    label.push_str(&match quick.iter().position(|&q| q == i) {
+                        Some(pos) => format!("{} ", pos + 1),
+                        None => "  ".to_string(),
+                    });
+                    label.push_str(&match app.marked.iter().position(|n| *n == h.name) {
+                        Some(pos) => format!("[{}] ", pos + 1),
+                        None if app.marked.is_empty() => String::new(),
+                        None => "    ".to_string(),
+                    });
+                    let mut text = vec![Span::raw(label)];
+                    text.extend(name_spans(h, matched(false)));
+                    if app.has_exposed_key(h) {
+                        text.push(Span::styled(" ⚠", theme.warning));
+                    }
+                    let mut rest = String::new();
+                    for tag in &h.meta.tags {
+                        rest.push_str(&format!(" #{}", tag));
+                    }
+                    if let Some(hostname) = &h.hostname {
+                        let candidates = h.hostname_candidates();
+                        rest.push_str(" (");
+                        text.push(Span::raw(std::mem::take(&mut rest)));
+                        if candidates.len() == 1 && candidates[0] == hostname {
+                            text.extend(highlighted(hostname, matched(true)));
+                        } else {
+                            text.push(Span::raw(candidates[0].to_string()));
+                        }
+                        match candidates.len() {
+                            1 => rest.push(')'),
+                            n => rest.push_str(&format!(" +{})", n - 1)),
+                        }
+                    }
+                    text.push(Span::raw(rest));
+                    let mut style = Style::default();
+                    if h.disabled || h.match_block {
+                        style = theme.muted;
+                    } else if let Some((color, _)) = app.host_color(h) {
+                        style = style.fg(color);
+                    }
+                    let mut spans = Vec::new();
+                    if app.prober.is_some() {
+                        let dot = match app.reachable.get(&h.name) {
+                            Some(true) => theme.ok,
+                            Some(false) => theme.error,
+                            None => theme.muted,
+                        };
+                        spans.push(Span::styled("● ", dot));
+                    }
+                    spans.extend(text);
+                    if let Some(&at) = app.last_connected.get(&h.name) {
+                        spans.push(Span::styled(
+                            format!("  {}", format_ago(now.saturating_sub(at))),
+                            theme.label,
+                        ));
+                    }
+                    ListItem::new(Text::from(Line::from(spans))).style(style)
+                })
+                .collect()
+        };
+
+        let mut list_title = format!("SSH Hosts ({})", config_path_str);
+        if let Some(env) = app.env_filter {
+            list_title.push_str(&format!(" [env: {}]", env.as_str()));
+        }
+        if let Some(group) = &app.group_filter {
+            list_title.push_str(&format!(" [group: {}]", group));
+        }
+        if app.sort != SortOrder::File {
+            list_title.push_str(&format!(" [sort: {}]", app.sort.label()));
+        }
+        let list = List::new(items)
+            .block(theme.block(list_title))
+            .highlight_style(theme.selected)
+            .highlight_symbol("→ ");
+
+        // ratatui moves the offset just enough to keep the selection on
+        // screen, including after the window shrinks.
+        let mut list_state = ListState::default()
+            .with_offset(app.scroll_offset)
+            .with_selected(rows.iter().position(|row| row.host() == Some(app.selected)));
+        let list_area = if chunks[0].width >= DETAIL_MIN_WIDTH {
+            let halves = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Percentage(55), Constraint::Percentage(45)])
+                .split(chunks[0]);
+            let details = Paragraph::new(host_details(app, now))
+                .wrap(Wrap { trim: false })
+                .block(theme.block("Details"));
+            f.render_widget(details, halves[1]);
+            halves[0]
+        } else {
+            chunks[0]
+        };
+        f.render_stateful_widget(list, list_area, &mut list_state);
+        app.scroll_offset = list_state.offset();
+        app.list_area = list_area;
+
+        let title = if let Some(err) = &app.save_error {
+            Line::from(Span::styled(err.clone(), theme.error))
+        } else if let Some((msg, _)) = &app.flash {
+            Line::from(Span::styled(msg.clone(), theme.ok))
+        } else {
+            app.status_line()
+        };
+        if let Some(query) = &app.search {
+            let bar = Paragraph::new(format!("/{}", query)).block(
+                theme.block("Search ([Enter] connect, [Up/Down] move, [Esc] clear)"),
+            );
+            f.render_widget(bar, chunks[1]);
+            f.set_cursor_position((
+                chunks[1].x + 2 + query.chars().count() as u16,
+                chunks[1].y + 1,
+            ));
+        } else if let Some(prompt) = &app.prompt {
+            let prompt_title = match &prompt.error {
+                Some(err) => Line::from(Span::styled(
+                    format!("{} — {}", prompt.kind.label(), err),
+                    theme.error,
+                )),
+                None => Line::from(format!("{} ([Enter] save, [Esc] cancel)", prompt.kind.label())),
+            };
+            let input = Paragraph::new(prompt.input.clone())
+                .block(theme.block(prompt_title));
+            f.render_widget(input, chunks[1]);
+            f.set_cursor_position((
+                chunks[1].x + 1 + prompt.cursor() as u16,
+                chunks[1].y + 1,
+            ));
+        } else {
+            let legend = Line::from(app.color_legend()).right_aligned();
+            let edit = Paragraph::new(key_summary(LIST_KEYS, true))
+                .block(theme.block(title).title(legend));
+            f.render_widget(edit, chunks[1]);
+        }
+
+        if let Some(picker) = &app.web_picker {
+            let items: Vec<ListItem> = picker
+                .urls
+                .iter()
+                .enumerate()
+                .map(|(i, url)| {
+                    let mut item = ListItem::new(url.clone());
+                    if i == picker.selected {
+                        item = item.style(theme.selected);
+                    }
+                    item
+                })
+                .collect();
+            let popup = List::new(items)
+                .block(theme.block("Open Web UI ([Enter] open, [Esc] cancel)"));
+            let area = centered_rect(60, 30, f.area());
+            f.render_widget(Clear, area);
+            f.render_widget(popup, area);
+        }
+
+        if let Some((msg, _)) = &app.status_message {
+            draw_status(f, msg, &mut app.status_scroll, &theme);
+        }
+
+        if app.show_help {
+            draw_help(f, &theme, app.settings.vim_keys);
+        }
+        if let Some(lines) = &app.log_view {
+            draw_log(f, lines, &theme);
+        }
+    }
+
+    if let Some(confirm) = &app.confirm {
+        draw_confirm(f, confirm, &theme);
+    }
+}
+
+/// One row of the `?` help. `brief` rows also appear in the Controls bar.
+#[derive(Clone, Copy)]
+struct KeyHelp {
+    keys: &'static str,
+    action: &'static str,
+    brief: bool,
+}
+
+const fn key(keys: &'static str, action: &'static str, brief: bool) -> KeyHelp {
+    KeyHelp { keys, action, brief }
+}
+
+/// Host list bindings, in the order the help lists them. Keep this in step
+/// with the list-mode `match` in `main`.
+const LIST_KEYS: &[KeyHelp] = &[
+    key("Up/Down", "move the selection", false),
+    key("Home/End", "jump to the first / last host", false),
+    key("Shift+Up/Down", "move the host within the config", false),
+    key("Enter", "connect", true),
+    key("1-9", "select the host with that number", false),
+    key("Left/Right", "collapse/expand the group", false),
+    key("o", "connect with extra ssh arguments", false),
+    key("x", "run a command on the host", false),
+    key("m", "connect with mosh", false),
+    key("t", "open an sftp session", false),
+    key("Space", "mark or unmark the host for J, y, k or d", false),
+    key("J", "connect to the last marked host through the others", false),
+    key("Esc", "clear marks", false),
+    key("/", "search", true),
+    key("e", "edit", true),
+    key("i", "edit the HostName", false),
+    key("n", "new host", true),
+    key("c", "duplicate the host", false),
+    key("d", "delete", true),
+    key("D", "disable/enable the host (comments its block out)", false),
+    key("u", "undo the last change", true),
+    key("Ctrl+R", "redo", false),
+    key("p", "check the host is reachable", false),
+    key("r", "look up the host's HostName in DNS", false),
+    key("k", "fix key file permissions (the marked hosts', if any)", false),
+    key("K", "fix the permissions of every host's key files", false),
+    key("a", "add keys to ssh-agent", false),
+    key("G", "generate a new key for the host", false),
+    key("C", "install the host's public key on the server", false),
+    key("w", "open the web UI", false),
+    key("h", "copy the host's HostName (or alias) to the clipboard", false),
+    key("y", "copy the host (or marked hosts) as ssh_config text", false),
+    key("Y", "copy, including passwords", false),
+    key("I", "import hosts from an ssh_config file", false),
+    key("E", "cycle the environment filter", false),
+    key("g", "cycle the group filter", false),
+    key("s", "cycle the sort order", false),
+    key("T", "switch between the dark and light theme", false),
+    key("L", "show recent connections", false),
+    key("?", "help", true),
+    key("q", "quit", true),
+    key("Ctrl+C", "quit (twice skips the unsaved-changes question)", false),
+];
+
+/// Edit form bindings; see the edit-mode `match` in `main`.
+const EDIT_KEYS: &[KeyHelp] = &[
+    key("Enter", "save", true),
+    key("Esc", "cancel", true),
+    key("Up/Down", "move between fields", true),
+    key("Tab", "complete a key path or accept the suggestion, or next field", true),
+    key("Left/Right/Home/End", "move the cursor", false),
+    key("Space/Left/Right", "cycle Env or a yes/no switch", true),
+    key("Ctrl+N", "add another IdentityFile or SshOption row", true),
+    key("Ctrl+A", "add a directive (type `Keyword value`)", true),
+    key("Ctrl+D", "remove/clear the row", true),
+    key("Ctrl+R", "show/hide the password", true),
+];
+
+/// `[key] action` pairs for a one-line Controls bar.
+fn key_summary(keys: &[KeyHelp], brief_only: bool) -> String {
+    keys.iter()
+        .filter(|k| k.brief || !brief_only)
+        .map(|k| format!("[{}] {}", k.keys, k.action))
+        .collect::<Vec<_>>()
+        .join("  ")
+}
+
+/// The `?` overlay. With `vim_keys` the host list shows the keys
+/// `vim_key` moved, and g/G next to Home/End.
+fn draw_help(f: &mut ratatui::Frame, theme: &Theme, vim_keys: bool) {
+    let section = |title: &str, keys: &[KeyHelp]| {
+        let mut lines = vec![Line::from(Span::styled(title.to_string(), theme.heading))];
+        lines.extend(keys.iter().map(|k| Line::from(format!("  {:<18} {}", k.keys, k.action))));
+        lines
+    };
+    let mut list: Vec<KeyHelp> = LIST_KEYS.to_vec();
+    if vim_keys {
+        for k in &mut list {
+            k.keys = match k.keys {
+                "Up/Down" => "Up/Down, k/j",
+                "Home/End" => "Home/End, g/G",
+                "k" => "Ctrl+K",
+                "g" => "Ctrl+G",
+                "G" => "Ctrl+N",
+                keys => keys,
+            };
+        }
+    }
+    let mut lines = section("Host list", &list);
+    lines.push(Line::from(""));
+    lines.extend(section("Editing a host", EDIT_KEYS));
+    let area = centered_rect(80, 90, f.area());
+    let help = Paragraph::new(Text::from(lines))
+        .block(theme.block("Help ([?] or [Esc] to close)"));
+    f.render_widget(Clear, area);
+    f.render_widget(help, area);
+}
+
+/// The paths Tab is cycling through, in a box just under the field at
+/// `at`, with the one in the field highlighted.
+fn draw_path_choices(f: &mut ratatui::Frame, choices: &[String], current: usize, at: (u16, u16), theme: &Theme) {
+    let full = f.area();
+    let width = (choices.iter().map(|c| c.chars().count()).max().unwrap_or(0) as u16 + 4).min(full.width);
+    let height = (choices.len() as u16 + 2).min(10).min(full.height);
+    let x = at.0.min(full.right().saturating_sub(width));
+    let y = if at.1 + height <= full.bottom() { at.1 } else { full.bottom().saturating_sub(height) };
+    let area = Rect { x, y, width, height };
+    let items: Vec<ListItem> = choices.iter().map(|c| ListItem::new(c.clone())).collect();
+    let list = List::new(items).block(theme.block("Tab")).highlight_style(theme.selected);
+    let mut state = ListState::default().with_selected(Some(current));
+    f.render_widget(Clear, area);
+    f.render_stateful_widget(list, area, &mut state);
+}
+
+/// The status popup, grown to fit the message up to most of the screen.
+/// Long lines (a full icacls command, say) wrap. Beyond that it scrolls:
+/// `scroll` becomes the offset, kept in range here since only the drawing
+/// knows how many lines fit.
+fn draw_status(f: &mut ratatui::Frame, msg: &str, scroll: &mut Option<usize>, theme: &Theme) {
+    let full = f.area();
+    let base = centered_rect(60, 20, full);
+    let text = Paragraph::new(msg.to_string()).wrap(Wrap { trim: false });
+    let lines = text.line_count(base.width.saturating_sub(2));
+    let height = (lines as u16).saturating_add(2).min(full.height * 4 / 5).max(base.height);
+    let area = Rect { y: full.y + (full.height - height) / 2, height, ..base };
+    let fits = height.saturating_sub(2) as usize;
+    let mut title = "Status".to_string();
+    let offset = if lines > fits {
+        let offset = scroll.get_or_insert(0);
+        *offset = (*offset).min(lines - fits);
+        title = format!(
+            "Status (lines {}-{} of {}; [Up/Down/PgUp/PgDn] scroll, [Esc] close)",
+            *offset + 1,
+            *offset + fits,
+            lines
+        );
+        *offset
+    } else {
+        *scroll = None;
+        0
+    };
+    let popup = text.scroll((offset as u16, 0)).block(theme.block(title));
+    f.render_widget(Clear, area);
+    f.render_widget(popup, area);
+}
+
+/// The connect log viewer: as many of the newest entries as fit.
+fn draw_log(f: &mut ratatui::Frame, lines: &[String], theme: &Theme) {
+    let area = centered_rect(90, 80, f.area());
+    let fit = area.height.saturating_sub(2) as usize;
+    let shown: Vec<Line> = lines[lines.len().saturating_sub(fit)..].iter().map(|l| Line::from(l.clone())).collect();
+    let title = format!("Recent connections, newest last ({}) ([L] or [Esc] to close)", connect_log_path().display());
+    let log = Paragraph::new(Text::from(shown)).block(theme.block(title));
+    f.render_widget(Clear, area);
+    f.render_widget(log, area);
+}
+
+fn draw_confirm(f: &mut ratatui::Frame, confirm: &Confirm, theme: &Theme) {
+    let mut lines: Vec<Line> = confirm.prompt.lines().map(|l| Line::from(l.to_string())).collect();
+    lines.push(Line::from(""));
+    lines.push(Line::from("[y] Yes  [n/Esc] No"));
+    // A list of commands needs more room than a one-line question.
+    let area = if lines.len() > 3 {
+        let full = f.area();
+        let height = (lines.len() as u16 + 2).min(full.height);
+        let wide = centered_rect(90, 100, full);
+        Rect { y: full.y + (full.height - height) / 2, height, ..wide }
+    } else {
+        centered_rect(50, 20, f.area())
+    };
+    let popup = Paragraph::new(Text::from(lines))
+        .wrap(Wrap { trim: false })
+        .block(theme.block("Confirm"));
+    f.render_widget(Clear, area);
+    f.render_widget(popup, area);
+}
+
+fn centered_rect(percent_x: u16, percent_y: u16, area: ratatui::layout::Rect) -> ratatui::layout::Rect {
+    let popup_layout = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage((100 - percent_y) / 2),
+            Constraint::Percentage(percent_y),
+            Constraint::Percentage((100 - percent_y) / 2),
+        ])
+        .split(area);
+
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage((100 - percent_x) / 2),
+            Constraint::Percentage(percent_x),
+            Constraint::Percentage((100 - percent_x) / 2),
+        ])
+        .split(popup_layout[1])[1]
+}
+
+
+const USAGE: &str = "Usage: putty [--config|-F <file>] [--read-only] [--connect <host>] [--import <file>|-]";
+
+/// What was asked for on the command line.
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    /// Manage this file instead of ~/.ssh/config.
+    config: Option<PathBuf>,
+    /// Connect to this host straight away, without the TUI.
+    connect: Option<String>,
+    /// Merge the hosts from this file (`-` for stdin) and exit.
+    import: Option<String>,
+    /// Browse and connect, but never change the config.
+    read_only: bool,
+    help: bool,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+            _ => (arg, None),
+        };
+        let mut value = || inline.clone().or_else(|| args.next()).ok_or(format!("{} needs a value", flag));
+        match flag.as_str() {
+            "--config" | "-F" => parsed.config = Some(PathBuf::from(value()?)),
+            "--connect" => parsed.connect = Some(value()?),
+            "--import" => parsed.import = Some(value()?),
+            "--read-only" => parsed.read_only = true,
+            "--help" | "-h" => parsed.help = true,
+            _ => return Err(format!("unknown argument {}", flag)),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Exit status of `--connect` when the host isn't in the config.
+const EXIT_NO_SUCH_HOST: i32 = 3;
+
+/// `--connect`: runs ssh to `name` in the current terminal and returns the
+/// status to exit with, ssh's own unless something went wrong first.
+fn connect_headless(name: &str) -> i32 {
+    let path = ssh_config_path();
+    let loaded = match load_config_file() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("putty: can't read {}: {}", path.display(), e);
+            return 1;
+        }
+    };
+    let Some(host) = loaded.hosts.iter().find(|h| !h.disabled && h.concrete_patterns().contains(&name)) else {
+        eprintln!("putty: no host named {} in {}", name, path.display());
+        return EXIT_NO_SUCH_HOST;
+    };
+    let settings = Settings::load();
+    let mut preambles = vec![loaded.preamble.as_str()];
+    preambles.extend(loaded.included.iter().map(|f| f.preamble.as_str()));
+    let command = ssh_command(host, &settings, &inherited_directives(&preambles, &loaded.hosts, host));
+    let status = std::process::Command::new(&command[0]).args(&command[1..]).status();
+    let exit_code = status.as_ref().ok().and_then(|s| s.code());
+    if !settings.no_connect_log {
+        let _ = append_connect_log("connect", host, &command, exit_code);
+    }
+    match status {
+        Ok(_) => exit_code.unwrap_or(1),
+        Err(e) => {
+            eprintln!("putty: {}", launch_error(&command[0], &e).trim_start_matches("❌ "));
+            1
+        }
+    }
+}
+
+/// `--import`: merges hosts from `source` (a path, or `-` for stdin) into
+/// the config and returns the status to exit with.
+fn import_headless(source: &str) -> i32 {
+    let text = if source == "-" {
+        io::read_to_string(io::stdin())
+    } else {
+        fs::read_to_string(expand_path(source))
+    };
+    let text = match text {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("putty: can't read {}: {}", source, e);
+            return 1;
+        }
+    };
+    let mut app = AppState::new(Vec::new(), Settings::load());
+    match load_config_file() {
+        Ok(loaded) => app.install_config(loaded),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            eprintln!("putty: can't read {}: {}", ssh_config_path().display(), e);
+            return 1;
+        }
+    }
+    match app.import(&text) {
+        Ok(summary) if app.save_error.is_none() => {
+            println!("{}", summary);
+            0
+        }
+        Ok(_) => {
+            eprintln!("putty: {}", app.save_error.unwrap().trim_start_matches("❌ "));
+            1
+        }
+        Err(e) => {
+            eprintln!("putty: {}", e);
+            1
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("putty: {}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    if let Some(path) = args.config {
+        let _ = CONFIG_OVERRIDE.set(path);
+    }
+    let _ = READ_ONLY.set(args.read_only);
+    if let Err(e) = Settings::load().connect_template() {
+        eprintln!("putty: bad connect command template: {}", e);
+        std::process::exit(2);
+    }
+    if let Some(name) = args.connect {
+        std::process::exit(connect_headless(&name));
+    }
+    if let Some(source) = args.import {
+        if args.read_only {
+            eprintln!("putty: --import changes the config, so it can't be used with --read-only");
+            std::process::exit(2);
+        }
+        std::process::exit(import_headless(&source));
+    }
+
+    install_panic_hook();
+    enable_raw_mode()?;
+    let mut stdout = stdout();
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture, EnableBracketedPaste)?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    let config_path = ssh_config_path();
+    let config_path_str = config_path.display().to_string();
+    let mut app = AppState::new(Vec::new(), Settings::load());
+    app.install_config(load_config_file().unwrap_or_default());
+    if let Some(interval) = app.settings.probe_interval {
+        app.prober = Some(Prober::start(interval, &app.hosts));
+    }
+    // Migrate any `# Password` comments left from before the keyring was on.
+    if app.settings.keyring && !app.read_only {
+        let moved = app.move_passwords_to_keyring();
+        if moved > 0 {
+            app.save();
+            if !app.dirty {
+                app.show_flash(format!("Moved {} password(s) into the keyring.", moved));
+            }
+        }
+    }
+    match load_last_connected() {
+        Ok(times) => app.last_connected = times,
+        Err(e) => app.set_status(format!("❌ Ignoring {}: {}", meta_path().display(), e)),
+    }
+    if let Ok(text) = fs::read_to_string(state_path()) {
+        app.restore_state(&text);
+    }
+
+    // Initial draw before flushing events
+    terminal.draw(|f| draw_ui(f, &mut app, &config_path_str))?;
+
+    // Give terminal time to settle
+    sleep(Duration::from_millis(100));
+
+    // Flush any leftover events (e.g. Enter from cargo run)
+    while event::poll(Duration::from_millis(0))? {
+        let _ = event::read();
+    }
+
+    loop {
+        if let Some(Launch { index, action, command }) = app.pending_launch.take() {
+            if app.hosts[index].disabled {
+                app.set_status(format!("{} is disabled; press D to enable it first.", app.hosts[index].name));
+                continue;
+            }
+            let log = !app.settings.no_connect_log;
+            let status = run_session(&mut terminal, action, &app.hosts[index], &command, log);
+            if action == "command" {
+                // Leave the output on screen until it has been read.
+                println!("\nPress Enter to return to putty.");
+                let _ = io::stdin().read_line(&mut String::new());
+            }
+            resume_tui(&mut terminal)?;
+            // Drop anything left over from the session, e.g. the release of
+            // the key that closed it.
+            while event::poll(Duration::from_millis(0))? {
+                let _ = event::read();
+            }
+            let name = app.hosts[index].name.clone();
+            let outcome = match status {
+                Ok(status) => {
+                    if status.code() != Some(255) {
+                        app.last_connected.insert(name.clone(), unix_now());
+                        let _ = save_last_connected(&app.last_connected);
+                    }
+                    session_outcome(&command[0], &name, status)
+                }
+                Err(e) => launch_error(&command[0], &e),
+            };
+            // A note queued with the launch (e.g. a template warning) was
+            // never on screen; show it with the outcome.
+            let outcome = match app.status_message.take() {
+                Some((note, _)) => format!("{}\n\n{}", note, outcome),
+                None => outcome,
+            };
+            app.set_status(outcome);
+            continue;
+        }
+        if let Some(keygen) = app.pending_keygen.take() {
+            // ssh-keygen asks for the passphrase on the terminal.
+            suspend_tui(&mut terminal);
+            println!("Generating {}...", keygen.path);
+            let result = run_keygen("ssh-keygen", &expand_path(&keygen.path), &app.hosts[keygen.index].name);
+            resume_tui(&mut terminal)?;
+            while event::poll(Duration::from_millis(0))? {
+                let _ = event::read();
+            }
+            app.key_generated(keygen, result);
+            continue;
+        }
+        app.check_disk();
+        if let Some(prober) = &app.prober {
+            prober.set_targets(&app.hosts);
+            app.reachable.extend(prober.results.try_iter());
+        }
+        terminal.draw(|f| draw_ui(f, &mut app, &config_path_str))?;
+
+        // Redraw at least every TICK so timers, reachability results and
+        // "ago" times move without a keypress, and sooner if a flash or
+        // status message is due to come down.
+        let wait = app.next_expiry().map_or(TICK, |remaining| remaining.min(TICK));
+        if !event::poll(wait)? {
+            app.expire_messages();
+            continue;
+        }
+        let event = event::read()?;
+        if let Event::Resize(_, _) = event {
+            // Reflow straight away rather than on the next keypress.
+            terminal.autoresize()?;
+            app.clamp_selection();
+            continue;
+        }
+        if let Event::Mouse(mouse) = event {
+            app.handle_mouse(mouse);
+            continue;
+        }
+        if let Event::Paste(text) = event {
+            if let Some(edit) = app.edit_mode.as_mut() {
+                edit.paste(&text);
+            } else if let Some(prompt) = app.prompt.as_mut() {
+                prompt.paste(&text);
+            }
+            continue;
+        }
+        if let Event::Key(key) = event {
+            if !key_wanted(key) {
+                continue;
+            }
+            // The host list reads vim's keys as the ones they stand in for.
+            let list_key = if app.settings.vim_keys { vim_key(key) } else { key };
+            let now = std::time::Instant::now();
+            let allow = !matches!(
+                (app.last_key, app.last_key_time),
+                (Some(prev), Some(t)) if prev == key.code && now.duration_since(t) < Duration::from_millis(50)
+            );
+            if allow {
+                if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+                    app.interrupt();
+                } else if app.confirm.is_some() {
+                    match key.code {
+                        KeyCode::Char('y') | KeyCode::Char('Y') => app.answer_confirm(true),
+                        KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc => app.answer_confirm(false),
+                        _ => {}
+                    }
+                } else if app.show_help {
+                    if matches!(key.code, KeyCode::Esc | KeyCode::Char('?')) {
+                        app.show_help = false;
+                    }
+                } else if app.log_view.is_some() {
+                    if matches!(key.code, KeyCode::Esc | KeyCode::Char('L') | KeyCode::Char('q')) {
+                        app.log_view = None;
+                    }
+                } else if let Some(edit) = &mut app.edit_mode {
+                    // Edit mode key handling
+                    match key.code {
+                        KeyCode::Esc => {
+                            if edit.is_dirty() {
+                                let prompt = format!("Discard changes to {}?", edit.original.name);
+                                app.ask(prompt, Action::DiscardEdit);
+                            } else {
+                                app.edit_mode = None;
+                            }
+                        }
+                        KeyCode::Enter => app.finish_edit(),
+                        KeyCode::Tab => app.edit_tab(),
+                        KeyCode::Down => edit.move_field(1),
+                        KeyCode::Up => edit.move_field(-1),
+                        KeyCode::Char(' ') | KeyCode::Left | KeyCode::Right if edit.current_row().is_choice() => {
+                            edit.cycle_choice();
+                        }
+                        KeyCode::Backspace if edit.current_row().is_choice() => {
+                            edit.rows[edit.field_index].value.clear();
+                        }
+                        KeyCode::Char('n') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            edit.add_identity_file();
+                        }
+                        KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            edit.reveal_password = !edit.reveal_password;
+                        }
+                        KeyCode::Char('a') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            edit.add_directive();
+                        }
+                        KeyCode::Char('d') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            edit.remove_row();
+                        }
+                        KeyCode::Backspace => edit.delete_char(true),
+                        KeyCode::Delete => edit.delete_char(false),
+                        KeyCode::Left => edit.move_cursor(-1),
+                        KeyCode::Right => edit.move_cursor(1),
+                        KeyCode::Home => edit.cursor = 0,
+                        KeyCode::End => edit.cursor = usize::MAX,
+                        KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => edit.insert_char(c),
+                        _ => {}
+                    }
+                } else if app.status_message.is_some() {
+                    app.status_key(key.code);
+                } else if let Some(prompt) = &mut app.prompt {
+                    match key.code {
+                        KeyCode::Esc => app.prompt = None,
+                        KeyCode::Backspace => prompt.delete_char(true),
+                        KeyCode::Delete => prompt.delete_char(false),
+                        KeyCode::Left => prompt.move_cursor(-1),
+                        KeyCode::Right => prompt.move_cursor(1),
+                        KeyCode::Home => prompt.cursor = 0,
+                        KeyCode::End => prompt.cursor = usize::MAX,
+                        KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => prompt.insert_char(c),
+                        KeyCode::Enter => {
+                            let kind = prompt.kind;
+                            let input = prompt.input.clone();
+                            match app.submit_prompt(kind, input) {
+                                Ok(()) => app.prompt = None,
+                                Err(err) => {
+                                    if let Some(prompt) = &mut app.prompt {
+                                        prompt.error = Some(err);
+                                    }
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                } else if let Some(picker) = &mut app.web_picker {
+                    match key.code {
+                        KeyCode::Esc => app.web_picker = None,
+                        KeyCode::Up => {
+                            picker.selected = (picker.selected + picker.urls.len() - 1) % picker.urls.len();
+                        }
+                        KeyCode::Down => {
+                            picker.selected = (picker.selected + 1) % picker.urls.len();
+                        }
+                        KeyCode::Enter => {
+                            let url = picker.urls[picker.selected].clone();
+                            app.web_picker = None;
+                            app.open_url(&url);
+                        }
+                        _ => {}
+                    }
+                } else if app.handle_search_key(key) {
+                    // Consumed by the search bar.
+                } else if app.intercept_list_key(key, list_key) {
+                    // A vim jump, or refused in read-only mode or on a Match block.
+                } else {
+                    let key = list_key;
+                    match key.code {
+                        KeyCode::Enter if app.selected_host().is_some() => app.connect_selected(),
+                        KeyCode::Enter | KeyCode::Right => app.expand_selected_group(),
+                        KeyCode::Left => app.collapse_selected_group(),
+                        KeyCode::Char(' ') if app.selected_host().is_some() => app.toggle_mark(),
+                        KeyCode::Char('/') => {
+                            app.search = Some(String::new());
+                        }
+                        KeyCode::Char('d') if app.selected_host().is_some() => app.ask_delete(),
+                        KeyCode::Char('c') if app.selected_host().is_some() => app.duplicate_selected(),
+                        KeyCode::Char('D') if app.selected_host().is_some() => app.toggle_disabled(),
+                        KeyCode::Char('T') => app.toggle_theme(),
+                        KeyCode::Char(c @ '1'..='9') => app.quick_select(c as usize - '0' as usize),
+                        KeyCode::Char('p') if app.selected_host().is_some() => {
+                            let report = probe_report(&app.hosts[app.selected], !app.settings.no_connect_log);
+                            app.set_status(report);
+                        }
+                        KeyCode::Char('u') => app.undo(),
+                        KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => app.redo(),
+                        KeyCode::Char('r') if app.selected_host().is_some() => {
+                            let report = resolve_report(&app.hosts[app.selected]);
+                            app.set_status(report);
+                        }
+                        KeyCode::Char('t') if app.selected_host().is_some() => {
+                            // The alias lets OpenSSH pick up User, Port and keys from the config.
+                            let mut command = with_options("sftp", &app.launch_options(app.selected));
+                            command.push(app.hosts[app.selected].alias().to_string());
+                            app.pending_launch = Some(Launch { index: app.selected, action: "sftp", command });
+                        }
+                        KeyCode::Char('m') if app.selected_host().is_some() => {
+                            let command = mosh_command(&app.hosts[app.selected], &app.launch_options(app.selected));
+                            app.pending_launch = Some(Launch { index: app.selected, action: "mosh", command });
+                        }
+                        KeyCode::Char('o') if app.selected_host().is_some() => {
+                            app.open_prompt(PromptKind::SshArgs, String::new());
+                        }
+                        KeyCode::Char('x') if app.selected_host().is_some() => {
+                            app.open_prompt(PromptKind::RemoteCommand, String::new());
+                        }
+                        KeyCode::Char('i') if app.selected_host().is_some() => {
+                            let current = app.hosts[app.selected].hostname.clone().unwrap_or_default();
+                            app.open_prompt(PromptKind::Hostname, current);
+                        }
+                        KeyCode::Char('w') if app.selected_host().is_some() => {
+                            let host = &app.hosts[app.selected];
+                            let urls: Vec<String> = host.web_ui.iter().map(|t| host.web_ui_url(t)).collect();
+                            match urls.len() {
+                                0 => app.set_status(format!("No # WebUI configured for {}.", host.name)),
+                                1 => app.open_url(&urls[0]),
+                                _ => app.web_picker = Some(WebPicker { urls, selected: 0 }),
+                            }
+                        }
+                        KeyCode::Char('E') => {
+                            app.env_filter = Env::cycle(app.env_filter);
+                            app.snap_selection();
+                        }
+                        KeyCode::Char('g') if !key.modifiers.contains(KeyModifiers::CONTROL) => app.cycle_group_filter(),
+                        KeyCode::Char('?') => app.show_help = true,
+                        KeyCode::Char('L') => app.open_log(),
+                        KeyCode::Char('s') => {
+                            app.sort = app.sort.next();
+                            app.show_flash(format!("Sorted by {}", app.sort.label()));
+                        }
+                        KeyCode::Esc => app.marked.clear(),
+                        KeyCode::Char('J') => {
+                            let chain = app.marked_hosts();
+                            let index = chain.last().map(|target| app.hosts.iter().position(|h| h.name == target.name).unwrap());
+                            let options = index.map(|i| app.launch_options(i)).unwrap_or_default();
+                            match chain_command(&chain, &options) {
+                                Ok(command) => {
+                                    let index = index.unwrap();
+                                    app.pending_launch = Some(Launch { index, action: "connect", command });
+                                }
+                                Err(msg) => app.set_status(format!("❌ {}", msg)),
+                            }
+                        }
+                        KeyCode::Char('q') => app.request_quit(),
+                        KeyCode::Char('I') => app.open_prompt(PromptKind::Import, String::new()),
+                        KeyCode::Char('C') if app.selected_host().is_some() => {
+                            let host = &app.hosts[app.selected];
+                            if host.disabled {
+                                app.set_status(format!("{} is disabled; press D to enable it first.", host.name));
+                            } else {
+                                match copy_id_command(host, on_path("ssh-copy-id"), &app.launch_options(app.selected)) {
+                                    Ok(copy) => {
+                                        let name = host.name.clone();
+                                        suspend_tui(&mut terminal);
+                                        println!("Installing {} on {}...", copy.public_key, name);
+                                        let report = run_copy_id(&copy, &name);
+                                        resume_tui(&mut terminal)?;
+                                        app.set_status(report);
+                                    }
+                                    Err(msg) => app.set_status(msg),
+                                }
+                            }
+                        }
+                        KeyCode::Char('G') if app.selected_host().is_some() => {
+                            let path = format!("~/.ssh/id_ed25519_{}", app.hosts[app.selected].alias());
+                            app.open_prompt(PromptKind::KeyPath, path);
+                        }
+                        KeyCode::Char('h') => app.copy_hostname(),
+                        KeyCode::Char('y') => app.export(false),
+                        KeyCode::Char('Y') => app.export(true),
+                        KeyCode::Char('a') if app.selected_host().is_some() => {
+                            let identity_files = app.hosts[app.selected].identity_files.clone();
+                            if !identity_files.is_empty() {
+                                let missing: Vec<&String> = identity_files
+                                    .iter()
+                                    .filter(|f| !key_loaded_in_agent(&expand_path(f)))
+                                    .collect();
+                                if missing.is_empty() {
+                                    app.set_status(format!("✔ {} already loaded in ssh-agent", identity_files.join(", ")));
+                                } else {
+                                    let names = missing.iter().map(|f| f.as_str()).collect::<Vec<_>>().join(", ");
+                                    // ssh-add prompts for the passphrase itself, so hand it the real terminal.
+                                    suspend_tui(&mut terminal);
+                                    println!("Adding {} to ssh-agent...", names);
+                                    let status = std::process::Command::new("ssh-add")
+                                        .args(missing.iter().map(|f| expand_path(f)))
+                                        .status();
+                                    resume_tui(&mut terminal)?;
+                                    app.set_status(match status {
+                                        Ok(s) if s.success() => format!("✔ Added {} to ssh-agent", names),
+                                        Ok(s) => format!(
+                                            "❌ ssh-add failed with code {}{}",
+                                            s.code().unwrap_or(-1),
+                                            agent_hint()
+                                        ),
+                                        Err(e) => format!("❌ Failed to run ssh-add: {}", e),
+                                    });
+                                }
+                            } else {
+                                app.set_status(no_identity_file(&app.hosts[app.selected]));
+                            }
+                        }
+                        KeyCode::Char('e') if app.selected_host().is_some() => {
+                            let host = app.hosts[app.selected].clone();
+                            app.edit_mode = Some(EditState::new(host, Some(app.selected)));
+                        }
+                        KeyCode::Char('n') => {
+                            // Add new host
+                            let new_host = HostEntry {
+                                name: String::from("new-host"),
+                                ..Default::default()
+                            };
+                            app.edit_mode = Some(EditState::new(new_host, None));
+                        }
+                        KeyCode::Char('k') if app.selected_host().is_some() && !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            app.ask_secure_keys();
+                        }
+                        KeyCode::Char('K') => app.ask_secure_key_files(false),
+                        KeyCode::Home => app.select_end(false),
+                        KeyCode::End => app.select_end(true),
+                        KeyCode::Up | KeyCode::Down => {
+                            let delta = if key.code == KeyCode::Up { -1 } else { 1 };
+                            if key.modifiers.contains(KeyModifiers::SHIFT) {
+                                if app.sort != SortOrder::File {
+                                    app.set_status("Press [s] to return to file order before moving hosts.".to_string());
+                                } else if app.move_host(delta) {
+                                    app.save();
+                                }
+                            } else {
+                                app.move_selection(delta);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                app.last_key = Some(key.code);
+                app.last_key_time = Some(now);
+            }
+            if app.quit {
+                break;
+            }
+        }
+    }
+    // Only a convenience for next time; not worth holding up the exit for.
+    let _ = fs::write(state_path(), app.state_text());
+
+    disable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+        DisableBracketedPaste
+    )?;
+    terminal.show_cursor()?;
+    for value in &app.print_on_exit {
+        println!("{}", value);
+    }
+    Ok(())
+}
+
+/// With `vim_keys`, the host list key that `key` stands for: j and k are
+/// Down and Up, and the actions they and g/G push aside move to Ctrl+K
+/// (fix key files), Ctrl+G (group filter) and Ctrl+N (generate a key).
+/// g and G themselves jump to the first and last host; see `select_end`.
+fn vim_key(key: KeyEvent) -> KeyEvent {
+    let control = key.modifiers.contains(KeyModifiers::CONTROL);
+    let code = match (key.code, control) {
+        (KeyCode::Char('j'), false) => KeyCode::Down,
+        (KeyCode::Char('k'), false) => KeyCode::Up,
+        (KeyCode::Char('k'), true) => KeyCode::Char('k'),
+        (KeyCode::Char('g'), true) => KeyCode::Char('g'),
+        (KeyCode::Char('n'), true) => KeyCode::Char('G'),
+        _ => return key,
+    };
+    KeyEvent::new(code, KeyModifiers::NONE)
+}
+
+/// Whether a key in the host list would change the config (or, like k and
+/// G, the key files it points at), and so is refused in read-only mode.
+fn changes_config(key: KeyEvent) -> bool {
+    let control = key.modifiers.contains(KeyModifiers::CONTROL);
+    match key.code {
+        KeyCode::Char('r') => control,
+        KeyCode::Char('d' | 'c' | 'D' | 'u' | 'e' | 'n' | 'i' | 'I' | 'k' | 'K' | 'G') => !control,
+        KeyCode::Up | KeyCode::Down => key.modifiers.contains(KeyModifiers::SHIFT),
+        _ => false,
+    }
+}
+
+/// Shown when a key that needs a real host is used on a `Match` block.
+const MATCH_READ_ONLY: &str = "Match blocks are kept as written; edit them in the config file.";
+
+/// Whether a key in the host list connects to, edits or marks the
+/// selected host, none of which make sense for a `Match` block. d still
+/// deletes one, and moving it works as for any other entry.
+fn uses_host(key: KeyEvent) -> bool {
+    !key.modifiers.contains(KeyModifiers::CONTROL)
+        && matches!(
+            key.code,
+            KeyCode::Char(' ' | 'a' | 'c' | 'C' | 'D' | 'e' | 'G' | 'h' | 'i' | 'k' | 'm' | 'o' | 'p' | 'r' | 't' | 'w' | 'x')
+        )
+}
+
+/// Values already used for the field being edited, gathered across all
+/// hosts. Only HostName and User offer completion.
+fn completion_candidates(hosts: &[HostEntry], directive: &str) -> BTreeSet<String> {
+    hosts
+        .iter()
+        .filter_map(|h| match directive {
+            "HostName" => h.hostname.clone(),
+            "User" => h.user.clone(),
+            _ => None,
+        })
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Files and directories that complete `typed`, a path as written in the
+/// config: `~/.ssh/id_` offers `~/.ssh/id_ed25519`, `~/.ssh/id_rsa` and so
+/// on, keeping the `~`. Directories end in a separator so Tab can carry on
+/// into them. Dotfiles only show up once a `.` is typed.
+fn path_candidates(typed: &str) -> Vec<String> {
+    let split = typed.rfind(['/', '\\']).map_or(0, |i| i + 1);
+    let (dir, prefix) = typed.split_at(split);
+    let separator = dir.chars().last().unwrap_or('/');
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { PathBuf::from(".") } else { expand_path(dir) }) else {
+        return Vec::new();
+    };
+    let mut found: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let is_dir = entry.path().is_dir();
+            Some(format!("{}{}{}", dir, name, if is_dir { separator.to_string() } else { String::new() }))
+        })
+        .collect();
+    found.sort();
+    found
+}
+
+/// Suggests a full value for the active field from what is typed so far.
+/// HostName also completes just the domain part, so `web3.ex` can become
+/// `web3.example.com` when `example.com` is used by another host.
+fn edit_completion(hosts: &[HostEntry], edit: &EditState) -> Option<String> {
+    let row = edit.current_row();
+    let typed = Some(row.value.as_str()).filter(|v| !v.is_empty())?;
+    let candidates = completion_candidates(hosts, &row.directive);
+    if let Some(full) = candidates.iter().find(|c| c.len() > typed.len() && c.starts_with(typed)) {
+        return Some(full.clone());
+    }
+    if row.directive == "HostName" {
+        let (label, partial) = typed.split_once('.')?;
+        let domains: BTreeSet<&str> = candidates
+            .iter()
+            .filter_map(|c| c.split_once('.').map(|(_, domain)| domain))
+            .collect();
+        return domains
+            .into_iter()
+            .find(|d| d.len() > partial.len() && d.starts_with(partial))
+            .map(|d| format!("{}.{}", label, d));
+    }
+    None
+}
+
+/// Tears down the TUI, runs `command` in the foreground and, if `log`,
+/// records the attempt in the connect log.
+fn run_session(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    action: &str,
+    host: &HostEntry,
+    command: &[String],
+    log: bool,
+) -> io::Result<std::process::ExitStatus> {
+    suspend_tui(terminal);
+    println!("Connecting to {}...", host.name);
+    let mut process = std::process::Command::new(&command[0]);
+    process.args(&command[1..]);
+    if command[0] == "sshpass"
+        && let Some(password) = &host.password
+    {
+        process.env("SSHPASS", password);
+    }
+    let status = process.status();
+    let exit_code = status.as_ref().ok().and_then(|s| s.code());
+    if log {
+        // A log that can't be written never gets in the way of the session.
+        let _ = append_connect_log(action, host, command, exit_code);
+    }
+    status
+}
+
+/// What to tell the user once a session's program has exited. ssh (and
+/// sftp and mosh's bootstrap) exits with 255 for its own failures, such as
+/// an unreachable host or a rejected login; anything else is the remote
+/// shell's status.
+fn session_outcome(program: &str, host: &str, status: std::process::ExitStatus) -> String {
+    match status.code() {
+        Some(0) => format!("✔ Session to {} exited normally.", host),
+        Some(255) => format!(
+            "❌ {} couldn't connect to {} (exit 255). Check the HostName, Port and your login, or press p to test the port.",
+            program, host
+        ),
+        Some(code) => format!("⚠ Session to {} exited with code {}.", host, code),
+        None => format!("⚠ Session to {} was ended by a signal.", host),
+    }
+}
+
+/// `ssh <host>`, or the configured connect template filled in for the
+/// host. Connecting by alias lets OpenSSH apply everything in the host's
+/// block, not just the fields shown here. The template is split into
+/// arguments before the placeholders are filled, so values with spaces
+/// stay whole; arguments that come out empty (no `{user}`) are dropped.
+fn ssh_command(host: &HostEntry, settings: &Settings, inherited: &BTreeSet<String>) -> Vec<String> {
+    let ssh = || with_options("ssh", &connect_options(host, settings, inherited));
+    // The template was checked at startup; a bad one falls back to ssh.
+    let Ok(Some(template)) = settings.connect_template() else {
+        let mut command = ssh();
+        command.push(host.alias().to_string());
+        return command;
+    };
+    let lookup = |name: &str| -> Option<Vec<String>> {
+        let value = match name {
+            "cmd" => return Some(ssh()),
+            "host" => host.alias(),
+            "user" => host.user.as_deref().unwrap_or_default(),
+            "port" => host.port.as_deref().unwrap_or("22"),
+            "identity" => host.identity_files.first().map(String::as_str).unwrap_or_default(),
+            _ => return None,
+        };
+        Some(vec![value.to_string()])
+    };
+    template
+        .iter()
+        .flat_map(|arg| expand_template(arg, lookup).unwrap_or_default())
+        .filter(|arg| !arg.is_empty())
+        .collect()
+}
+
+/// Seconds ssh waits for a connection when neither the host nor the
+/// `connect_timeout` setting says otherwise; ssh's own default is the
+/// system's TCP timeout, which can be minutes.
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+
+/// The `-o` options a connection gets: the host's `# SshOption`s, then
+/// those from the settings. A setting is left out when the host's block,
+/// its options or anything in `inherited` (see `inherited_directives`)
+/// sets that directive, since the command line would otherwise win over it.
+fn connect_options(host: &HostEntry, settings: &Settings, inherited: &BTreeSet<String>) -> Vec<String> {
+    let sets = |directive: &str| {
+        inherited.contains(&directive.to_ascii_lowercase())
+            || raw_directives(host).any(|(_, keyword, _)| keyword.eq_ignore_ascii_case(directive))
+            || host.ssh_options.iter().any(|o| split_directive(o).0.eq_ignore_ascii_case(directive))
+    };
+    let mut options: Vec<String> = host.ssh_options.iter().flat_map(|o| ["-o".to_string(), o.clone()]).collect();
+    let timeout = settings.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+    if timeout > 0 && host.connect_timeout.is_none() && !sets("ConnectTimeout") {
+        options.extend(["-o".to_string(), format!("ConnectTimeout={}", timeout)]);
+    }
+    if let Some(attempts) = settings.connection_attempts
+        && !sets("ConnectionAttempts")
+    {
+        options.extend(["-o".to_string(), format!("ConnectionAttempts={}", attempts)]);
+    }
+    options
+}
+
+/// Directives (lowercased) that reach `host` from outside its own block:
+/// anything in the `preambles` (global options and leading `Host *`
+/// blocks), and the blocks of other `hosts` that are patterns matching its
+/// alias or `Match` blocks. Errs on the side of saying a directive is set,
+/// since then putty only leaves out an option ssh would have had anyway.
+fn inherited_directives(preambles: &[&str], hosts: &[HostEntry], host: &HostEntry) -> BTreeSet<String> {
+    let alias = host.alias();
+    let applies = |other: &HostEntry| {
+        let patterns = other.patterns();
+        other.match_block
+            || (patterns.iter().any(|p| glob_match(p, alias))
+                && !patterns.iter().any(|p| p.strip_prefix('!').is_some_and(|p| glob_match(p, alias))))
+    };
+    let blocks = hosts
+        .iter()
+        .filter(|other| *other != host && !other.disabled && other.is_pattern() && applies(other))
+        .map(|other| serialize_block(other, false));
+    let mut directives = BTreeSet::new();
+    for text in preambles.iter().map(|p| p.to_string()).chain(blocks) {
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let keyword = split_directive(line).0.to_ascii_lowercase();
+            if !matches!(keyword.as_str(), "host" | "match" | "include") {
+                directives.insert(keyword);
+            }
+        }
+    }
+    directives
+}
+
+/// `ssh -t <host> <command>` for `x`. The command goes over as one
+/// argument for the remote shell to split, as if quoted on a local
+/// command line; `-t` gives it a terminal so pagers and prompts work.
+fn remote_command(host: &HostEntry, remote: &str, options: &[String]) -> Vec<String> {
+    let mut command = with_options("ssh", options);
+    command.extend(["-t".to_string(), host.alias().to_string(), remote.to_string()]);
+    command
+}
+
+/// `program` (ssh, sftp) with `-F <config>` when `--config` or
+/// `PUTTY_SSH_CONFIG` pointed putty somewhere other than the file OpenSSH
+/// reads by default, so aliases resolve the same way for both.
+fn ssh_program(program: &str) -> Vec<String> {
+    let mut command = vec![program.to_string()];
+    if let Some(path) = config_override() {
+        command.push("-F".to_string());
+        command.push(path.display().to_string());
+    }
+    command
+}
+
+/// `ssh_program(program)` followed by `options` (from `connect_options`).
+/// Every launch path builds its ssh, sftp or mosh bootstrap from this, so
+/// the host's `# SshOption`s and the timeout settings reach all of them.
+fn with_options(program: &str, options: &[String]) -> Vec<String> {
+    let mut command = ssh_program(program);
+    command.extend(options.iter().cloned());
+    command
+}
+
+/// Wraps `command` in `sshpass -e`, which reads the password from the
+/// `SSHPASS` variable `run_session` sets. Unlike `-p` that keeps it out of
+/// the command line, the process list and the connect log.
+fn sshpass_command(command: Vec<String>) -> Vec<String> {
+    ["sshpass".to_string(), "-e".to_string()].into_iter().chain(command).collect()
+}
+
+/// Whether `program` can be found in a `PATH` directory.
+fn on_path(program: &str) -> bool {
+    let Some(paths) = std::env::var_os("PATH") else {
+        return false;
+    };
+    std::env::split_paths(&paths).any(|dir| {
+        let path = dir.join(program);
+        path.is_file() || (cfg!(windows) && path.with_extension("exe").is_file())
+    })
+}
+
+/// A way to install a host's public key on the server (`C`).
+#[derive(Debug, PartialEq)]
+struct CopyId {
+    command: Vec<String>,
+    /// File to feed the command on stdin, for the plain ssh fallback.
+    input: Option<PathBuf>,
+    /// The `.pub` file being installed, as written in messages.
+    public_key: String,
+}
+
+/// `ssh-copy-id -i <key>.pub <host>` for the host's first IdentityFile, or
+/// without ssh-copy-id (Windows doesn't ship it) an ssh that appends the key
+/// to `~/.ssh/authorized_keys` itself. The fallback is also used when putty
+/// manages another config, since not every ssh-copy-id takes `-F`.
+fn copy_id_command(host: &HostEntry, have_copy_id: bool, options: &[String]) -> Result<CopyId, String> {
+    let identity = host.identity_files.first().ok_or_else(|| no_identity_file(host))?;
+    let public_key = format!("{}.pub", identity);
+    let path = expand_path(&public_key);
+    if !path.is_file() {
+        return Err(format!("No public key at {}; press G to generate a key pair.", public_key));
+    }
+    if have_copy_id && config_override().is_none() {
+        let mut command = vec!["ssh-copy-id".to_string(), "-i".to_string(), path.display().to_string()];
+        command.extend(options.iter().cloned());
+        command.push(host.alias().to_string());
+        return Ok(CopyId { command, input: None, public_key });
+    }
+    let mut command = with_options("ssh", options);
+    command.push(host.alias().to_string());
+    command.push("umask 077; mkdir -p ~/.ssh && cat >> ~/.ssh/authorized_keys".to_string());
+    Ok(CopyId { command, input: Some(path), public_key })
+}
+
+/// Runs a `copy_id_command` on the real terminal, so the server password
+/// can be typed, and describes how it went.
+fn run_copy_id(copy: &CopyId, host: &str) -> String {
+    let mut command = std::process::Command::new(&copy.command[0]);
+    command.args(&copy.command[1..]);
+    if let Some(input) = &copy.input {
+        match fs::File::open(input) {
+            Ok(file) => command.stdin(file),
+            Err(e) => return format!("❌ Can't read {}: {}", copy.public_key, e),
+        };
+    }
+    match command.status() {
+        Ok(s) if s.success() => format!("✔ Installed {} on {}; ssh should now log in with the key.", copy.public_key, host),
+        // ssh and ssh-copy-id both use 255 (or 1) when they never got in.
+        Ok(s) if s.code() == Some(255) => {
+            format!("❌ {} couldn't connect or log in to {} (code 255)", copy.command[0], host)
+        }
+        Ok(s) => format!("❌ {} failed with code {}", copy.command[0], s.code().unwrap_or(-1)),
+        Err(e) => launch_error(&copy.command[0], &e),
+    }
+}
+
+/// `mosh <host>`, telling mosh's ssh bootstrap about a non-default port.
+fn mosh_command(host: &HostEntry, options: &[String]) -> Vec<String> {
+    let mut command = vec!["mosh".to_string()];
+    let mut bootstrap = with_options("ssh", options);
+    if let Some(port) = &host.port {
+        bootstrap.extend(["-p".to_string(), port.clone()]);
+    }
+    if bootstrap.len() > 1 {
+        command.push(format!("--ssh={}", join_args(&bootstrap)));
+    }
+    command.push(host.alias().to_string());
+    command
+}
+
+/// The `p` report for `host`: whether its address and port accept a TCP
+/// connection within `PROBE_TIMEOUT`. With `log` the test goes in the
+/// connect log as a `probe`, with no command and exit code 0 if the port
+/// answered or 1 if not.
+fn probe_report(host: &HostEntry, log: bool) -> String {
+    if !host.is_connectable() {
+        return format!("{} is a pattern, not a host you can reach", host.name);
+    }
+    let target = host.hostname_candidates()[0].to_string();
+    let port = host.port.as_deref().and_then(|p| p.parse().ok()).unwrap_or(22);
+    let result = probe(&target, port, PROBE_TIMEOUT);
+    if log {
+        let _ = append_connect_log("probe", host, &[], Some(if result.is_ok() { 0 } else { 1 }));
+    }
+    match result {
+        Ok(took) => format!("✔ {} ({}:{}) is reachable ({} ms)", host.name, target, port, took.as_millis()),
+        Err(why) => format!("❌ {} ({}:{}) is unreachable ({})", host.name, target, port, why),
+    }
+}
+
+/// The `r` report for `host`: the addresses each of its HostName entries
+/// resolves to, so a stale DNS record shows up before connecting.
+fn resolve_report(host: &HostEntry) -> String {
+    if !host.is_connectable() {
+        return format!("{} is a pattern, not a host you can look up", host.name);
+    }
+    let mut report = Vec::new();
+    for name in host.hostname_candidates() {
+        report.push(match resolve(name, PROBE_TIMEOUT) {
+            Ok(addrs) if addrs.len() == 1 && addrs[0].to_string() == name => format!("✔ {} is an IP address", name),
+            Ok(addrs) => {
+                let addrs: Vec<String> = addrs.iter().map(|a| format!("  {}", a)).collect();
+                format!("✔ {} resolves to\n{}", name, addrs.join("\n"))
+            }
+            Err(why) => format!("❌ Could not resolve {} ({})", name, why),
+        });
+    }
+    report.join("\n\n")
+}
+
+/// Looks `name` up with the system resolver, giving each address once.
+/// Like `probe`, the lookup runs on a thread abandoned after `timeout`.
+fn resolve(name: &str, timeout: Duration) -> Result<Vec<std::net::IpAddr>, String> {
+    use std::net::ToSocketAddrs;
+    let (tx, rx) = mpsc::channel();
+    let name = name.to_string();
+    std::thread::spawn(move || {
+        let result = (name.as_str(), 0).to_socket_addrs().map_err(|e| e.to_string()).and_then(|found| {
+            let mut addrs = Vec::new();
+            for addr in found {
+                if !addrs.contains(&addr.ip()) {
+                    addrs.push(addr.ip());
+                }
+            }
+            if addrs.is_empty() { Err("no addresses".to_string()) } else { Ok(addrs) }
+        });
+        let _ = tx.send(result);
+    });
+    rx.recv_timeout(timeout).unwrap_or_else(|_| Err("timeout".to_string()))
+}
+
+/// Opens and drops a TCP connection to `host:port`, returning how long it
+/// took. Name resolution can't be given a timeout, so the work happens on a
+/// thread that is abandoned if it overruns.
+fn probe(host: &str, port: u16, timeout: Duration) -> Result<Duration, String> {
+    let (tx, rx) = mpsc::channel();
+    let host = host.to_string();
+    std::thread::spawn(move || {
+        let started = Instant::now();
+        let _ = tx.send(connect_any(&host, port, timeout).map(|()| started.elapsed()));
+    });
+    rx.recv_timeout(timeout).unwrap_or_else(|_| Err("timeout".to_string()))
+}
+
+/// Host name, address and port for each host the prober should check.
+fn probe_targets(hosts: &[HostEntry]) -> Vec<(String, String, u16)> {
+    hosts
+        .iter()
+        .filter(|h| h.is_connectable() && !h.disabled)
+        .map(|h| {
+            let port = h.port.as_deref().and_then(|p| p.parse().ok()).unwrap_or(22);
+            (h.name.clone(), h.hostname_candidates()[0].to_string(), port)
+        })
+        .collect()
+}
+
+/// Checks every host's port every `interval` on a background thread, which
+/// fans each round out over `PROBE_WORKERS` threads. Results arrive on
+/// `results` as `(name, reachable)`. Dropping it stops the threads and waits
+/// for any check in flight.
+struct Prober {
+    targets: Arc<Mutex<Vec<(String, String, u16)>>>,
+    results: mpsc::Receiver<(String, bool)>,
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Prober {
+    fn start(interval: Duration, hosts: &[HostEntry]) -> Self {
+        let targets = Arc::new(Mutex::new(probe_targets(hosts)));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, results) = mpsc::channel();
+        let thread = {
+            let (targets, stop) = (targets.clone(), stop.clone());
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let queue = Mutex::new(targets.lock().unwrap().clone());
+                    std::thread::scope(|s| {
+                        for _ in 0..PROBE_WORKERS {
+                            let tx = tx.clone();
+                            let (queue, stop) = (&queue, &stop);
+                            s.spawn(move || {
+                                while !stop.load(Ordering::Relaxed) {
+                                    let Some((name, host, port)) = queue.lock().unwrap().pop() else {
+                                        break;
+                                    };
+                                    let up = connect_any(&host, port, PROBE_TIMEOUT).is_ok();
+                                    if tx.send((name, up)).is_err() {
+                                        break;
+                                    }
+                                }
+                            });
+                        }
+                    });
+                    // Sleep in slices so quitting doesn't wait out the interval.
+                    let round_end = Instant::now() + interval;
+                    while !stop.load(Ordering::Relaxed) && Instant::now() < round_end {
+                        sleep(Duration::from_millis(100).min(round_end - Instant::now()));
+                    }
+                }
+            })
+        };
+        Self { targets, results, stop, thread: Some(thread) }
+    }
+
+    /// Points the next round at `hosts`, e.g. after an edit.
+    fn set_targets(&self, hosts: &[HostEntry]) {
+        *self.targets.lock().unwrap() = probe_targets(hosts);
+    }
+}
+
+impl Drop for Prober {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Tries each address `host` resolves to until one accepts.
+fn connect_any(host: &str, port: u16, timeout: Duration) -> Result<(), String> {
+    let addrs = std::net::ToSocketAddrs::to_socket_addrs(&(host, port)).map_err(|e| format!("can't resolve: {}", e))?;
+    let mut last = "no addresses".to_string();
+    for addr in addrs {
+        match std::net::TcpStream::connect_timeout(&addr, timeout) {
+            Ok(_) => return Ok(()),
+            Err(e) => last = probe_error(&e),
+        }
+    }
+    Err(last)
+}
+
+fn probe_error(e: &io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => "timeout".to_string(),
+        io::ErrorKind::ConnectionRefused => "connection refused".to_string(),
+        _ => e.to_string(),
+    }
+}
+
+/// Explains why `program` couldn't be started.
+fn launch_error(program: &str, e: &io::Error) -> String {
+    if e.kind() == io::ErrorKind::NotFound {
+        let hint = if cfg!(windows) && matches!(program, "ssh" | "sftp") {
+            "\nInstall the OpenSSH Client under Settings > Optional features."
+        } else {
+            ""
+        };
+        format!("❌ Couldn't run {}: it isn't installed or isn't on PATH{}", program, hint)
+    } else {
+        format!("❌ Couldn't run {}: {}", program, e)
+    }
+}
+
+/// Splits typed arguments like a POSIX shell would for simple cases:
+/// whitespace separates, quotes group, and a backslash escapes the next
+/// character outside single quotes.
+fn split_args(input: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some('\\') if c == '"' => arg.extend(chars.next()),
+                        Some(other) => arg.push(other),
+                        None => return Err(format!("Unterminated {} quote", c)),
+                    }
+                }
+            }
+            '\\' => current.get_or_insert_with(String::new).extend(chars.next()),
+            c if c.is_whitespace() => args.extend(current.take()),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(current);
+    Ok(args)
+}
+
+/// Renders a command for display, single-quoting arguments that need it.
+fn join_args(command: &[String]) -> String {
+    command
+        .iter()
+        .map(|arg| {
+            if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "'\"\\$`".contains(c)) {
+                arg.clone()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Builds `ssh -J hop1,hop2 target` from hosts marked in order: the last
+/// one is the destination and the rest are jump hosts. `options` are the
+/// destination's.
+fn chain_command(chain: &[&HostEntry], options: &[String]) -> Result<Vec<String>, String> {
+    if chain.len() < 2 {
+        return Err("Mark at least two hosts with [Space] to connect through a chain".to_string());
+    }
+    if let Some(pattern) = chain.iter().find(|h| !h.is_connectable()) {
+        return Err(format!("{} is a pattern, not a host you can connect to", pattern.name));
+    }
+    if let Some(off) = chain.iter().find(|h| h.disabled) {
+        return Err(format!("{} is disabled", off.name));
+    }
+    let mut seen = BTreeSet::new();
+    if let Some(dup) = chain.iter().find(|h| !seen.insert(h.name.as_str())) {
+        return Err(format!("{} appears more than once in the chain", dup.name));
+    }
+    let (target, hops) = chain.split_last().unwrap();
+    let hops = hops.iter().map(|h| h.alias()).collect::<Vec<_>>().join(",");
+    let mut command = with_options("ssh", options);
+    command.extend(["-J".to_string(), hops, target.alias().to_string()]);
+    Ok(command)
+}
+
+/// Service name the keyring entries are filed under; the account is the
+/// host name.
+const KEYRING_SERVICE: &str = "putty";
+
+/// Fills in the passwords of hosts whose config only has the keyring
+/// marker. Returns a line per host that couldn't be read.
+fn load_keyring_passwords(hosts: &mut [HostEntry]) -> Vec<String> {
+    let mut errors = Vec::new();
+    for host in hosts.iter_mut().filter(|h| h.password_in_keyring) {
+        match keyring_get(&host.name) {
+            Ok(password) => host.password = Some(password),
+            Err(e) => errors.push(format!("{}: {}", host.name, e)),
+        }
+    }
+    errors
+}
+
+/// Reads the password stored for `host`: `security` on macOS,
+/// `secret-tool` on Linux and the Windows Credential Manager via PowerShell.
+fn keyring_get(host: &str) -> io::Result<String> {
+    let mut output = if cfg!(windows) {
+        keyring_powershell(
+            "$c = $v.Retrieve($service, $name); $c.RetrievePassword(); [Console]::Out.Write($c.Password)",
+            &format!("{}\n", host),
+        )?
+    } else if cfg!(target_os = "macos") {
+        run_with_input(
+            std::process::Command::new("security").args(["find-generic-password", "-s", KEYRING_SERVICE, "-a", host, "-w"]),
+            "",
+        )?
+    } else {
+        run_with_input(
+            std::process::Command::new("secret-tool").args(["lookup", "service", KEYRING_SERVICE, "host", host]),
+            "",
+        )?
+    };
+    if cfg!(target_os = "macos") && output.ends_with('\n') {
+        output.pop();
+    }
+    Ok(output)
+}
+
+/// Stores `password` for `host`, replacing any earlier entry.
+fn keyring_set(host: &str, password: &str) -> io::Result<()> {
+    if cfg!(windows) {
+        keyring_powershell(
+            "$v.Add((New-Object Windows.Security.Credentials.PasswordCredential($service, $name, [Console]::In.ReadToEnd())))",
+            &format!("{}\n{}", host, password),
+        )?;
+    } else if cfg!(target_os = "macos") {
+        // Given as an argument, the secret would show up in `ps`; in
+        // interactive mode `security` reads the command from stdin instead.
+        if password.contains('\n') {
+            let why = "the keychain can't store a password with a line break";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, why));
+        }
+        let command = ["add-generic-password", "-U", "-s", KEYRING_SERVICE, "-a", host, "-w", password]
+            .map(security_quote)
+            .join(" ");
+        run_with_input(std::process::Command::new("security").arg("-i"), &format!("{}\n", command))?;
+    } else {
+        let label = format!("putty: {}", host);
+        run_with_input(
+            std::process::Command::new("secret-tool").args([
+                "store",
+                "--label",
+                &label,
+                "service",
+                KEYRING_SERVICE,
+                "host",
+                host,
+            ]),
+            password,
+        )?;
+    }
+    Ok(())
+}
+
+/// Quotes `arg` for a command line typed into `security -i`.
+fn security_quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Runs a PasswordVault `script` with `$v` bound to the vault, `$service`
+/// to `KEYRING_SERVICE` and `$name` to the first line of `input`; the rest
+/// of `input` is left on stdin.
+fn keyring_powershell(script: &str, input: &str) -> io::Result<String> {
+    let script = format!(
+        "[Console]::InputEncoding = [Console]::OutputEncoding = [Text.Encoding]::UTF8; \
+         $null = [Windows.Security.Credentials.PasswordVault, Windows.Security.Credentials, ContentType = WindowsRuntime]; \
+         $v = New-Object Windows.Security.Credentials.PasswordVault; $service = '{}'; \
+         $name = [Console]::In.ReadLine(); {}",
+        KEYRING_SERVICE, script
+    );
+    run_with_input(
+        std::process::Command::new("powershell").args(["-NoProfile", "-NonInteractive", "-Command", &script]),
+        input,
+    )
+}
+
+/// Puts `text` on the system clipboard with the platform's own tool.
+fn copy_to_clipboard(text: &str) -> io::Result<()> {
+    let candidates: &[&[&str]] = if cfg!(windows) {
+        &[&["clip"]]
+    } else if cfg!(target_os = "macos") {
+        &[&["pbcopy"]]
+    } else if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        &[&["wl-copy"], &["xclip", "-selection", "clipboard"]]
+    } else {
+        &[&["xclip", "-selection", "clipboard"], &["xsel", "--clipboard", "--input"]]
+    };
+    let Some(tool) = candidates.iter().find(|tool| on_path(tool[0])) else {
+        let names: Vec<&str> = candidates.iter().map(|tool| tool[0]).collect();
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("install {}", names.join(" or "))));
+    };
+    // xclip and wl-copy stay behind to serve the selection, so don't wait
+    // on their output, only on the process we started.
+    let mut child = std::process::Command::new(tool[0])
+        .args(&tool[1..])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(text.as_bytes())?;
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("{} exited with {}", tool[0], status)))
+    }
+}
+
+/// Runs `command` with `input` on stdin and returns its stdout, or an error
+/// carrying its stderr if it fails.
+fn run_with_input(command: &mut std::process::Command, input: &str) -> io::Result<String> {
+    let mut child = command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(input.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        return Err(io::Error::other(if stderr.is_empty() {
+            format!("exited with {}", output.status)
+        } else {
+            stderr
+        }));
+    }
+    String::from_utf8(output.stdout).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Opens `url` with the platform's default handler without waiting on it.
+fn open_in_browser(url: &str) -> io::Result<()> {
+    let mut command = if cfg!(windows) {
+        // `start` would need cmd.exe quoting for `&` in query strings.
+        let mut c = std::process::Command::new("rundll32");
+        c.arg("url.dll,FileProtocolHandler");
+        c
+    } else if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+    command
+        .arg(url)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .map(|_| ())
+}
+
+/// Locks a key file down to the current user: icacls on Windows, mode 600
+/// elsewhere. Returns the report shown in the status popup.
+fn secure_keyfile(identity_file: &str) -> String {
+    let path = expand_path(identity_file);
+    if cfg!(windows) {
+        secure_keyfile_icacls(&path)
+    } else {
+        secure_keyfile_chmod(&path)
+    }
+}
+
+/// Directives the connect template refers to that `host` doesn't set, so
+/// their arguments were left out.
+fn unset_template_fields(host: &HostEntry, settings: &Settings) -> Vec<&'static str> {
+    let Ok(Some(template)) = settings.connect_template() else {
+        return Vec::new();
+    };
+    let uses = |placeholder: &str| template.iter().any(|arg| arg.contains(placeholder));
+    let mut unset = Vec::new();
+    if uses("{user}") && host.user.is_none() {
+        unset.push("User");
+    }
+    if uses("{identity}") && host.identity_files.is_empty() {
+        unset.push("IdentityFile");
+    }
+    unset
+}
+
+/// Why `k` and `a` did nothing for `host`.
+fn no_identity_file(host: &HostEntry) -> String {
+    format!("No IdentityFile configured for {}.", host.name)
+}
+
+/// The icacls arguments, after the file, that leave only the current user
+/// able to read a key.
+fn icacls_steps() -> Vec<Vec<String>> {
+    let username = std::env::var("USERNAME").unwrap_or_else(|_| "User".to_string());
+    [
+        vec!["/reset".to_string()],
+        vec!["/inheritance:r".to_string()],
+        vec!["/remove".to_string(), "NT AUTHORITY\\Authenticated Users".to_string()],
+        vec!["/remove".to_string(), "BUILTIN\\Users".to_string()],
+        vec!["/remove".to_string(), "Everyone".to_string()],
+        vec!["/grant:r".to_string(), format!("{}:R", username)],
+    ]
+    .into()
+}
+
+/// What `secure_keyfile` will run for `path`, for the confirmation.
+fn keyfile_commands(path: &Path) -> Vec<String> {
+    let file = path.display().to_string();
+    if cfg!(windows) {
+        icacls_steps()
+            .into_iter()
+            .map(|args| join_args(&[vec!["icacls".to_string(), file.clone()], args].concat()))
+            .collect()
+    } else {
+        vec![join_args(&["chmod".to_string(), "600".to_string(), file])]
+    }
+}
+
+fn secure_keyfile_icacls(identity_file: &Path) -> String {
+    let cmds = icacls_steps();
+
+    let mut full_output = String::new();
+    let mut failed = None;
+
+    for args in cmds {
+        let output = std::process::Command::new("icacls")
+            .arg(identity_file)
+            .args(&args)
+            .output();
+
+        match output {
+            Ok(out) => {
+                let stdout = String::from_utf8_lossy(&out.stdout);
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                full_output.push_str(&format!("> icacls {:?}\n", args));
+                if !stdout.is_empty() {
+                    full_output.push_str(&format!("stdout:\n{}\n", stdout));
+                }
+                if !stderr.is_empty() {
+                    full_output.push_str(&format!("stderr:\n{}\n", stderr));
+                }
+                if !out.status.success() {
+                    failed = Some(format!(
+                        "❌ icacls {:?} failed with code {}\n{}",
+                        args,
+                        out.status.code().unwrap_or(-1),
+                        full_output
+                    ));
+                    break;
+                }
+            }
+            Err(e) => {
+                failed = Some(format!("❌ Failed to run icacls {:?}: {}\n{}", args, e, full_output));
+                break;
+            }
+        }
+    }
+
+    match failed {
+        Some(msg) => msg,
+        None => format!("✔ Permissions fixed for {}\n{}", identity_file.display(), full_output),
+    }
+}
+
+#[cfg(unix)]
+fn secure_keyfile_chmod(identity_file: &Path) -> String {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut full_output = format!("> chmod 600 {}\n", identity_file.display());
+    if let Err(e) = fs::set_permissions(identity_file, fs::Permissions::from_mode(0o600)) {
+        return format!("❌ chmod 600 failed: {}\n{}", e, full_output);
+    }
+    match fs::metadata(identity_file) {
+        Ok(meta) => {
+            let mode = meta.permissions().mode() & 0o777;
+            full_output.push_str(&format!("mode is now {:o} ({})\n", mode, format_mode(mode)));
+            format!("✔ Permissions fixed for {}\n{}", identity_file.display(), full_output)
+        }
+        Err(e) => format!("❌ Could not read back permissions: {}\n{}", e, full_output),
+    }
+}
+
+/// Whether a key file grants access beyond its owner: any group or other
+/// permission bit on Unix, or an entry for a broad group in its ACL on
+/// Windows. A missing or unreadable file isn't flagged.
+fn key_is_exposed(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::metadata(path).is_ok_and(|meta| meta.permissions().mode() & 0o077 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        let Ok(out) = std::process::Command::new("icacls").arg(path).output() else {
+            return false;
+        };
+        out.status.success() && acl_is_broad(&String::from_utf8_lossy(&out.stdout))
+    }
+}
+
+/// Whether `icacls <file>` output lists one of the groups `k` removes.
+#[cfg_attr(unix, allow(dead_code))]
+fn acl_is_broad(icacls_output: &str) -> bool {
+    ["Everyone", "BUILTIN\\Users", "NT AUTHORITY\\Authenticated Users"]
+        .iter()
+        .any(|group| icacls_output.contains(&format!("{}:", group)))
+}
+
+#[cfg(not(unix))]
+fn secure_keyfile_chmod(identity_file: &Path) -> String {
+    format!("❌ Don't know how to secure {} on this platform", identity_file.display())
+}
+
+/// Renders permission bits like `ls -l` does, e.g. `rw-------`.
+#[cfg(unix)]
+fn format_mode(mode: u32) -> String {
+    (0..9)
+        .map(|bit| {
+            if mode & (0o400 >> bit) == 0 {
+                '-'
+            } else {
+                ['r', 'w', 'x'][bit % 3]
+            }
+        })
+        .collect()
+}
+
+/// Resolves a path as written in the config for use on disk: a leading
+/// `~` becomes the home directory and `$VAR`, `${VAR}` and `%VAR%` are
+/// substituted. The config value itself is never rewritten.
+fn expand_path(path: &str) -> PathBuf {
+    let expanded = expand_env_vars(path);
+    match expanded.strip_prefix('~') {
+        Some("") => home_dir(),
+        Some(rest) if rest.starts_with(['/', '\\']) => home_dir().join(&rest[1..]),
+        _ => PathBuf::from(expanded),
+    }
+}
+
+/// Substitutes environment variables; unset ones are left as written.
+fn expand_env_vars(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(pos) = rest.find(['%', '$']) {
+        out.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+        let (name, consumed) = if let Some(t) = tail.strip_prefix('%') {
+            match t.find('%') {
+                Some(end) => (&t[..end], end + 2),
+                None => ("", 1),
+            }
+        } else if let Some(t) = tail.strip_prefix("${") {
+            match t.find('}') {
+                Some(end) => (&t[..end], end + 3),
+                None => ("", 1),
+            }
+        } else {
+            let t = &tail[1..];
+            let end = t
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(t.len());
+            (&t[..end], end + 1)
+        };
+        match std::env::var(name).ok().filter(|_| !name.is_empty()) {
+            Some(v) => out.push_str(&v),
+            None => out.push_str(&tail[..consumed]),
+        }
+        rest = &tail[consumed..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Puts the terminal back to normal before a panic message prints, so it
+/// is readable and the shell isn't left in raw mode. The previous hook
+/// still runs afterwards and reports the panic (and backtrace) as usual.
+fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        disable_raw_mode().ok();
+        execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture, DisableBracketedPaste, crossterm::cursor::Show).ok();
+        default_hook(info);
+    }));
+}
+
+/// Hands the terminal back to a child process that needs it (ssh, ssh-add).
+fn suspend_tui(terminal: &mut Terminal<CrosstermBackend<Stdout>>) {
+    disable_raw_mode().ok();
+    execute!(
+        terminal.backend_mut(),
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+        DisableBracketedPaste
+    ).ok();
+    terminal.show_cursor().ok();
+}
+
+fn resume_tui(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> io::Result<()> {
+    enable_raw_mode()?;
+    execute!(terminal.backend_mut(), EnterAlternateScreen, EnableMouseCapture, EnableBracketedPaste)?;
+    terminal.clear()
+}
+
+/// Runs `ssh-keygen -t ed25519` (`program`) for a new key at `path`,
+/// replacing any key already there (the caller has asked). The old pair is
+/// moved aside first and only deleted once the new one is made, so a
+/// missing ssh-keygen, a failure or an aborted passphrase prompt leaves it
+/// as it was. Its prompts go to the terminal; what it prints is returned
+/// for the status popup.
+fn run_keygen(program: &str, path: &Path, comment: &str) -> io::Result<std::process::Output> {
+    let with_suffix = |suffix: &str| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+    let restore = |moved: &[(PathBuf, PathBuf)]| {
+        for (old, aside) in moved {
+            let _ = fs::rename(aside, old);
+        }
+    };
+    let mut moved = Vec::new();
+    let pair = [(path.to_path_buf(), with_suffix(".putty-old")), (with_suffix(".pub"), with_suffix(".pub.putty-old"))];
+    for (old, aside) in pair {
+        match fs::rename(&old, &aside) {
+            Ok(()) => moved.push((old, aside)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                restore(&moved);
+                return Err(e);
+            }
+        }
+    }
+    let result = std::process::Command::new(program)
+        .args(["-t", "ed25519", "-C", comment, "-f"])
+        .arg(path)
+        .stdin(std::process::Stdio::inherit())
+        .output();
+    if matches!(&result, Ok(output) if output.status.success()) {
+        for (_, aside) in &moved {
+            let _ = fs::remove_file(aside);
+        }
+    } else {
+        restore(&moved);
+    }
+    result
+}
+
+/// Compares the key's fingerprint against `ssh-add -l`. Any failure (no
+/// agent, unreadable key) counts as not loaded so ssh-add gets to report it.
+fn key_loaded_in_agent(identity_file: &Path) -> bool {
+    let fingerprint = std::process::Command::new("ssh-keygen")
+        .args(["-l", "-f"])
+        .arg(identity_file)
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| {
+            String::from_utf8_lossy(&out.stdout)
+                .split_whitespace()
+                .nth(1)
+                .map(str::to_string)
+        });
+    let Some(fingerprint) = fingerprint else {
+        return false;
+    };
+    std::process::Command::new("ssh-add")
+        .arg("-l")
+        .output()
+        .map(|out| String::from_utf8_lossy(&out.stdout).contains(&fingerprint))
+        .unwrap_or(false)
+}
+
+fn agent_hint() -> &'static str {
+    if cfg!(windows) {
+        "\nIs the OpenSSH Authentication Agent service running? (Start-Service ssh-agent)"
+    } else {
+        "\nIs ssh-agent running and SSH_AUTH_SOCK set?"
+    }
+}
+
+/// Whether to act on `key`. Windows and kitty-protocol terminals also
+/// report releases (and repeats), which would otherwise fire every action
+/// twice; repeats only count for `is_repeatable` keys.
+fn key_wanted(key: KeyEvent) -> bool {
+    match key.kind {
+        KeyEventKind::Press => true,
+        KeyEventKind::Repeat => is_repeatable(key.code),
+        KeyEventKind::Release => false,
+    }
+}
+
+/// Keys that may auto-repeat while held. Anything that connects, saves or
+/// opens a dialog should only fire once per press.
+fn is_repeatable(code: KeyCode) -> bool {
+    matches!(
+        code,
+        KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right | KeyCode::Backspace | KeyCode::Delete
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Serialises tests that modify process environment variables.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn with_env_var<T>(key: &str, value: &str, f: impl FnOnce() -> T) -> T {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let previous = std::env::var_os(key);
+        // SAFETY: env-mutating tests hold ENV_LOCK, so nothing else is
+        // reading or writing the environment concurrently.
+        unsafe { std::env::set_var(key, value) };
+        let result = f();
+        match previous {
+            Some(v) => unsafe { std::env::set_var(key, v) },
+            None => unsafe { std::env::remove_var(key) },
+        }
+        result
+    }
+
+    #[test]
+    fn atomic_write_keeps_a_backup_of_the_previous_contents() {
+        let dir = std::env::temp_dir().join(format!("putty-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config");
+        let before = "Host old\n    HostName 10.0.0.1\n";
+        fs::write(&path, before).unwrap();
+
+        write_config_atomic(&path, "Host new\n", false).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "Host new\n");
+        assert_eq!(fs::read_to_string(dir.join("config.bak")).unwrap(), before);
+        assert!(!dir.join(".config.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn includes_are_followed_and_written_back_to_their_file() {
+        let home = std::env::temp_dir().join(format!("putty-include-{}", std::process::id()));
+        let ssh = home.join(".ssh");
+        fs::create_dir_all(ssh.join("config.d")).unwrap();
+        let main = "Include config.d/*\n\nHost main\n    HostName 10.0.0.1\n\n";
+        fs::write(ssh.join("config"), main).unwrap();
+        // Includes the main config again, which must not loop.
+        fs::write(ssh.join("config.d/work"), "Include ~/.ssh/config\n\nHost work\n    HostName 10.0.0.2\n\n").unwrap();
+        fs::write(ssh.join("config.d/.ignored"), "Host hidden\n").unwrap();
+
+        with_env_var(home_var(), home.to_str().unwrap(), || {
+            let loaded = load_config_file().unwrap();
+            let names: Vec<&str> = loaded.hosts.iter().map(|h| h.name.as_str()).collect();
+            assert_eq!(names, vec!["work", "main"]);
+            assert_eq!(loaded.hosts[0].source.as_deref(), Some(ssh.join("config.d/work").as_path()));
+            assert_eq!(loaded.hosts[1].source, None);
+
+            let mut app = AppState::new(loaded.hosts, Settings::default());
+            app.preamble = loaded.preamble;
+            app.included = loaded.included;
+            app.hosts[0].user = Some("deploy".into());
+            app.save();
+            assert_eq!(app.save_error, None);
+            assert_eq!(app.flash.as_ref().unwrap().0, format!("✔ Saved to {}", ssh.join("config.d/work").display()));
+            app.save();
+            assert_eq!(app.flash.as_ref().unwrap().0, "✔ Nothing changed; the config already says this.");
+        });
+
+        let work = fs::read_to_string(ssh.join("config.d/work")).unwrap();
+        assert!(work.contains("Host work\n    HostName 10.0.0.2\n    User deploy\n"));
+        assert_eq!(fs::read_to_string(ssh.join("config")).unwrap(), main);
+        assert!(ssh.join("config.d/.work.bak").exists());
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob_match("*", "web"));
+        assert!(glob_match("*.conf", "a.conf"));
+        assert!(glob_match("w?b*", "web-01"));
+        assert!(!glob_match("*.conf", "a.conf.bak"));
+        assert!(!glob_match("w?b", "wb"));
+    }
+
+    fn home_var() -> &'static str {
+        if cfg!(windows) { "USERPROFILE" } else { "HOME" }
+    }
+
+    #[test]
+    fn the_config_flag_beats_the_environment_which_beats_the_default() {
+        let flag = Some(PathBuf::from("/from/flag"));
+        let env = || Some("/from/env ".to_string());
+        assert_eq!(pick_config_override(flag.clone(), env()), flag);
+        assert_eq!(pick_config_override(None, env()), Some(PathBuf::from("/from/env")));
+        assert_eq!(pick_config_override(None, Some(" ".to_string())), None);
+        assert_eq!(pick_config_override(None, None), None);
+        with_env_var(CONFIG_ENV, "/from/env", || {
+            assert_eq!(ssh_config_path(), PathBuf::from("/from/env"));
+            assert_eq!(ssh_program("ssh"), ["ssh", "-F", "/from/env"]);
+        });
+    }
+
+    #[test]
+    fn expand_path_handles_tilde() {
+        with_env_var(home_var(), "/home/alice", || {
+            assert_eq!(expand_path("~"), home_dir());
+            assert_eq!(expand_path("~/.ssh/id_ed25519"), home_dir().join(".ssh/id_ed25519"));
+        });
+    }
+
+    #[test]
+    fn expand_path_passes_absolute_paths_through() {
+        assert_eq!(expand_path("/etc/ssh/key"), PathBuf::from("/etc/ssh/key"));
+        assert_eq!(expand_path(r"C:\keys\id_rsa"), PathBuf::from(r"C:\keys\id_rsa"));
+    }
+
+    #[test]
+    fn expand_path_substitutes_env_vars() {
+        with_env_var("PUTTY_TEST_KEYS", "/keys", || {
+            assert_eq!(expand_path("$PUTTY_TEST_KEYS/id"), PathBuf::from("/keys/id"));
+            assert_eq!(expand_path("${PUTTY_TEST_KEYS}/id"), PathBuf::from("/keys/id"));
+            assert_eq!(expand_path("%PUTTY_TEST_KEYS%/id"), PathBuf::from("/keys/id"));
+            assert_eq!(expand_path("$PUTTY_TEST_UNSET/id"), PathBuf::from("$PUTTY_TEST_UNSET/id"));
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn config_path_uses_home_on_unix() {
+        let path = with_env_var("HOME", "/home/alice", ssh_config_path);
+        assert_eq!(path, PathBuf::from("/home/alice/.ssh/config"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn config_path_uses_userprofile_on_windows() {
+        let path = with_env_var("USERPROFILE", r"C:\Users\alice", ssh_config_path);
+        assert_eq!(path, PathBuf::from(r"C:\Users\alice\.ssh\config"));
+    }
+
+    #[test]
+    fn password_with_special_characters_round_trips() {
+        let password = "p#ss word\nsecond line";
+        let encoded = encode_password(password);
+        assert!(!encoded.contains(['#', ' ', '\n']));
+
+        let config = format!("Host box\n    HostName 10.0.0.1\n    # Password {}\n", encoded);
+        let hosts = HostEntry::parse_ssh_config(&config);
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].password.as_deref(), Some(password));
+    }
+
+    #[test]
+    fn comma_separated_hostname_uses_first_entry() {
+        let hosts = HostEntry::parse_ssh_config("Host rr\n    HostName web1.example.com, web2.example.com\n");
+        let host = &hosts[0];
+        assert_eq!(host.hostname_candidates(), vec!["web1.example.com", "web2.example.com"]);
+        assert_eq!(host.primary_hostname(), "web1.example.com");
+        // The stored value is left untouched so it is written back as-is.
+        assert_eq!(host.hostname.as_deref(), Some("web1.example.com, web2.example.com"));
+    }
+
+    #[test]
+    fn env_comment_is_parsed_case_insensitively() {
+        let hosts = HostEntry::parse_ssh_config("Host a\n    # Env PROD\nHost b\n    # Env qa\n");
+        assert_eq!(hosts[0].env, Some(Env::Prod));
+        assert_eq!(hosts[1].env, None);
+    }
+
+    #[test]
+    fn web_ui_tokens_expand_from_host_fields() {
+        let hosts = HostEntry::parse_ssh_config(
+            "Host nas\n    HostName 10.0.0.5\n    Port 2222\n    # WebUI https://%h:8443/?ssh=%p\n    # WebUI :5000\n",
+        );
+        let host = &hosts[0];
+        assert_eq!(host.web_ui_url(&host.web_ui[0]), "https://10.0.0.5:8443/?ssh=2222");
+        assert_eq!(host.web_ui_url(&host.web_ui[1]), "https://10.0.0.5:5000");
+    }
+
+    #[test]
+    fn extra_ssh_args_go_before_the_host_and_wait_for_confirmation() {
+        let host = HostEntry { name: "web".to_string(), ..Default::default() };
+        let mut app = AppState::new(vec![host], Settings::default());
+        app.submit_prompt(PromptKind::SshArgs, "-p 2222 -o 'ProxyCommand nc %h %p'".to_string()).unwrap();
+        let confirm = app.confirm.as_ref().unwrap();
+        assert_eq!(confirm.prompt, "Run ssh -p 2222 -o 'ProxyCommand nc %h %p' -o ConnectTimeout=10 web?");
+        assert_eq!(app.pending_launch, None);
+        app.answer_confirm(true);
+        let Launch { index, command, .. } = app.pending_launch.unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(command, vec!["ssh", "-p", "2222", "-o", "ProxyCommand nc %h %p", "-o", "ConnectTimeout=10", "web"]);
+    }
+
+    #[test]
+    fn split_args_rejects_unbalanced_quotes() {
+        assert_eq!(split_args(r#"-L "8080:localhost:80" a\ b"#).unwrap(), vec!["-L", "8080:localhost:80", "a b"]);
+        assert!(split_args("-o 'oops").is_err());
+    }
+
+    #[test]
+    fn duplicate_copies_every_field_below_the_original() {
+        let home = std::env::temp_dir().join(format!("putty-duplicate-{}", std::process::id()));
+        fs::create_dir_all(home.join(".ssh")).unwrap();
+        let web = HostEntry {
+            name: "web".to_string(),
+            hostname: Some("10.0.0.1".to_string()),
+            identity_files: vec!["~/.ssh/a".to_string(), "~/.ssh/b".to_string()],
+            password: Some("hunter2".to_string()),
+            ..Default::default()
+        };
+        let db = HostEntry { name: "db".to_string(), ..Default::default() };
+        let mut app = AppState::new(vec![web.clone(), db], Settings::default());
+
+        with_env_var(home_var(), home.to_str().unwrap(), || {
+            app.duplicate_selected();
+            app.selected = 0;
+            app.duplicate_selected();
+        });
+
+        let names: Vec<&str> = app.hosts.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(names, vec!["web", "web-copy-2", "web-copy", "db"]);
+        assert_eq!(app.selected, 1);
+        assert_eq!(app.hosts[2], HostEntry { name: "web-copy".to_string(), ..web });
+        let edit = app.edit_mode.as_ref().unwrap();
+        assert_eq!((edit.index, edit.current_row().directive.as_str()), (Some(1), "Host"));
+        let saved = fs::read_to_string(home.join(".ssh/config")).unwrap();
+        assert!(saved.contains("Host web-copy\n"));
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn saving_under_a_taken_name_keeps_the_form_open() {
+        let home = std::env::temp_dir().join(format!("putty-clash-{}", std::process::id()));
+        fs::create_dir_all(home.join(".ssh")).unwrap();
+        let host = |name: &str| HostEntry { name: name.to_string(), ..Default::default() };
+        let mut app = AppState::new(vec![host("prod"), host("*"), host("db")], Settings::default());
+        let rename = |app: &mut AppState, index: usize, name: &str| {
+            let mut edit = EditState::new(app.hosts[index].clone(), Some(index));
+            select(&mut edit, "Host");
+            *edit.current_value_mut().unwrap() = name.to_string();
+            app.edit_mode = Some(edit);
+        };
+
+        with_env_var(home_var(), home.to_str().unwrap(), || {
+            rename(&mut app, 2, "prod");
+            app.finish_edit();
+            let edit = app.edit_mode.as_ref().unwrap();
+            assert_eq!(edit.warning.as_deref(), Some("There is already a host named prod"));
+            assert_eq!(app.hosts[2].name, "db");
+
+            rename(&mut app, 2, "*");
+            app.finish_edit();
+            assert!(app.edit_mode.is_none());
+            assert_eq!(app.hosts[2].name, "*");
+            assert!(app.status_message.as_ref().unwrap().0.starts_with("⚠ Host * appears more than once"));
+        });
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn empty_or_spaced_host_names_are_refused() {
+        let mut app = AppState::new(Vec::new(), Settings::default());
+        for (name, warning) in [
+            ("", "Host name can't be empty"),
+            ("   ", "Host name can't be empty"),
+            ("web 2", "Host name can't contain spaces; ssh would read each word as a pattern"),
+        ] {
+            let mut edit = EditState::new(HostEntry::default(), None);
+            select(&mut edit, "Host");
+            *edit.current_value_mut().unwrap() = name.to_string();
+            app.edit_mode = Some(edit);
+            app.finish_edit();
+            assert_eq!(app.edit_mode.as_ref().unwrap().warning.as_deref(), Some(warning));
+            assert!(app.hosts.is_empty());
+        }
+    }
+
+    #[test]
+    fn marked_hosts_are_deleted_and_secured_together() {
+        let home = std::env::temp_dir().join(format!("putty-batch-{}", std::process::id()));
+        fs::create_dir_all(home.join(".ssh")).unwrap();
+        let host = |name: &str, key: &str| HostEntry {
+            name: name.to_string(),
+            identity_files: vec![key.to_string()],
+            ..Default::default()
+        };
+        let hosts = vec![host("a", "/keys/a"), host("b", "/keys/shared"), host("c", "/keys/shared"), host("d", "/keys/d")];
+        let mut app = AppState::new(hosts, Settings::default());
+        app.marked = vec!["c".to_string(), "b".to_string()];
+
+        app.ask_secure_keys();
+        let prompt = app.confirm.take().unwrap().prompt;
+        assert!(prompt.starts_with("Restrict these 1 key files"), "{}", prompt);
+        assert!(prompt.ends_with("/keys/shared (b, c)"), "{}", prompt);
+
+        with_env_var(home_var(), home.to_str().unwrap(), || {
+            app.selected = 3;
+            app.ask_delete();
+            assert_eq!(app.confirm.as_ref().unwrap().prompt, "Delete these 2 marked hosts?\n\nc\nb");
+            app.answer_confirm(true);
+            let names: Vec<&str> = app.hosts.iter().map(|h| h.name.as_str()).collect();
+            assert_eq!(names, ["a", "d"]);
+            assert_eq!(app.hosts[app.selected].name, "d");
+            assert!(app.marked.is_empty());
+            app.undo();
+            assert_eq!(app.hosts.len(), 4);
+        });
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn undo_and_redo_restore_and_rewrite_the_config() {
+        let home = std::env::temp_dir().join(format!("putty-undo-{}", std::process::id()));
+        fs::create_dir_all(home.join(".ssh")).unwrap();
+        let host = |name: &str| HostEntry { name: name.to_string(), ..Default::default() };
+        let mut app = AppState::new(vec![host("a"), host("b")], Settings::default());
+        let names = |app: &AppState| app.hosts.iter().map(|h| h.name.clone()).collect::<Vec<_>>();
+
+        with_env_var(home_var(), home.to_str().unwrap(), || {
+            app.selected = 1;
+            app.perform(Action::DeleteHost(1));
+            assert_eq!(names(&app), ["a"]);
+            app.undo();
+            assert_eq!(names(&app), ["a", "b"]);
+            assert!(fs::read_to_string(home.join(".ssh/config")).unwrap().contains("Host b\n"));
+            app.redo();
+            assert_eq!(names(&app), ["a"]);
+            assert!(!fs::read_to_string(home.join(".ssh/config")).unwrap().contains("Host b\n"));
+
+            // A fresh change drops what was undone.
+            app.undo();
+            app.duplicate_selected();
+            app.redo();
+            assert_eq!(app.flash.as_ref().unwrap().0, "Nothing to redo.");
+
+            for _ in 0..UNDO_LIMIT + 5 {
+                app.checkpoint();
+            }
+            assert_eq!(app.undo.len(), UNDO_LIMIT);
+        });
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn outside_changes_are_reloaded_or_asked_about() {
+        let home = std::env::temp_dir().join(format!("putty-reload-{}", std::process::id()));
+        let config = home.join(".ssh/config");
+        fs::create_dir_all(home.join(".ssh")).unwrap();
+        // Editors may save twice in the same second, so move the mtime on by hand.
+        let rewrite = |contents: &str, secs| {
+            fs::write(&config, contents).unwrap();
+            let file = fs::File::options().write(true).open(&config).unwrap();
+            file.set_modified(SystemTime::now() + Duration::from_secs(secs)).unwrap();
+        };
+        rewrite("Host a\n\nHost b\n", 0);
+
+        with_env_var(home_var(), home.to_str().unwrap(), || {
+            let mut app = AppState::new(Vec::new(), Settings::default());
+            app.install_config(load_config_file().unwrap());
+            app.selected = 1;
+            app.check_disk();
+            assert!(app.status_message.is_none());
+
+            rewrite("Host new\n\nHost a\n\nHost b\n", 10);
+            app.check_disk();
+            let names: Vec<&str> = app.hosts.iter().map(|h| h.name.as_str()).collect();
+            assert_eq!(names, ["new", "a", "b"]);
+            assert_eq!(app.hosts[app.selected].name, "b");
+
+            app.edit_mode = Some(EditState::new(app.hosts[0].clone(), Some(0)));
+            app.edit_mode.as_mut().unwrap().current_value_mut().unwrap().push('2');
+            rewrite("Host only\n", 20);
+            app.check_disk();
+            assert!(app.confirm.is_some());
+            app.answer_confirm(false);
+            app.check_disk();
+            assert!(app.confirm.is_none(), "asked twice about the same change");
+            assert_eq!(app.hosts.len(), 3);
+        });
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn tab_completes_and_cycles_key_file_paths() {
+        let dir = std::env::temp_dir().join(format!("putty-tabpath-{}", std::process::id()));
+        fs::create_dir_all(dir.join("id_dir")).unwrap();
+        for name in ["id_b", "id_a", ".id_hidden", "other"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let base = format!("{}/", dir.display());
+        assert_eq!(
+            path_candidates(&format!("{}id_", base)),
+            [format!("{}id_a", base), format!("{}id_b", base), format!("{}id_dir/", base)]
+        );
+        assert_eq!(path_candidates(&format!("{}.id", base)), [format!("{}.id_hidden", base)]);
+
+        let host = HostEntry { name: "web".into(), ..Default::default() };
+        let mut edit = EditState::new(host, Some(0));
+        select(&mut edit, "IdentityFile");
+        assert!(!edit.complete_path(), "an empty field moves on");
+        edit.paste(&format!("{}id_", base));
+        let mut seen = Vec::new();
+        for _ in 0..4 {
+            assert!(edit.complete_path());
+            seen.push(edit.current_row().value.trim_start_matches(&base).to_string());
+        }
+        assert_eq!(seen, ["id_a", "id_b", "id_dir/", "id_a"]);
+        assert_eq!(edit.path_choices().map(|(choices, i)| (choices.len(), i)), Some((3, 0)));
+
+        // Typing drops the cycle; a path naming the only match is complete.
+        edit.insert_char('x');
+        assert!(edit.path_choices().is_none());
+        edit.rows[edit.field_index].value = format!("{}other", base);
+        assert!(!edit.complete_path());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_remote_command_is_one_trailing_argument() {
+        let host = HostEntry { name: "web".into(), ..Default::default() };
+        let mut app = AppState::new(vec![host], Settings::default());
+        assert!(app.submit_prompt(PromptKind::RemoteCommand, "  ".into()).is_err());
+        app.submit_prompt(PromptKind::RemoteCommand, " systemctl status nginx ".into()).unwrap();
+        let launch = app.pending_launch.unwrap();
+        assert_eq!(launch.action, "command");
+        assert_eq!(launch.command, ["ssh", "-o", "ConnectTimeout=10", "-t", "web", "systemctl status nginx"]);
+    }
+
+    #[test]
+    fn the_status_line_counts_hosts_and_flags_unsaved_changes() {
+        let host = |name: &str, env| HostEntry { name: name.to_string(), env, ..Default::default() };
+        let mut app = AppState::new(vec![host("a", Some(Env::Prod)), host("b", None)], Settings::default());
+        assert_eq!(app.status_line().to_string(), "2 hosts · sort: file order");
+        app.env_filter = Some(Env::Prod);
+        app.sort = SortOrder::Name;
+        app.dirty = true;
+        assert_eq!(app.status_line().to_string(), "2 hosts, 1 shown · sort: name · unsaved changes");
+    }
+
+    #[test]
+    fn read_only_mode_refuses_changes_but_not_navigation() {
+        let key = |code, modifiers| KeyEvent::new(code, modifiers);
+        for (code, modifiers) in [
+            (KeyCode::Char('e'), KeyModifiers::NONE),
+            (KeyCode::Char('d'), KeyModifiers::NONE),
+            (KeyCode::Char('K'), KeyModifiers::SHIFT),
+            (KeyCode::Char('r'), KeyModifiers::CONTROL),
+            (KeyCode::Up, KeyModifiers::SHIFT),
+        ] {
+            assert!(changes_config(key(code, modifiers)), "{:?}", code);
+        }
+        for (code, modifiers) in [
+            (KeyCode::Enter, KeyModifiers::NONE),
+            (KeyCode::Up, KeyModifiers::NONE),
+            (KeyCode::Char('/'), KeyModifiers::NONE),
+            (KeyCode::Char('s'), KeyModifiers::NONE),
+            (KeyCode::Char('y'), KeyModifiers::NONE),
+        ] {
+            assert!(!changes_config(key(code, modifiers)), "{:?}", code);
+        }
+
+        let host = HostEntry { name: "web".into(), ..Default::default() };
+        let mut app = AppState::new(vec![host], Settings::default());
+        app.read_only = true;
+        assert_eq!(app.status_line().to_string(), "1 host · sort: file order · read-only mode");
+        app.save();
+        assert!(app.flash.is_none() && app.save_error.is_none());
+    }
+
+    #[test]
+    fn the_view_is_restored_by_host_name() {
+        let host = |name: &str| HostEntry { name: name.to_string(), env: Some(Env::Dev), ..Default::default() };
+        let mut app = AppState::new(vec![host("a"), host("b"), host("c")], Settings::default());
+        app.selected = 2;
+        app.sort = SortOrder::Recent;
+        app.env_filter = Some(Env::Dev);
+        let saved = app.state_text();
+        assert_eq!(saved, "selected = c\nsort = recent\nenv_filter = dev\n");
+
+        // c has moved to the front since.
+        let mut next = AppState::new(vec![host("c"), host("a"), host("b")], Settings::default());
+        next.restore_state(&saved);
+        assert_eq!((next.selected, next.sort, next.env_filter), (0, SortOrder::Recent, Some(Env::Dev)));
+
+        let mut gone = AppState::new(vec![host("a"), host("b")], Settings::default());
+        gone.selected = 1;
+        gone.restore_state("selected = c\ngroup_filter = web\n");
+        assert_eq!((gone.selected, gone.group_filter.as_deref()), (0, Some("web")));
+    }
+
+    #[test]
+    fn g_steps_through_each_group_then_back_to_all() {
+        let host = |name: &str, group: Option<&str>| HostEntry {
+            name: name.to_string(),
+            group: group.map(str::to_string),
+            ..Default::default()
+        };
+        let hosts = vec![host("a", Some("prod")), host("b", None), host("c", Some("dev")), host("d", Some("prod"))];
+        let mut app = AppState::new(hosts, Settings::default());
+        let mut seen = Vec::new();
+        for _ in 0..3 {
+            app.cycle_group_filter();
+            seen.push((app.group_filter.clone(), app.visible_indices()));
+        }
+        assert_eq!(
+            seen,
+            vec![(Some("prod".into()), vec![0, 3]), (Some("dev".into()), vec![2]), (None, vec![0, 3, 2, 1])]
+        );
+
+        // A group that's gone since (renamed, say) goes back to all.
+        app.group_filter = Some("old".into());
+        app.cycle_group_filter();
+        assert_eq!(app.group_filter, None);
+
+        let mut ungrouped = AppState::new(vec![host("x", None)], Settings::default());
+        ungrouped.cycle_group_filter();
+        assert_eq!(ungrouped.group_filter, None);
+        assert!(ungrouped.status_message.is_some());
+    }
+
+    #[test]
+    fn move_host_steps_past_the_visible_neighbour_and_stops_at_the_ends() {
+        let host = |name: &str, env| HostEntry { name: name.to_string(), env, ..Default::default() };
+        let hosts = vec![host("a", Some(Env::Prod)), host("b", None), host("c", Some(Env::Prod)), host("d", None)];
+        let mut app = AppState::new(hosts, Settings::default());
+        let names = |app: &AppState| app.hosts.iter().map(|h| h.name.as_str()).collect::<String>();
+
+        assert!(!app.move_host(-1));
+        assert!(app.move_host(1));
+        assert_eq!((names(&app), app.selected), ("bacd".to_string(), 1));
+        assert!(app.move_host(-1));
+        assert_eq!((names(&app), app.selected), ("abcd".to_string(), 0));
+
+        // With b filtered out, a goes just past c; b keeps its place.
+        app.env_filter = Some(Env::Prod);
+        assert!(app.move_host(1));
+        assert_eq!((names(&app), app.selected), ("bcad".to_string(), 2));
+        assert!(!app.move_host(1));
+        assert!(app.move_host(-1));
+        assert_eq!((names(&app), app.selected), ("bacd".to_string(), 1));
+        assert!(!app.move_host(-1));
+    }
+
+    #[test]
+    fn a_long_status_message_scrolls_and_stays_until_closed() {
+        use ratatui::backend::TestBackend;
+
+        let mut app = AppState::new(Vec::new(), Settings::default());
+        let mut terminal = Terminal::new(TestBackend::new(80, 20)).unwrap();
+        let mut draw = |app: &mut AppState| {
+            terminal.draw(|f| draw_ui(f, app, "config")).unwrap();
+            format!("{:?}", terminal.backend().buffer())
+        };
+
+        app.set_status("short".to_string());
+        draw(&mut app);
+        assert_eq!(app.status_scroll, None);
+
+        app.set_status((1..=40).map(|i| format!("line {:02}", i)).collect::<Vec<_>>().join("\n"));
+        let screen = draw(&mut app);
+        assert_eq!(app.status_scroll, Some(0));
+        assert!(screen.contains("lines 1-14 of 40"), "{}", screen);
+        assert_eq!(app.next_expiry(), None);
+
+        app.status_key(KeyCode::PageDown);
+        app.status_key(KeyCode::Down);
+        let screen = draw(&mut app);
+        assert!(screen.contains("line 12") && !screen.contains("line 11"), "{}", screen);
+        app.status_key(KeyCode::End);
+        let screen = draw(&mut app);
+        assert_eq!(app.status_scroll, Some(26));
+        assert!(screen.contains("line 40"));
+
+        app.status_key(KeyCode::Esc);
+        assert!(app.status_message.is_none());
+
+        // 48 columns inside the popup: the long line wraps onto a second one.
+        let long = format!("> icacls {}\\id_rsa /inheritance:r", "C:\\Users\\someone".repeat(3));
+        app.set_status(format!("✔ Permissions fixed\n{}", long));
+        let screen = draw(&mut app);
+        assert_eq!(app.status_scroll, None);
+        assert!(screen.contains("/inheritance:r"), "{}", screen);
+    }
+
+    #[test]
+    fn selection_stays_on_screen_when_the_window_shrinks() {
+        use ratatui::backend::TestBackend;
+        use ratatui::layout::Rect;
+
+        let hosts = (0..30)
+            .map(|i| HostEntry { name: format!("host{:02}", i), ..Default::default() })
+            .collect();
+        let mut app = AppState::new(hosts, Settings::default());
+        app.selected = 20;
+        let mut terminal = Terminal::new(TestBackend::new(60, 30)).unwrap();
+        terminal.draw(|f| draw_ui(f, &mut app, "config")).unwrap();
+        // 30 rows leave 23 for hosts, so row 20 fits without scrolling.
+        assert_eq!(app.scroll_offset, 0);
+
+        terminal.backend_mut().resize(60, 12);
+        terminal.resize(Rect::new(0, 0, 60, 12)).unwrap();
+        terminal.draw(|f| draw_ui(f, &mut app, "config")).unwrap();
+        // 5 visible rows now; the selection is the last of them.
+        assert_eq!(app.scroll_offset, 16);
+        let screen = format!("{:?}", terminal.backend().buffer());
+        assert!(screen.contains("host20"));
+        assert!(!screen.contains("host15"));
+
+        app.selected = 0;
+        terminal.draw(|f| draw_ui(f, &mut app, "config")).unwrap();
+        assert_eq!(app.scroll_offset, 0);
+    }
+
+    #[test]
+    fn vim_keys_navigate_and_move_the_actions_they_displace() {
+        use ratatui::backend::TestBackend;
+
+        let press = |code, modifiers| vim_key(KeyEvent::new(code, modifiers)).code;
+        assert_eq!(press(KeyCode::Char('j'), KeyModifiers::NONE), KeyCode::Down);
+        assert_eq!(press(KeyCode::Char('k'), KeyModifiers::NONE), KeyCode::Up);
+        assert_eq!(press(KeyCode::Char('k'), KeyModifiers::CONTROL), KeyCode::Char('k'));
+        assert_eq!(press(KeyCode::Char('g'), KeyModifiers::CONTROL), KeyCode::Char('g'));
+        assert_eq!(press(KeyCode::Char('n'), KeyModifiers::CONTROL), KeyCode::Char('G'));
+        assert_eq!(press(KeyCode::Char('e'), KeyModifiers::NONE), KeyCode::Char('e'));
+        assert!(Settings::parse("vim_keys = true\n").vim_keys);
+
+        let hosts = ["c", "a", "b"].map(|name| HostEntry { name: name.into(), ..Default::default() }).to_vec();
+        let mut app = AppState::new(hosts, Settings::default());
+        app.sort = SortOrder::Name;
+        app.select_end(true);
+        assert_eq!(app.hosts[app.selected].name, "c");
+        app.select_end(false);
+        assert_eq!(app.hosts[app.selected].name, "a");
+
+        app.settings.vim_keys = true;
+        app.show_help = true;
+        let mut terminal = Terminal::new(TestBackend::new(120, 80)).unwrap();
+        terminal.draw(|f| draw_ui(f, &mut app, "config")).unwrap();
+        let screen = format!("{:?}", terminal.backend().buffer());
+        for text in ["Up/Down, k/j", "Home/End, g/G      jump to the first", "Ctrl+K             fix key file"] {
+            assert!(screen.contains(text), "missing {:?}", text);
+        }
+    }
+
+    #[test]
+    fn vim_navigation_works_in_read_only_mode_and_on_match_blocks() {
+        let hosts = ["a", "b", "c"].map(|name| HostEntry { name: name.into(), ..Default::default() }).to_vec();
+        let mut app = AppState::new(hosts, Settings { vim_keys: true, ..Default::default() });
+        app.read_only = true;
+        app.hosts[1].match_block = true;
+        let press = |app: &mut AppState, code, modifiers| {
+            let key = KeyEvent::new(code, modifiers);
+            let list_key = vim_key(key);
+            if !app.intercept_list_key(key, list_key) && let KeyCode::Up | KeyCode::Down = list_key.code {
+                app.move_selection(if list_key.code == KeyCode::Up { -1 } else { 1 });
+            }
+            app.flash.take()
+        };
+        assert_eq!(press(&mut app, KeyCode::Char('G'), KeyModifiers::SHIFT), None);
+        assert_eq!(app.selected, 2);
+        assert_eq!(press(&mut app, KeyCode::Char('k'), KeyModifiers::NONE), None);
+        assert_eq!(app.selected, 1);
+        assert_eq!(press(&mut app, KeyCode::Char('g'), KeyModifiers::NONE), None);
+        assert_eq!(app.selected, 0);
+        assert_eq!(press(&mut app, KeyCode::Char('j'), KeyModifiers::NONE), None);
+        assert_eq!(app.selected, 1);
+        // On the Match block, with the config read-only: still a jump.
+        assert_eq!(press(&mut app, KeyCode::Char('G'), KeyModifiers::SHIFT), None);
+        assert_eq!(app.selected, 2);
+        app.selected = 1;
+        app.read_only = false;
+        assert_eq!(press(&mut app, KeyCode::Char('g'), KeyModifiers::NONE), None);
+        assert_eq!(app.selected, 0);
+        // The actions vim moved are still refused where they don't apply.
+        app.read_only = true;
+        let refused = press(&mut app, KeyCode::Char('k'), KeyModifiers::CONTROL).unwrap().0;
+        assert_eq!(refused, "Read-only mode: the config can't be changed.");
+    }
+
+    #[test]
+    fn home_and_end_jump_to_the_ends_and_scroll_there() {
+        use ratatui::backend::TestBackend;
+
+        let mut app = AppState::new(Vec::new(), Settings::default());
+        app.select_end(true);
+        app.select_end(false);
+        assert_eq!(app.selected, 0);
+
+        let hosts = (0..30).map(|i| HostEntry { name: format!("host{:02}", i), ..Default::default() }).collect();
+        let mut app = AppState::new(hosts, Settings::default());
+        let mut terminal = Terminal::new(TestBackend::new(60, 12)).unwrap();
+        app.select_end(true);
+        terminal.draw(|f| draw_ui(f, &mut app, "config")).unwrap();
+        assert_eq!(app.selected, 29);
+        assert!(format!("{:?}", terminal.backend().buffer()).contains("host29"));
+        app.select_end(false);
+        terminal.draw(|f| draw_ui(f, &mut app, "config")).unwrap();
+        assert_eq!((app.selected, app.scroll_offset), (0, 0));
+
+        // Filtered-out hosts are skipped.
+        app.hosts[3].env = Some(Env::Dev);
+        app.hosts[7].env = Some(Env::Dev);
+        app.env_filter = Some(Env::Dev);
+        app.select_end(true);
+        assert_eq!(app.selected, 7);
+        app.select_end(false);
+        assert_eq!(app.selected, 3);
+    }
+
+    #[test]
+    fn clicks_select_and_double_clicks_connect() {
+        use ratatui::backend::TestBackend;
+
+        let hosts = (0..30)
+            .map(|i| HostEntry { name: format!("host{:02}", i), ..Default::default() })
+            .collect();
+        let mut app = AppState::new(hosts, Settings::default());
+        app.selected = 20;
+        let mut terminal = Terminal::new(TestBackend::new(60, 12)).unwrap();
+        terminal.draw(|f| draw_ui(f, &mut app, "config")).unwrap();
+        let click = |column, row| MouseEvent {
+            kind: MouseEventKind::Down(MouseButton::Left),
+            column,
+            row,
+            modifiers: KeyModifiers::NONE,
+        };
+
+        // The list starts inside the margin and its border; rows are scrolled by 16.
+        app.handle_mouse(click(5, 2));
+        assert_eq!(app.selected, 16);
+        assert!(app.pending_launch.is_none());
+        app.handle_mouse(click(5, 3));
+        assert_eq!(app.selected, 17);
+        app.handle_mouse(click(5, 3));
+        assert_eq!(app.pending_launch.as_ref().unwrap().command, ["ssh", "-o", "ConnectTimeout=10", "host17"]);
+
+        // The border and the Controls bar are not hosts.
+        app.handle_mouse(click(5, 1));
+        app.handle_mouse(click(5, 10));
+        assert_eq!(app.selected, 17);
+
+        app.handle_mouse(MouseEvent { kind: MouseEventKind::ScrollDown, ..click(0, 0) });
+        assert_eq!(app.selected, 18);
+    }
+
+    #[test]
+    fn probe_reports_open_and_closed_ports() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host = HostEntry {
+            name: "local".into(),
+            hostname: Some("127.0.0.1".into()),
+            port: Some(port.to_string()),
+            ..Default::default()
+        };
+        let report = probe_report(&host, false);
+        assert!(report.starts_with(&format!("✔ local (127.0.0.1:{}) is reachable", port)), "{}", report);
+
+        drop(listener);
+        let report = probe_report(&host, false);
+        assert!(report.starts_with("❌ local"), "{}", report);
+        assert!(probe_report(&HostEntry { name: "*".into(), ..Default::default() }, false).contains("pattern"));
+    }
+
+    #[test]
+    fn probes_go_in_the_connect_log() {
+        let home = std::env::temp_dir().join(format!("putty-probelog-{}", std::process::id()));
+        fs::create_dir_all(home.join(".ssh")).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = HostEntry {
+            name: "local".into(),
+            hostname: Some("127.0.0.1".into()),
+            port: Some(listener.local_addr().unwrap().port().to_string()),
+            ..Default::default()
+        };
+        let log = with_env_var(home_var(), home.to_str().unwrap(), || {
+            // The audit trail's path is part of the contract; tools tail it.
+            assert_eq!(connect_log_path(), home.join(".ssh").join("putty_connect.log"));
+            probe_report(&host, true);
+            drop(listener);
+            probe_report(&host, true);
+            probe_report(&host, false);
+            fs::read_to_string(connect_log_path()).unwrap()
+        });
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let session = fs::read_to_string(home.join(".ssh").join("putty.log")).unwrap();
+        let readable: Vec<String> = lines.iter().map(|l| describe_log_line(l)).collect();
+        assert_eq!(session, format!("{}\n", readable.join("\n")));
+        let port = host.port.as_deref().unwrap();
+        assert!(lines[0].starts_with("{\"timestamp\":\""), "{}", lines[0]);
+        assert!(
+            lines[0].ends_with(&format!(
+                "\"action\":\"probe\",\"host\":\"local\",\"hostname\":\"127.0.0.1\",\"user\":null,\"port\":{},\"command\":[],\"exit_code\":0}}",
+                port
+            )),
+            "{}",
+            lines[0]
+        );
+        assert!(lines[1].ends_with("\"exit_code\":1}"), "{}", lines[1]);
+        assert!(describe_log_line(lines[0]).ends_with("probe    local (127.0.0.1)  reachable"));
+        assert!(describe_log_line(lines[1]).ends_with("unreachable"));
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn resolve_report_lists_each_address_once() {
+        let host = |hostname: &str| HostEntry { name: "web".into(), hostname: Some(hostname.into()), ..Default::default() };
+        assert_eq!(resolve_report(&host("127.0.0.1")), "✔ 127.0.0.1 is an IP address");
+        let report = resolve_report(&host("localhost, 10.0.0.1"));
+        assert!(report.starts_with("✔ localhost resolves to\n  "), "{}", report);
+        assert!(report.ends_with("\n\n✔ 10.0.0.1 is an IP address"), "{}", report);
+        let localhost = report.lines().filter(|l| l.starts_with("  ")).collect::<Vec<_>>();
+        assert_eq!(localhost.len(), localhost.iter().collect::<BTreeSet<_>>().len());
+        let report = resolve_report(&host("no such host"));
+        assert!(report.starts_with("❌ Could not resolve no such host ("), "{}", report);
+        assert!(resolve_report(&HostEntry { name: "*".into(), ..Default::default() }).contains("pattern"));
+    }
+
+    #[test]
+    fn prober_reports_each_host_in_the_background() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let host = |name: &str, port: &str| HostEntry {
+            name: name.into(),
+            hostname: Some("127.0.0.1".into()),
+            port: Some(port.into()),
+            ..Default::default()
+        };
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port().to_string();
+        let hosts = vec![host("up", &port), host("down", &closed), HostEntry { name: "*".into(), ..Default::default() }];
+
+        let prober = Prober::start(Duration::from_secs(60), &hosts);
+        let mut results: BTreeMap<String, bool> = BTreeMap::new();
+        while results.len() < 2 {
+            let (name, up) = prober.results.recv_timeout(Duration::from_secs(5)).unwrap();
+            results.insert(name, up);
+        }
+        assert_eq!(results, BTreeMap::from([("up".to_string(), true), ("down".to_string(), false)]));
+        // Dropping stops the thread without waiting out the interval.
+        let started = Instant::now();
+        drop(prober);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(Settings::parse("probe_interval = 30\n").probe_interval, Some(Duration::from_secs(30)));
+        assert_eq!(Settings::parse("probe_interval = 0\n").probe_interval, None);
+    }
+
+    #[test]
+    fn detail_pane_shows_on_wide_terminals_only() {
+        use ratatui::backend::TestBackend;
+
+        let host = HostEntry {
+            name: "web".into(),
+            user: Some("deploy".into()),
+            password: Some("hunter2".into()),
+            raw_lines: vec!["    ProxyJump bastion".into()],
+            ..Default::default()
+        };
+        let mut app = AppState::new(vec![host], Settings::default());
+        let mut terminal = Terminal::new(TestBackend::new(120, 20)).unwrap();
+        terminal.draw(|f| draw_ui(f, &mut app, "config")).unwrap();
+        let screen = format!("{:?}", terminal.backend().buffer());
+        for shown in ["Details", "User: deploy", "SshOption: ProxyJump bastion (in config)", "Password: set", "Last connected: never"] {
+            assert!(screen.contains(shown), "missing {:?}", shown);
+        }
+        assert!(!screen.contains("hunter2"));
+
+        let mut terminal = Terminal::new(TestBackend::new(60, 20)).unwrap();
+        terminal.draw(|f| draw_ui(f, &mut app, "config")).unwrap();
+        assert!(!format!("{:?}", terminal.backend().buffer()).contains("Details"));
+    }
+
+    #[test]
+    fn hosts_are_coloured_by_env_then_group_then_tag_with_a_legend() {
+        use ratatui::backend::TestBackend;
+
+        let host = |name: &str, env: Option<Env>, group: Option<&str>, tag: Option<&str>| HostEntry {
+            name: name.into(),
+            env,
+            group: group.map(Into::into),
+            meta: HostMeta { tags: tag.into_iter().map(Into::into).collect(), ..Default::default() },
+            ..Default::default()
+        };
+        let hosts = vec![
+            host("web", Some(Env::Dev), Some("prod"), None),
+            host("db", None, Some("Prod"), None),
+            host("ci", None, Some("tools"), Some("lab")),
+            host("misc", None, None, None),
+        ];
+        let settings = Settings::parse("color.lab = magenta\n");
+        let mut app = AppState::new(hosts, settings);
+        let colors: Vec<_> = app.hosts.iter().map(|h| app.host_color(h)).collect();
+        assert_eq!(
+            colors,
+            [
+                Some((Color::Green, Some("dev".to_string()))),
+                Some((Color::Red, Some("Prod".to_string()))),
+                Some((Color::Magenta, Some("lab".to_string()))),
+                None,
+            ]
+        );
+
+        let mut terminal = Terminal::new(TestBackend::new(80, 20)).unwrap();
+        terminal.draw(|f| draw_ui(f, &mut app, "config")).unwrap();
+        let screen = format!("{:?}", terminal.backend().buffer());
+        assert!(screen.contains("● dev  ● Prod  ● lab"), "{}", screen);
+    }
+
+    #[test]
+    fn the_theme_toggles_and_is_remembered() {
+        let home = std::env::temp_dir().join(format!("putty-theme-{}", std::process::id()));
+        fs::create_dir_all(home.join(".ssh")).unwrap();
+        fs::write(home.join(".ssh/putty_settings"), "# mine\nkeyring = true\ntheme = dark\n").unwrap();
+        let mut app = AppState::new(Vec::new(), Settings::parse("theme = dark"));
+        assert_eq!(app.theme, DARK_THEME);
+
+        with_env_var(home_var(), home.to_str().unwrap(), || {
+            app.toggle_theme();
+            assert_eq!(app.theme, LIGHT_THEME);
+            let saved = fs::read_to_string(settings_path()).unwrap();
+            assert_eq!(saved, "# mine\nkeyring = true\ntheme = light\n");
+            assert_eq!(Settings::load().theme, Some(LIGHT_THEME));
+        });
+        fs::remove_dir_all(&home).unwrap();
+
+        let missing = std::env::temp_dir().join(format!("putty-theme-new-{}", std::process::id()));
+        save_setting(&missing, "theme", "dark").unwrap();
+        assert_eq!(fs::read_to_string(&missing).unwrap(), "theme = dark\n");
+        fs::remove_file(&missing).unwrap();
+    }
+
+    #[test]
+    fn password_is_masked_until_revealed() {
+        use ratatui::backend::TestBackend;
+
+        let host = HostEntry {
+            name: "web".to_string(),
+            password: Some("hunter2".to_string()),
+            ..Default::default()
+        };
+        let mut app = AppState::new(vec![host.clone()], Settings::default());
+        let mut terminal = Terminal::new(TestBackend::new(60, 30)).unwrap();
+        terminal.draw(|f| draw_ui(f, &mut app, "config")).unwrap();
+        assert!(!format!("{:?}", terminal.backend().buffer()).contains("hunter2"));
+
+        app.edit_mode = Some(EditState::new(host, Some(0)));
+        terminal.draw(|f| draw_ui(f, &mut app, "config")).unwrap();
+        let screen = format!("{:?}", terminal.backend().buffer());
+        assert!(!screen.contains("hunter2"));
+        assert!(screen.contains("Password: *******"));
+
+        app.edit_mode.as_mut().unwrap().reveal_password = true;
+        terminal.draw(|f| draw_ui(f, &mut app, "config")).unwrap();
+        assert!(format!("{:?}", terminal.backend().buffer()).contains("Password: hunter2"));
+    }
+
+    #[test]
+    fn last_connected_round_trips_through_json() {
+        let times = BTreeMap::from([("web".to_string(), 1_700_000_000), ("odd \"name\"".to_string(), 5)]);
+        let path = std::env::temp_dir().join(format!("putty-meta-{}", std::process::id()));
+        fs::create_dir_all(path.join(".ssh")).unwrap();
+        let text = with_env_var(home_var(), path.to_str().unwrap(), || {
+            save_last_connected(&times).unwrap();
+            fs::read_to_string(meta_path()).unwrap()
+        });
+        fs::remove_dir_all(&path).unwrap();
+        assert_eq!(parse_last_connected(&text).unwrap(), times);
+        assert_eq!(parse_last_connected("{}").unwrap(), BTreeMap::new());
+        assert!(parse_last_connected("{\"web\": \"soon\"}").is_err());
+        assert!(parse_last_connected("{\"web\": 1} x").is_err());
+    }
+
+    #[test]
+    fn ages_are_rounded_down_to_the_largest_unit() {
+        assert_eq!(format_ago(59), "just now");
+        assert_eq!(format_ago(5 * 60 + 59), "5m ago");
+        assert_eq!(format_ago(2 * 3_600 + 1), "2h ago");
+        assert_eq!(format_ago(3 * 86_400), "3d ago");
+    }
+
+    #[test]
+    fn sorting_reorders_the_view_but_not_the_hosts() {
+        let host = |name: &str| HostEntry { name: name.to_string(), ..Default::default() };
+        let mut app = AppState::new(vec![host("web"), host("Db"), host("cache")], Settings::default());
+        app.last_connected = BTreeMap::from([("cache".to_string(), 10), ("web".to_string(), 20)]);
+        app.selected = 1;
+
+        app.sort = SortOrder::Name;
+        assert_eq!(app.visible_indices(), vec![2, 1, 0]);
+        app.sort = SortOrder::Recent;
+        assert_eq!(app.visible_indices(), vec![0, 2, 1]);
+        assert_eq!(app.selected_host().unwrap().name, "Db");
+        assert_eq!(app.hosts[0].name, "web");
+    }
+
+    #[test]
+    fn quitting_asks_only_when_something_is_unsaved() {
+        let host = HostEntry { name: "web".to_string(), ..Default::default() };
+        let mut app = AppState::new(vec![host], Settings::default());
+        app.request_quit();
+        assert!(app.quit && app.confirm.is_none());
+
+        let mut app = edited_app();
+        app.request_quit();
+        assert!(!app.quit);
+        app.answer_confirm(false);
+        assert!(!app.quit && app.edit_mode.is_some());
+
+        app.edit_mode = None;
+        app.dirty = true;
+        app.request_quit();
+        app.answer_confirm(true);
+        assert!(app.quit);
+    }
+
+    #[test]
+    fn ctrl_c_quits_and_a_second_one_skips_the_question() {
+        let mut app = edited_app();
+        app.interrupt();
+        assert!(!app.quit);
+        assert_eq!(app.confirm.as_ref().map(|c| &c.on_yes), Some(&Action::Quit));
+        app.interrupt();
+        assert!(app.quit);
+
+        let mut app = AppState::new(Vec::new(), Settings::default());
+        app.interrupt();
+        assert!(app.quit);
+    }
+
+    #[test]
+    fn the_connect_template_fills_in_the_host() {
+        let host = HostEntry {
+            name: "web".to_string(),
+            user: Some("deploy".to_string()),
+            identity_files: vec![r"C:\Users\Jo Smith\.ssh\id".to_string()],
+            ..Default::default()
+        };
+        let settings = Settings::parse("connect_command = kitty +kitten ssh -p {port} -i {identity} {user}@{host}\n");
+        assert_eq!(
+            ssh_command(&host, &settings, &BTreeSet::new()),
+            ["kitty", "+kitten", "ssh", "-p", "22", "-i", r"C:\Users\Jo Smith\.ssh\id", "deploy@web"]
+        );
+        let settings = Settings::parse("connect_command = wrap {cmd} {user} {host}\n");
+        let anonymous = HostEntry { user: None, ..host };
+        assert_eq!(ssh_command(&anonymous, &settings, &BTreeSet::new()), ["wrap", "ssh", "-o", "ConnectTimeout=10", "web"]);
+        assert_eq!(unset_template_fields(&anonymous, &settings), ["User"]);
+        with_env_var(CONNECT_ENV, "echo {host}", || {
+            assert_eq!(ssh_command(&anonymous, &settings, &BTreeSet::new()), ["echo", "web"]);
+        });
+    }
+
+    #[test]
+    fn a_malformed_connect_template_is_reported() {
+        let check = |template: &str| Settings::parse(&format!("connect_command = {}", template)).connect_template();
+        assert_eq!(check("ssh {host}"), Ok(Some(vec!["ssh".to_string(), "{host}".to_string()])));
+        assert_eq!(
+            check("ssh {hots}"),
+            Err("unknown placeholder {hots} (known: {cmd}, {host}, {user}, {port}, {identity})".to_string())
+        );
+        assert_eq!(check("ssh {host"), Err("unclosed { in {host".to_string()));
+        assert_eq!(check("ssh host}"), Err("stray } in host}".to_string()));
+        assert!(check("ssh '{host}").is_err());
+        assert_eq!(check("echo {{literal}}"), Ok(Some(vec!["echo".to_string(), "{{literal}}".to_string()])));
+    }
+
+    #[test]
+    fn ssh_options_reach_every_way_of_launching() {
+        let hosts = HostEntry::parse_ssh_config("Host jump\n\nHost web\n    # SshOption ServerAliveInterval=30\n");
+        let mut app = AppState::new(hosts, Settings::parse("connect_timeout = 0\n"));
+        app.selected = 1;
+        let options = app.launch_options(1);
+        assert_eq!(options, ["-o", "ServerAliveInterval=30"]);
+        assert_eq!(remote_command(&app.hosts[1], "uptime", &options), ["ssh", "-o", "ServerAliveInterval=30", "-t", "web", "uptime"]);
+        assert_eq!(mosh_command(&app.hosts[1], &options), ["mosh", "--ssh=ssh -o ServerAliveInterval=30", "web"]);
+        assert_eq!(
+            chain_command(&[&app.hosts[0], &app.hosts[1]], &options).unwrap(),
+            ["ssh", "-o", "ServerAliveInterval=30", "-J", "jump", "web"]
+        );
+        app.submit_prompt(PromptKind::SshArgs, "-v".to_string()).unwrap();
+        app.answer_confirm(true);
+        assert_eq!(app.pending_launch.unwrap().command, ["ssh", "-v", "-o", "ServerAliveInterval=30", "web"]);
+    }
+
+    #[test]
+    fn ssh_options_are_passed_on_connect_and_round_trip() {
+        let text = "Host web\n    # SshOption ServerAliveInterval=30\n    # SshOption ConnectTimeout=3\n";
+        let hosts = HostEntry::parse_ssh_config(text);
+        assert_eq!(hosts[0].ssh_options, ["ServerAliveInterval=30", "ConnectTimeout=3"]);
+        assert_eq!(serialize_hosts("", &hosts, true), format!("{}\n", text));
+        assert!(!serialize_hosts("", &hosts, false).contains("SshOption"));
+        assert!(serialize_companion(&hosts).contains("  ssh_options:\n    - ServerAliveInterval=30\n"));
+        let mut with_raw = hosts[0].clone();
+        with_raw.raw_lines.push("    UserKnownHostsFile /dev/null".to_string());
+        let details: Vec<String> =
+            host_details(&AppState::new(vec![with_raw], Settings::default()), 0).iter().map(|l| l.to_string()).collect();
+        let options: Vec<&str> = details.iter().filter_map(|l| l.strip_prefix("SshOption: ")).collect();
+        assert_eq!(options, ["UserKnownHostsFile /dev/null (in config)", "-o ServerAliveInterval=30", "-o ConnectTimeout=3"]);
+        // The host's own ConnectTimeout option replaces the default one.
+        assert_eq!(
+            ssh_command(&hosts[0], &Settings::default(), &BTreeSet::new()),
+            ["ssh", "-o", "ServerAliveInterval=30", "-o", "ConnectTimeout=3", "web"]
+        );
+
+        let mut edit = EditState::new(hosts[0].clone(), Some(0));
+        select(&mut edit, "SshOption");
+        edit.add_identity_file();
+        *edit.current_value_mut().unwrap() = "Compression".to_string();
+        assert_eq!(edit.finished_host().ssh_options.len(), 3);
+        let mut app = AppState::new(hosts, Settings::default());
+        app.edit_mode = Some(edit);
+        app.finish_edit();
+        assert_eq!(
+            app.edit_mode.unwrap().warning.as_deref(),
+            Some("SshOption Compression needs a value, as in ServerAliveInterval=30")
+        );
+    }
+
+    #[test]
+    fn ssh_connects_by_alias() {
+        let host = HostEntry {
+            name: "web".to_string(),
+            hostname: Some("10.0.0.1".to_string()),
+            port: Some("2222".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ssh_command(&host, &Settings::default(), &BTreeSet::new()), vec!["ssh", "-o", "ConnectTimeout=10", "web"]);
+    }
+
+    #[test]
+    fn connect_options_yield_to_the_hosts_own_directives() {
+        let mut host = HostEntry { name: "web".to_string(), ..Default::default() };
+        let settings = Settings::parse("connect_timeout = 5\nconnection_attempts = 3\n");
+        assert_eq!(
+            ssh_command(&host, &settings, &BTreeSet::new()),
+            ["ssh", "-o", "ConnectTimeout=5", "-o", "ConnectionAttempts=3", "web"]
+        );
+        host.connect_timeout = Some("30".to_string());
+        host.raw_lines.push("    ConnectionAttempts 1".to_string());
+        assert_eq!(ssh_command(&host, &settings, &BTreeSet::new()), ["ssh", "web"]);
+        let host = HostEntry { name: "web".to_string(), ..Default::default() };
+        assert_eq!(ssh_command(&host, &Settings::parse("connect_timeout = 0\n"), &BTreeSet::new()), ["ssh", "web"]);
+
+        let text = "Host web\n    ConnectTimeout 30\n";
+        let hosts = HostEntry::parse_ssh_config(text);
+        assert_eq!(hosts[0].connect_timeout.as_deref(), Some("30"));
+        assert_eq!(serialize_hosts("", &hosts, true), format!("{}\n", text));
+        let mut app = AppState::new(hosts, Settings::default());
+        app.edit_mode = Some(EditState::new(app.hosts[0].clone(), Some(0)));
+        let edit = app.edit_mode.as_mut().unwrap();
+        select(edit, "ConnectTimeout");
+        *edit.current_value_mut().unwrap() = "soon".to_string();
+        app.finish_edit();
+        assert_eq!(app.edit_mode.unwrap().warning.as_deref(), Some("ConnectTimeout is a whole number of seconds"));
+    }
+
+    #[test]
+    fn connect_options_yield_to_the_preamble_and_matching_pattern_blocks() {
+        let hosts = HostEntry::parse_ssh_config("Host *\n  ConnectTimeout 30\n\nHost web\n    HostName 10.0.0.1\n\nHost db-*\n    ConnectionAttempts 4\n");
+        let settings = Settings::parse("connection_attempts = 3\n");
+        let web = hosts.iter().position(|h| h.name == "web").unwrap();
+        let inherited = inherited_directives(&[""], &hosts, &hosts[web]);
+        assert_eq!(ssh_command(&hosts[web], &settings, &inherited), ["ssh", "-o", "ConnectionAttempts=3", "web"]);
+        let inherited = inherited_directives(&["ConnectionAttempts 2\n"], &hosts, &hosts[web]);
+        assert_eq!(ssh_command(&hosts[web], &settings, &inherited), ["ssh", "web"]);
+
+        let app = AppState::new(hosts, settings);
+        assert!(app.inherited_directives(web).contains("connecttimeout"));
+        assert!(!app.inherited_directives(web).contains("connectionattempts"));
+    }
+
+    #[test]
+    fn several_patterns_connect_by_the_first_concrete_one() {
+        let text = "Host *.example.com web1 web2\n    HostName 10.0.0.1\n";
+        let hosts = HostEntry::parse_ssh_config(text);
+        assert_eq!(hosts[0].patterns(), ["*.example.com", "web1", "web2"]);
+        assert_eq!(ssh_command(&hosts[0], &Settings::default(), &BTreeSet::new()), vec!["ssh", "-o", "ConnectTimeout=10", "web1"]);
+        assert_eq!(serialize_hosts("", &hosts, true), format!("{}\n", text));
+        let only_patterns = HostEntry { name: "*.example.com !bastion".to_string(), ..Default::default() };
+        assert!(!only_patterns.is_connectable());
+        assert_eq!(probe_targets(&[only_patterns]), Vec::new());
+    }
+
+    #[test]
+    fn a_second_pattern_can_not_reuse_another_hosts_name() {
+        let host = |name: &str| HostEntry { name: name.to_string(), ..Default::default() };
+        let mut app = AppState::new(vec![host("web1 web2"), host("db")], Settings::default());
+        let mut edit = EditState::new(app.hosts[0].clone(), Some(0));
+        select(&mut edit, "Host");
+        *edit.current_value_mut().unwrap() = "web1 db".to_string();
+        app.edit_mode = Some(edit);
+        app.finish_edit();
+        assert_eq!(app.edit_mode.unwrap().warning.as_deref(), Some("There is already a host named db"));
+    }
+
+    #[test]
+    fn a_missing_program_is_reported_not_fatal() {
+        let e = std::process::Command::new("putty-test-no-such-program").status().unwrap_err();
+        assert_eq!(
+            launch_error("putty-test-no-such-program", &e),
+            "❌ Couldn't run putty-test-no-such-program: it isn't installed or isn't on PATH"
+        );
+    }
+
+    #[test]
+    fn mosh_passes_the_port_to_its_ssh() {
+        let mut host = HostEntry { name: "web".to_string(), ..Default::default() };
+        assert_eq!(mosh_command(&host, &[]), vec!["mosh", "web"]);
+        host.port = Some("2222".to_string());
+        assert_eq!(mosh_command(&host, &[]), vec!["mosh", "--ssh=ssh -p 2222", "web"]);
+    }
+
+    #[test]
+    fn port_field_only_takes_valid_port_numbers() {
+        let mut edit = EditState::new(HostEntry { name: "web".to_string(), ..Default::default() }, None);
+        select(&mut edit, "Port");
+        for c in "6a5535".chars() {
+            edit.insert_char(c);
+        }
+        assert_eq!(edit.finished_host().port.as_deref(), Some("65535"));
+        assert_eq!(edit.warning, None);
+        edit.insert_char('0');
+        assert_eq!(edit.finished_host().port.as_deref(), Some("65535"));
+        assert!(edit.warning.is_some());
+
+        let mut edit = EditState::new(HostEntry { name: "web".to_string(), ..Default::default() }, None);
+        select(&mut edit, "Port");
+        edit.insert_char('x');
+        assert_eq!(edit.warning.as_deref(), Some("Port must be a number"));
+        edit.insert_char('2');
+        edit.current_value_mut().unwrap().pop();
+        assert_eq!(edit.finished_host().port, None);
+    }
+
+    /// Highlights the first row for `directive` in the form.
+    #[test]
+    fn releases_and_repeats_of_one_shot_keys_are_ignored() {
+        let event = |code, kind| KeyEvent::new_with_kind(code, KeyModifiers::NONE, kind);
+        for code in [KeyCode::Enter, KeyCode::Char('c'), KeyCode::Up, KeyCode::Backspace] {
+            assert!(key_wanted(event(code, KeyEventKind::Press)), "{:?}", code);
+            assert!(!key_wanted(event(code, KeyEventKind::Release)), "{:?}", code);
+        }
+        for code in [KeyCode::Enter, KeyCode::Char('c')] {
+            assert!(!key_wanted(event(code, KeyEventKind::Repeat)), "{:?}", code);
+        }
+        for code in [KeyCode::Up, KeyCode::Down, KeyCode::Left, KeyCode::Right, KeyCode::Backspace] {
+            assert!(key_wanted(event(code, KeyEventKind::Repeat)), "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn tab_completes_users_and_hostnames_from_other_hosts() {
+        let host = |name: &str, hostname: &str, user: &str| HostEntry {
+            name: name.into(),
+            hostname: Some(hostname.into()),
+            user: Some(user.into()),
+            ..Default::default()
+        };
+        let hosts = vec![
+            host("web1", "web1.example.com", "deploy"),
+            host("web2", "web2.example.com", "deploy"),
+            host("db", "db.internal", "dba"),
+        ];
+        let typed = |directive: &str, value: &str| {
+            let mut edit = EditState::new(HostEntry::default(), None);
+            select(&mut edit, directive);
+            edit.paste(value);
+            edit_completion(&hosts, &edit)
+        };
+        // Both web hosts share one user, offered once.
+        assert_eq!(completion_candidates(&hosts, "User"), BTreeSet::from(["dba".into(), "deploy".into()]));
+        assert_eq!(typed("User", "dep").as_deref(), Some("deploy"));
+        assert_eq!(typed("User", "deploy"), None);
+        assert_eq!(typed("User", "x"), None);
+        assert_eq!(typed("User", ""), None);
+        assert_eq!(typed("HostName", "db.").as_deref(), Some("db.internal"));
+        assert_eq!(typed("HostName", "web3.ex").as_deref(), Some("web3.example.com"));
+        assert_eq!(typed("HostName", "web3.nowhere"), None);
+        assert_eq!(typed("Port", "2"), None);
+
+        let mut app = AppState::new(hosts, Settings::default());
+        app.edit_mode = Some(EditState::new(HostEntry::default(), None));
+        let edit = app.edit_mode.as_mut().unwrap();
+        select(edit, "User");
+        edit.paste("db");
+        app.edit_tab();
+        let edit = app.edit_mode.as_ref().unwrap();
+        assert_eq!((edit.current_row().value.as_str(), edit.cursor()), ("dba", 3));
+        app.edit_tab();
+        assert_ne!(app.edit_mode.as_ref().unwrap().current_row().directive, "User");
+    }
+
+    #[test]
+    fn typing_and_deleting_happen_at_the_cursor() {
+        let mut edit = EditState::new(HostEntry::default(), None);
+        select(&mut edit, "User");
+        for c in "rot".chars() {
+            edit.insert_char(c);
+        }
+        edit.move_cursor(-1);
+        edit.insert_char('o');
+        assert_eq!(edit.current_row().value, "root");
+        edit.cursor = 0;
+        edit.delete_char(false);
+        edit.delete_char(true);
+        assert_eq!(edit.current_row().value, "oot");
+        edit.move_cursor(10);
+        edit.delete_char(true);
+        assert_eq!(edit.current_row().value, "oo");
+        edit.move_field(1);
+        assert_eq!(edit.cursor(), edit.current_row().value.chars().count());
+    }
+
+    #[test]
+    fn the_hostname_prompt_edits_at_the_cursor() {
+        let home = std::env::temp_dir().join(format!("putty-prompt-{}", std::process::id()));
+        fs::create_dir_all(home.join(".ssh")).unwrap();
+        let host = HostEntry { name: "web".into(), hostname: Some("10.0.0.5".into()), ..Default::default() };
+        let mut app = AppState::new(vec![host], Settings::default());
+        app.open_prompt(PromptKind::Hostname, "10.0.0.5".into());
+        let prompt = app.prompt.as_mut().unwrap();
+        prompt.move_cursor(-2);
+        prompt.delete_char(true);
+        prompt.insert_char('9');
+        assert_eq!((prompt.input.as_str(), prompt.cursor()), ("10.0.9.5", 6));
+        prompt.cursor = 0;
+        prompt.paste("1\n");
+        prompt.delete_char(false);
+        prompt.cursor = usize::MAX;
+        prompt.delete_char(false);
+        assert_eq!(prompt.input, "10.0.9.5");
+
+        with_env_var(home_var(), home.to_str().unwrap(), || {
+            let err = app.submit_prompt(PromptKind::Hostname, "10.0. 9.5".into()).unwrap_err();
+            assert_eq!(err, "HostName can't contain spaces; ssh would reject it");
+            let input = app.prompt.as_ref().unwrap().input.clone();
+            app.submit_prompt(PromptKind::Hostname, input).unwrap();
+        });
+        assert_eq!(app.hosts[0].hostname.as_deref(), Some("10.0.9.5"));
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn pasting_inserts_at_the_cursor_without_line_breaks() {
+        let mut edit = EditState::new(HostEntry::default(), None);
+        select(&mut edit, "IdentityFile");
+        edit.paste("~/.ssh/.pem");
+        edit.move_cursor(-4);
+        edit.paste("id_work\r\n");
+        assert_eq!(edit.current_row().value, "~/.ssh/id_work.pem");
+        select(&mut edit, "HostName");
+        edit.paste("10.0.0.1\n");
+        assert_eq!(edit.current_row().value, "10.0.0.1");
+        select(&mut edit, "Host");
+        edit.cursor = usize::MAX;
+        edit.paste("-db\n");
+        assert!(edit.current_row().value.ends_with("-db"));
+    }
+
+    fn select(edit: &mut EditState, directive: &str) {
+        edit.field_index = edit.rows.iter().position(|r| r.is(directive)).unwrap();
+    }
+
+    fn edited_app() -> AppState {
+        let host = HostEntry {
+            name: "web".to_string(),
+            ..Default::default()
+        };
+        let mut app = AppState::new(vec![host.clone()], Settings::default());
+        let mut edit = EditState::new(host, Some(0));
+        select(&mut edit, "User");
+        edit.current_value_mut().unwrap().push_str("root");
+        app.edit_mode = Some(edit);
+        app
+    }
+
+    #[test]
+    fn confirm_cancel_leaves_state_untouched() {
+        let mut app = edited_app();
+        app.ask("Discard changes to web?".to_string(), Action::DiscardEdit);
+        app.answer_confirm(false);
+        assert!(app.confirm.is_none());
+        assert!(app.edit_mode.is_some());
+    }
+
+    #[test]
+    fn confirm_yes_runs_the_stored_action() {
+        let mut app = edited_app();
+        app.ask("Discard changes to web?".to_string(), Action::DiscardEdit);
+        app.answer_confirm(true);
+        assert!(app.confirm.is_none());
+        assert!(app.edit_mode.is_none());
+        assert_eq!(app.hosts[0].user, None);
+    }
+
+    #[test]
+    fn companion_round_trips_metadata() {
+        let mut host = HostEntry {
+            name: "db".to_string(),
+            env: Some(Env::Staging),
+            web_ui: vec!["https://%h:8443/#/login".to_string()],
+            ..Default::default()
+        };
+        host.meta = HostMeta {
+            notes: Some("replica: \"eu\" #2".to_string()),
+            tags: vec!["db".to_string(), "eu west".to_string()],
+            color: Some("magenta".to_string()),
+        };
+        let yaml = serialize_companion(std::slice::from_ref(&host));
+
+        let mut loaded = vec![HostEntry {
+            name: "db".to_string(),
+            ..Default::default()
+        }];
+        merge_companion(&mut loaded, &parse_companion(&yaml).unwrap());
+        assert_eq!(loaded[0], host);
+    }
+
+    #[test]
+    fn corrupt_companion_is_reported() {
+        assert!(parse_companion("db:\n  - orphan\n").is_err());
+        assert!(parse_companion("  env: prod\n").is_err());
+    }
+
+    /// A config using every line `parse_ssh_config` models, across hosts.
+    const FULL_CONFIG: &str = "\
+Host web
+    HostName 10.0.0.1
+    User deploy
+    Port 2222
+    IdentityFile ~/.ssh/web
+    IdentityFile ~/.ssh/fallback
+    ProxyJump bastion1,bastion2
+    ForwardAgent yes
+    # Password s3cret
+    # Env prod
+    # WebUI https://%h:8443
+    # Group: Frontend
+    ServerAliveInterval 30
+
+Host db
+    HostName db.internal
+    # Password (in keyring)
+
+Host *.example.com
+    User ops
+";
+
+    #[test]
+    fn every_modelled_line_is_parsed() {
+        let hosts = HostEntry::parse_ssh_config(FULL_CONFIG);
+        let names: Vec<&str> = hosts.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(names, ["web", "db", "*.example.com"]);
+        assert_eq!(
+            hosts[0],
+            HostEntry {
+                name: "web".to_string(),
+                hostname: Some("10.0.0.1".to_string()),
+                user: Some("deploy".to_string()),
+                port: Some("2222".to_string()),
+                identity_files: vec!["~/.ssh/web".to_string(), "~/.ssh/fallback".to_string()],
+                proxy_jump: Some("bastion1,bastion2".to_string()),
+                forward_agent: Some(true),
+                password: Some("s3cret".to_string()),
+                env: Some(Env::Prod),
+                group: Some("Frontend".to_string()),
+                web_ui: vec!["https://%h:8443".to_string()],
+                raw_lines: vec!["    ServerAliveInterval 30".to_string()],
+                ..Default::default()
+            }
+        );
+        assert_eq!((hosts[1].password.as_deref(), hosts[1].password_in_keyring), (None, true));
+        assert_eq!(hosts[2].user.as_deref(), Some("ops"));
+        assert!(hosts[2].is_pattern());
+    }
+
+    #[test]
+    fn parse_write_parse_keeps_every_field() {
+        let hosts = HostEntry::parse_ssh_config(FULL_CONFIG);
+        let written = serialize_hosts("", &hosts, true);
+        assert_eq!(HostEntry::parse_ssh_config(&written), hosts);
+        // Writing again changes nothing, so saves don't drift.
+        assert_eq!(serialize_hosts("", &HostEntry::parse_ssh_config(&written), true), written);
+    }
+
+    #[test]
+    fn without_tool_comments_only_ssh_lines_are_written() {
+        let hosts = HostEntry::parse_ssh_config(FULL_CONFIG);
+        let written = serialize_hosts("", &hosts, false);
+        assert!(!written.contains("# Env") && !written.contains("# WebUI") && !written.contains("# Group"));
+        assert!(written.contains("    # Password s3cret\n"));
+        let read = HostEntry::parse_ssh_config(&written);
+        assert_eq!((read[0].env, read[0].group.as_deref()), (None, None));
+        assert_eq!(read[0].identity_files, hosts[0].identity_files);
+    }
+
+    #[test]
+    fn identity_files_with_spaces_are_quoted() {
+        let config = "Host win\n    IdentityFile \"C:\\Users\\Me\\My Keys\\id_rsa\"\n    IdentityFile ~/.ssh/plain\n";
+        let hosts = HostEntry::parse_ssh_config(config);
+        assert_eq!(hosts[0].identity_files, [r"C:\Users\Me\My Keys\id_rsa", "~/.ssh/plain"]);
+        let written = serialize_hosts("", &hosts, true);
+        assert!(written.contains("    IdentityFile \"C:\\Users\\Me\\My Keys\\id_rsa\"\n"));
+        assert!(written.contains("    IdentityFile ~/.ssh/plain\n"));
+        assert_eq!(HostEntry::parse_ssh_config(&written), hosts);
+        // A quoted path without spaces loads the same and is written bare.
+        let hosts = HostEntry::parse_ssh_config("Host a\n    IdentityFile \"~/.ssh/id\"\n");
+        assert_eq!(hosts[0].identity_files, ["~/.ssh/id"]);
+        assert!(serialize_hosts("", &hosts, true).contains("IdentityFile ~/.ssh/id\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_permissions_are_only_changed_after_confirming() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let key = std::env::temp_dir().join(format!("putty-secure-{}", std::process::id()));
+        fs::write(&key, "secret").unwrap();
+        fs::set_permissions(&key, fs::Permissions::from_mode(0o644)).unwrap();
+        let host = HostEntry {
+            name: "web".into(),
+            identity_files: vec![key.display().to_string()],
+            ..Default::default()
+        };
+        let mut app = AppState::new(vec![host], Settings::default());
+        let mode = || fs::metadata(&key).unwrap().permissions().mode() & 0o777;
+
+        app.ask_secure_keys();
+        let prompt = &app.confirm.as_ref().unwrap().prompt;
+        assert!(prompt.ends_with(&format!("\n\nchmod 600 {}", key.display())), "{}", prompt);
+        app.answer_confirm(false);
+        assert_eq!(mode(), 0o644);
+
+        app.ask_secure_keys();
+        app.answer_confirm(true);
+        assert_eq!(mode(), 0o600);
+        assert!(app.status_message.unwrap().0.starts_with("✔ Permissions fixed"));
+        fs::remove_file(&key).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_generated_key_becomes_the_hosts_first_identity_file() {
+        use std::os::unix::process::ExitStatusExt;
+        let home = std::env::temp_dir().join(format!("putty-keygen-{}", std::process::id()));
+        fs::create_dir_all(home.join(".ssh")).unwrap();
+        fs::write(home.join(".ssh/taken"), "old key").unwrap();
+        let host = HostEntry {
+            name: "web".into(),
+            identity_files: vec!["~/.ssh/id_old".into()],
+            ..Default::default()
+        };
+        let mut app = AppState::new(vec![host], Settings::default());
+        let output = |code: i32, text: &str| std::process::Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: text.as_bytes().to_vec(),
+            stderr: Vec::new(),
+        };
+
+        with_env_var(home_var(), home.to_str().unwrap(), || {
+            app.submit_prompt(PromptKind::KeyPath, "~/.ssh/taken".into()).unwrap();
+            assert!(app.pending_keygen.is_none());
+            app.answer_confirm(true);
+            assert_eq!(app.pending_keygen.as_ref().map(|k| k.path.as_str()), Some("~/.ssh/taken"));
+
+            app.pending_keygen = None;
+            app.submit_prompt(PromptKind::KeyPath, " ~/.ssh/id_new ".into()).unwrap();
+            let keygen = app.pending_keygen.take().unwrap();
+            app.key_generated(keygen.clone(), Ok(output(1, "Saving key failed")));
+            assert_eq!(app.hosts[0].identity_files, ["~/.ssh/id_old"]);
+            assert!(app.status_message.take().unwrap().0.contains("Saving key failed"));
+
+            fs::write(home.join(".ssh/id_new"), "new key").unwrap();
+            app.key_generated(keygen, Ok(output(0, "The key fingerprint is: SHA256:abc")));
+            assert_eq!(app.hosts[0].identity_files, ["~/.ssh/id_new", "~/.ssh/id_old"]);
+            let status = app.status_message.take().unwrap().0;
+            assert!(status.starts_with("The key fingerprint is"), "{}", status);
+            assert!(status.contains("✔ Permissions fixed"), "{}", status);
+            let config = fs::read_to_string(home.join(".ssh/config")).unwrap();
+            assert!(config.contains("IdentityFile ~/.ssh/id_new"), "{}", config);
+        });
+
+        // A keygen that can't start or fails keeps the key it would replace.
+        let taken = home.join(".ssh/taken");
+        fs::write(home.join(".ssh/taken.pub"), "old public key").unwrap();
+        assert!(run_keygen("putty-test-no-such-program", &taken, "web").is_err());
+        assert!(!run_keygen("false", &taken, "web").unwrap().status.success());
+        assert_eq!(fs::read_to_string(&taken).unwrap(), "old key");
+        assert_eq!(fs::read_to_string(home.join(".ssh/taken.pub")).unwrap(), "old public key");
+        // Once it succeeds the old pair is gone, not left aside.
+        assert!(run_keygen("true", &taken, "web").unwrap().status.success());
+        assert!(!taken.exists() && !home.join(".ssh/taken.putty-old").exists());
+        assert!(!home.join(".ssh/taken.pub.putty-old").exists());
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn copying_a_key_prefers_ssh_copy_id_and_falls_back_to_ssh() {
+        let dir = std::env::temp_dir().join(format!("putty-copyid-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = dir.join("id_ed25519");
+        let mut host = HostEntry {
+            name: "web".into(),
+            identity_files: vec![key.display().to_string()],
+            ..Default::default()
+        };
+        let missing = copy_id_command(&host, true, &[]).unwrap_err();
+        assert!(missing.starts_with("No public key at"), "{}", missing);
+
+        let public = dir.join("id_ed25519.pub");
+        fs::write(&public, "ssh-ed25519 AAAA web").unwrap();
+        let copy = copy_id_command(&host, true, &[]).unwrap();
+        assert_eq!(copy.command, ["ssh-copy-id", "-i", &public.display().to_string(), "web"]);
+        assert_eq!(copy.input, None);
+
+        let copy = copy_id_command(&host, false, &[]).unwrap();
+        assert_eq!(copy.command[..2], ["ssh", "web"]);
+        assert!(copy.command[2].ends_with("cat >> ~/.ssh/authorized_keys"));
+        assert_eq!(copy.input, Some(public));
+
+        host.identity_files.clear();
+        assert_eq!(copy_id_command(&host, true, &[]).unwrap_err(), no_identity_file(&host));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_log_viewer_shows_the_newest_connections_readably() {
+        let home = std::env::temp_dir().join(format!("putty-logview-{}", std::process::id()));
+        fs::create_dir_all(home.join(".ssh")).unwrap();
+        let host = HostEntry {
+            name: "web \"eu\"".into(),
+            hostname: Some("10.0.0.5".into()),
+            ..Default::default()
+        };
+        let mut app = AppState::new(vec![host.clone()], Settings::default());
+        with_env_var(home_var(), home.to_str().unwrap(), || {
+            app.open_log();
+            assert_eq!(app.status_message.take().unwrap().0, "No connections logged yet.");
+
+            for i in 0..LOG_VIEW_LINES {
+                append_connect_log("connect", &host, &["ssh".into()], Some(i as i32)).unwrap();
+            }
+            append_connect_log("mosh", &host, &["mosh".into()], None).unwrap();
+            app.open_log();
+        });
+        let lines = app.log_view.unwrap();
+        assert_eq!(lines.len(), LOG_VIEW_LINES);
+        assert!(lines[0].ends_with("connect  web \"eu\" (10.0.0.5)  exit 1"), "{}", lines[0]);
+        assert!(lines.last().unwrap().ends_with("mosh     web \"eu\" (10.0.0.5)  didn't start"));
+        assert!(lines[0].contains(" UTC  "), "{}", lines[0]);
+        assert_eq!(describe_log_line("not json"), "not json");
+        assert!(Settings::parse("connect_log = false").no_connect_log);
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn session_outcomes_tell_a_failed_connection_from_a_logout() {
+        use std::os::unix::process::ExitStatusExt;
+        let exited = |code: i32| std::process::ExitStatus::from_raw(code << 8);
+        assert_eq!(session_outcome("ssh", "web", exited(0)), "✔ Session to web exited normally.");
+        assert_eq!(session_outcome("ssh", "web", exited(1)), "⚠ Session to web exited with code 1.");
+        assert!(session_outcome("sftp", "web", exited(255)).starts_with("❌ sftp couldn't connect to web (exit 255)."));
+        assert_eq!(
+            session_outcome("ssh", "web", std::process::ExitStatus::from_raw(9)),
+            "⚠ Session to web was ended by a signal."
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn k_fixes_each_shared_key_file_once_and_reports_failures() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("putty-secure-all-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let shared = dir.join("shared");
+        fs::write(&shared, "secret").unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o644)).unwrap();
+        let host = |name: &str, files: &[PathBuf]| HostEntry {
+            name: name.into(),
+            identity_files: files.iter().map(|f| f.display().to_string()).collect(),
+            ..Default::default()
+        };
+        let hosts = vec![
+            host("web", std::slice::from_ref(&shared)),
+            host("db", &[shared.clone(), dir.join("missing")]),
+            host("bare", &[]),
+        ];
+        let mut app = AppState::new(hosts, Settings::default());
+
+        app.ask_secure_key_files(false);
+        let prompt = app.confirm.as_ref().unwrap().prompt.clone();
+        assert!(prompt.starts_with("Restrict these 2 key files"), "{}", prompt);
+        assert!(prompt.contains(&format!("{} (web, db)", shared.display())), "{}", prompt);
+        app.answer_confirm(true);
+
+        assert_eq!(fs::metadata(&shared).unwrap().permissions().mode() & 0o777, 0o600);
+        let report = app.status_message.unwrap().0;
+        assert!(report.starts_with("⚠ Fixed 1 of 2 key files; 1 failed."), "{}", report);
+        assert!(report.contains(&format!("Fixed:\n  {}", shared.display())), "{}", report);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fixing_keys_on_a_host_without_any_says_so() {
+        let host = HostEntry { name: "web".into(), ..Default::default() };
+        let mut app = AppState::new(vec![host], Settings::default());
+        app.ask_secure_keys();
+        assert!(app.confirm.is_none());
+        assert_eq!(app.status_message.unwrap().0, "No IdentityFile configured for web.");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_quoted_key_path_with_spaces_gets_its_permissions_fixed() {
+        let key = std::env::temp_dir().join(format!("putty key {}", std::process::id()));
+        fs::write(&key, "secret").unwrap();
+        let hosts = HostEntry::parse_ssh_config(&format!("Host a\n    IdentityFile \"{}\"\n", key.display()));
+        let report = secure_keyfile(&hosts[0].identity_files[0]);
+        assert!(report.starts_with("✔ Permissions fixed"), "{}", report);
+        fs::remove_file(&key).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keys_readable_by_others_are_flagged_until_fixed() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("putty-exposed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (open, private) = (dir.join("open"), dir.join("private"));
+        for (path, mode) in [(&open, 0o644), (&private, 0o600)] {
+            fs::write(path, "secret").unwrap();
+            fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+        }
+        let host = |name: &str, key: &Path| HostEntry {
+            name: name.into(),
+            identity_files: vec![key.display().to_string()],
+            ..Default::default()
+        };
+        let mut app = AppState::new(vec![host("a", &open), host("b", &private)], Settings::default());
+        app.check_keys();
+        assert!(app.has_exposed_key(&app.hosts[0]));
+        assert!(!app.has_exposed_key(&app.hosts[1]));
+        assert!(!key_is_exposed(&dir.join("missing")));
+
+        app.selected = 0;
+        app.ask_secure_keys();
+        app.answer_confirm(true);
+        assert!(!app.has_exposed_key(&app.hosts[0]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn broad_groups_in_icacls_output_are_spotted() {
+        let owner_only = "C:\\Users\\me\\.ssh\\id_rsa ME\\me:(F)\n\nSuccessfully processed 1 files";
+        assert!(!acl_is_broad(owner_only));
+        assert!(acl_is_broad("C:\\key BUILTIN\\Users:(RX)\n               ME\\me:(F)"));
+        assert!(acl_is_broad("C:\\key Everyone:(R)"));
+    }
+
+    #[test]
+    fn trailing_comments_are_split_off_values_and_written_back() {
+        let config = "\
+Host web  # the main one
+    HostName 10.0.0.1
+    Port 2222  # non-standard
+    IdentityFile \"C:\\#keys\\id\"  # quoted
+    ForwardAgent yes # needed for deploys
+    User pass#word
+    ServerAliveInterval 30  # keepalive
+";
+        let host = HostEntry::parse_ssh_config(config).remove(0);
+        assert_eq!(host.name, "web");
+        assert_eq!(host.port.as_deref(), Some("2222"));
+        assert_eq!(host.identity_files, ["C:\\#keys\\id"]);
+        assert_eq!(host.forward_agent, Some(true));
+        assert_eq!(host.user.as_deref(), Some("pass#word"));
+        assert_eq!(host.raw_lines, ["    ServerAliveInterval 30  # keepalive"]);
+
+        let written = serialize_block(&host, true);
+        for line in [
+            "Host web  # the main one\n",
+            "    Port 2222  # non-standard\n",
+            "    IdentityFile C:\\#keys\\id  # quoted\n",
+            "    ForwardAgent yes  # needed for deploys\n",
+            "    User pass#word\n",
+        ] {
+            assert!(written.contains(line), "missing {:?} in\n{}", line, written);
+        }
+        assert_eq!(HostEntry::parse_ssh_config(&written), std::slice::from_ref(&host));
+
+        // A changed value loses the note about the old one; an edited raw
+        // directive keeps its own.
+        let mut edit = EditState::new(host, Some(0));
+        select(&mut edit, "Port");
+        *edit.current_value_mut().unwrap() = "22".to_string();
+        select(&mut edit, "ServerAliveInterval");
+        assert_eq!(edit.current_row().value, "30");
+        *edit.current_value_mut().unwrap() = "60".to_string();
+        let saved = edit.finished_host();
+        let written = serialize_block(&saved, true);
+        assert!(written.contains("    Port 22\n"), "{}", written);
+        assert_eq!(saved.raw_lines, ["    ServerAliveInterval 60  # keepalive"]);
+    }
+
+    #[test]
+    fn match_blocks_are_kept_whole_and_apart_from_hosts() {
+        let config = "\
+Host web
+    HostName 10.0.0.1
+
+Match host web user root  # admins only
+    User admin
+    # audited
+    IdentityFile ~/.ssh/root_key
+
+Host db
+    User postgres
+";
+        let hosts = HostEntry::parse_ssh_config(config);
+        assert_eq!(hosts.len(), 3);
+        assert_eq!(hosts[0].user, None);
+        assert!(hosts[1].match_block);
+        assert_eq!(hosts[1].name, "host web user root");
+        assert_eq!(hosts[1].identity_files, Vec::<String>::new());
+        assert_eq!(hosts[1].raw_lines, ["    User admin", "    # audited", "    IdentityFile ~/.ssh/root_key"]);
+        assert!(!hosts[1].is_connectable());
+        assert_eq!(hosts[2].user.as_deref(), Some("postgres"));
+        assert_eq!(serialize_hosts("", &hosts, true), config.to_string() + "\n");
+
+        // Sorting on save keeps hosts on their side of the block.
+        let settings = Settings { sort_on_save: true, ..Default::default() };
+        let mut reversed = hosts.clone();
+        reversed.swap(0, 2);
+        let order: Vec<String> = hosts_for_save(&reversed, &settings).into_iter().map(|h| h.name).collect();
+        assert_eq!(order, ["db", "host web user root", "web"]);
+
+        let mut app = AppState::new(hosts, Settings::default());
+        app.selected = 1;
+        app.connect_selected();
+        assert!(app.pending_launch.is_none());
+        assert_eq!(app.flash.unwrap().0, MATCH_READ_ONLY);
+        assert!(uses_host(KeyEvent::new(KeyCode::Char('e'), KeyModifiers::NONE)));
+        assert!(!uses_host(KeyEvent::new(KeyCode::Char('d'), KeyModifiers::NONE)));
+    }
+
+    #[test]
+    fn split_comment_only_cuts_at_a_word_starting_with_a_hash() {
+        assert_eq!(split_comment("2222"), ("2222", None));
+        assert_eq!(split_comment("2222  # x"), ("2222", Some("# x")));
+        assert_eq!(split_comment("#x"), ("", Some("#x")));
+        assert_eq!(split_comment("a#b c"), ("a#b c", None));
+        assert_eq!(split_comment("\"a #b\" #c"), ("\"a #b\"", Some("#c")));
+    }
+
+    #[test]
+    fn unknown_directives_are_kept_verbatim() {
+        let hosts = HostEntry::parse_ssh_config(
+            "Host jump\n    HostName 10.0.0.1\n    ProxyJump bastion\n\tForwardAgent yes\n    # managed by ops\n    UserKnownHostsFile /dev/null\n",
+        );
+        let host = &hosts[0];
+        assert_eq!(host.user, None);
+        assert_eq!(host.proxy_jump.as_deref(), Some("bastion"));
+        assert_eq!(host.forward_agent, Some(true));
+        assert_eq!(
+            host.raw_lines,
+            vec![
+                "    # managed by ops",
+                "    UserKnownHostsFile /dev/null",
+            ]
+        );
+    }
+
+    #[test]
+    fn keywords_are_case_insensitive() {
+        let hosts = HostEntry::parse_ssh_config("host web\n    hostname 10.0.0.1\n    USER deploy\n    pOrT 2222\n");
+        assert_eq!(hosts[0].name, "web");
+        assert_eq!(hosts[0].hostname.as_deref(), Some("10.0.0.1"));
+        assert_eq!(hosts[0].user.as_deref(), Some("deploy"));
+        assert_eq!(hosts[0].port.as_deref(), Some("2222"));
+    }
+
+    #[test]
+    fn tabs_and_equals_separate_values() {
+        let hosts = HostEntry::parse_ssh_config(
+            "Host=web\n\tHostName\t10.0.0.1\n    User=deploy\n    Port = 2222\n    IdentityFile\t= ~/.ssh/id_ed25519\n",
+        );
+        let host = &hosts[0];
+        assert_eq!(host.name, "web");
+        assert_eq!(host.hostname.as_deref(), Some("10.0.0.1"));
+        assert_eq!(host.user.as_deref(), Some("deploy"));
+        assert_eq!(host.port.as_deref(), Some("2222"));
+        assert_eq!(host.identity_files, vec!["~/.ssh/id_ed25519"]);
+        assert!(host.raw_lines.is_empty());
+    }
+
+    #[test]
+    fn every_identity_file_survives_a_round_trip() {
+        let config = "Host web\n    IdentityFile ~/.ssh/id_ed25519\n    IdentityFile ~/.ssh/id_rsa\n\n";
+        let hosts = HostEntry::parse_ssh_config(config);
+        assert_eq!(hosts[0].identity_files, vec!["~/.ssh/id_ed25519", "~/.ssh/id_rsa"]);
+        assert_eq!(serialize_hosts("", &hosts, true), config);
+    }
+
+    #[test]
+    fn blank_identity_file_rows_are_not_saved() {
+        let mut edit = EditState::new(HostEntry { name: "web".into(), ..Default::default() }, None);
+        select(&mut edit, "IdentityFile");
+        let first = edit.field_index;
+        edit.current_value_mut().unwrap().push_str("~/.ssh/a");
+        edit.add_identity_file();
+        assert_eq!((edit.field_index, edit.current_row().directive.as_str()), (first + 1, "IdentityFile"));
+        assert_eq!(edit.finished_host().identity_files, vec!["~/.ssh/a"]);
+        edit.remove_row();
+        assert_eq!(edit.field_index, first);
+        assert_eq!(edit.finished_host().identity_files, vec!["~/.ssh/a"]);
+        // The last IdentityFile row stays, emptied.
+        edit.remove_row();
+        assert_eq!(edit.current_row().directive, "IdentityFile");
+        assert_eq!(edit.finished_host().identity_files, Vec::<String>::new());
+    }
+
+    #[test]
+    fn unmodelled_directives_are_editable_rows() {
+        let config = "Host jump\n    HostName 10.0.0.1\n    # ops\n\tProxyJump bastion\n    LogLevel QUIET\n    AddKeysToAgent no\n";
+        let host = HostEntry::parse_ssh_config(config).remove(0);
+        let mut edit = EditState::new(host.clone(), Some(0));
+        let labels: Vec<&str> = edit.rows.iter().map(|r| r.label()).collect();
+        assert_eq!(
+            labels,
+            vec![
+                "Host", "HostName", "User", "Port", "IdentityFile", "ProxyJump", "ConnectTimeout", "ForwardAgent",
+                "ForwardX11", "Compression", "Password", "Env", "SshOption", "LogLevel", "AddKeysToAgent",
+            ]
+        );
+        assert!(!edit.is_dirty());
+        assert_eq!(edit.finished_host(), host);
+
+        select(&mut edit, "AddKeysToAgent");
+        *edit.current_value_mut().unwrap() = "yes".to_string();
+        select(&mut edit, "LogLevel");
+        edit.remove_row();
+        edit.add_directive();
+        edit.current_value_mut().unwrap().push_str("ServerAliveInterval 30");
+        let saved = edit.finished_host();
+        assert_eq!(
+            saved.raw_lines,
+            vec!["    # ops", "    AddKeysToAgent yes", "    ServerAliveInterval 30"]
+        );
+        assert_eq!(saved.proxy_jump.as_deref(), Some("bastion"));
+    }
+
+    #[test]
+    fn yes_no_directives_parse_and_write_as_switches() {
+        let config = "Host a\n    forwardagent Yes\n    ForwardX11 no\n    Compression=yes\n";
+        let host = HostEntry::parse_ssh_config(config).remove(0);
+        assert_eq!((host.forward_agent, host.forward_x11, host.compression), (Some(true), Some(false), Some(true)));
+        assert!(host.raw_lines.is_empty());
+        assert_eq!(
+            serialize_block(&host, true),
+            "Host a\n    ForwardAgent yes\n    ForwardX11 no\n    Compression yes\n"
+        );
+    }
+
+    #[test]
+    fn other_values_for_a_switch_are_kept_as_written() {
+        let config = "Host a\n    ForwardAgent $SSH_AUTH_SOCK\n    ForwardX11 ask\n";
+        let host = HostEntry::parse_ssh_config(config).remove(0);
+        assert_eq!((host.forward_agent, host.forward_x11), (None, None));
+        assert_eq!(host.raw_lines, ["    ForwardAgent $SSH_AUTH_SOCK", "    ForwardX11 ask"]);
+        assert_eq!(serialize_block(&host, true), config);
+
+        // They're edited as text; the switches without a line are toggles.
+        let mut edit = EditState::new(host, Some(0));
+        select(&mut edit, "ForwardAgent");
+        assert!(!edit.current_row().is_choice());
+        select(&mut edit, "Compression");
+        assert!(edit.current_row().is_choice());
+        assert!(edit.current_value_mut().is_none());
+    }
+
+    #[test]
+    fn space_cycles_a_switch_through_yes_no_and_unset() {
+        let host = HostEntry { name: "a".into(), compression: Some(false), ..Default::default() };
+        let mut edit = EditState::new(host, Some(0));
+        select(&mut edit, "ForwardX11");
+        edit.cycle_choice();
+        assert_eq!(edit.finished_host().forward_x11, Some(true));
+        edit.cycle_choice();
+        assert_eq!(edit.finished_host().forward_x11, Some(false));
+        edit.cycle_choice();
+        assert_eq!(edit.finished_host().forward_x11, None);
+
+        select(&mut edit, "Compression");
+        assert_eq!(edit.current_row().value, "no");
+        edit.cycle_choice();
+        assert_eq!(edit.finished_host().compression, None);
+        assert!(!serialize_block(&edit.finished_host(), true).contains("Compression"));
+    }
+
+    #[test]
+    fn proxy_jump_is_edited_as_one_comma_separated_field() {
+        let host = HostEntry { name: "db".into(), ..Default::default() };
+        let mut edit = EditState::new(host, Some(0));
+        select(&mut edit, "ProxyJump");
+        edit.paste("bastion1,ops@bastion2:2222");
+        edit.insert_char(' ');
+        assert_eq!(edit.warning.as_deref(), Some("Separate ProxyJump hops with commas, not spaces"));
+        let saved = edit.finished_host();
+        assert_eq!(saved.proxy_jump.as_deref(), Some("bastion1,ops@bastion2:2222"));
+        assert!(serialize_block(&saved, true).contains("\n    ProxyJump bastion1,ops@bastion2:2222\n"));
+
+        let app = AppState::new(vec![saved], Settings::default());
+        let details: Vec<String> = host_details(&app, 0).iter().map(|l| l.to_string()).collect();
+        assert!(details.contains(&"ProxyJump: bastion1,ops@bastion2:2222 (2 hops)".to_string()), "{:?}", details);
+
+        select(&mut edit, "ProxyJump");
+        edit.remove_row();
+        assert_eq!(edit.finished_host().proxy_jump, None);
+    }
+
+    #[test]
+    fn preamble_survives_editing_a_later_host() {
+        let preamble = "# global options\nAddKeysToAgent yes\n\nHost *\n\tServerAliveInterval 60\n\n";
+        let config = format!("{}Host web\n    HostName 10.0.0.1\n\nHost db\n    HostName 10.0.0.2\n", preamble);
+
+        let (head, rest) = HostEntry::split_preamble(&config);
+        assert_eq!(head, preamble);
+        let mut hosts = HostEntry::parse_ssh_config(rest);
+        assert_eq!(hosts.len(), 2);
+        hosts[1].user = Some("admin".to_string());
+
+        let written = serialize_hosts(head, &hosts, true);
+        assert_eq!(&written.as_bytes()[..preamble.len()], preamble.as_bytes());
+        let (_, rest) = HostEntry::split_preamble(&written);
+        assert_eq!(HostEntry::parse_ssh_config(rest)[1].user.as_deref(), Some("admin"));
+    }
+
+    #[test]
+    fn disabled_hosts_are_commented_out_and_read_back() {
+        let config = "Host web\n    HostName 10.0.0.1\n    # Env prod\n    ForwardAgent yes\n\nHost db\n    User admin\n\n";
+        let mut hosts = HostEntry::parse_ssh_config(config);
+        hosts[0].disabled = true;
+        let written = serialize_hosts("# top\n", &hosts, true);
+        assert!(written.starts_with("# top\n#Host web\n#    HostName 10.0.0.1\n#    ForwardAgent yes\n#    # Env prod\n\nHost db\n"));
+
+        let (head, rest) = HostEntry::split_preamble(&written);
+        assert_eq!(head, "# top\n");
+        let read = HostEntry::parse_ssh_config(rest);
+        assert_eq!(read, hosts);
+        assert_eq!(serialize_hosts(head, &read, true), written);
+    }
+
+    #[test]
+    fn a_commented_host_line_followed_by_directives_is_just_a_comment() {
+        let config = "Host web\n    HostName 10.0.0.1\n# Host old\n    User admin\n";
+        let hosts = HostEntry::parse_ssh_config(config);
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].raw_lines, ["# Host old"]);
+        assert_eq!(hosts[0].user.as_deref(), Some("admin"));
+        let by_hand = HostEntry::parse_ssh_config("# Host old\n#   HostName 10.0.0.9\n");
+        assert!(by_hand[0].disabled);
+        assert_eq!(by_hand[0].hostname.as_deref(), Some("10.0.0.9"));
+    }
+
+    #[test]
+    fn legacy_plain_password_still_loads() {
+        let hosts = HostEntry::parse_ssh_config("Host box\n    # Password 100%sure\n");
+        assert_eq!(hosts[0].password.as_deref(), Some("100%sure"));
+    }
+
+    #[test]
+    fn keyring_password_is_never_written_to_the_config() {
+        let mut hosts = HostEntry::parse_ssh_config("Host box\n    # Password (in keyring)\n");
+        assert!(hosts[0].password_in_keyring);
+        assert_eq!(hosts[0].password, None);
+
+        // As if load_keyring_passwords had filled it in.
+        hosts[0].password = Some("hunter2".to_string());
+        let written = serialize_hosts("", &hosts, true);
+        assert!(written.contains("# Password (in keyring)"));
+        assert!(!written.contains("hunter2"));
+
+        // A password that merely looks like the marker is encoded, not mistaken for it.
+        let lookalike = HostEntry { name: "b".into(), password: Some(KEYRING_MARKER.into()), ..Default::default() };
+        let parsed = HostEntry::parse_ssh_config(&serialize_hosts("", &[lookalike], true));
+        assert!(!parsed[0].password_in_keyring);
+        assert_eq!(parsed[0].password.as_deref(), Some(KEYRING_MARKER));
+    }
+
+    #[test]
+    fn renaming_a_host_stores_its_password_again() {
+        let host = HostEntry {
+            name: "box".into(),
+            password: Some("hunter2".into()),
+            password_in_keyring: true,
+            ..Default::default()
+        };
+        let mut edit = EditState::new(host, Some(0));
+        select(&mut edit, "User");
+        edit.current_value_mut().unwrap().push_str("root");
+        assert!(edit.finished_host().password_in_keyring);
+
+        select(&mut edit, "Host");
+        edit.current_value_mut().unwrap().push('2');
+        assert!(!edit.finished_host().password_in_keyring);
+    }
+
+    #[test]
+    fn command_line_flags_parse() {
+        let args = |list: &[&str]| parse_args(list.iter().map(|s| s.to_string()));
+        assert_eq!(args(&[]), Ok(Args::default()));
+        assert_eq!(
+            args(&["--config", "/tmp/other", "--connect=web"]),
+            Ok(Args { config: Some("/tmp/other".into()), connect: Some("web".into()), ..Default::default() })
+        );
+        assert_eq!(args(&["-F", "/tmp/other"]), Ok(Args { config: Some("/tmp/other".into()), ..Default::default() }));
+        assert_eq!(args(&["--read-only"]), Ok(Args { read_only: true, ..Default::default() }));
+        assert_eq!(args(&["--connect"]), Err("--connect needs a value".to_string()));
+        assert_eq!(args(&["--bogus"]), Err("unknown argument --bogus".to_string()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_hostname_without_a_clipboard_is_printed_on_quit() {
+        let host = HostEntry { name: "web".into(), hostname: Some("10.0.0.1, 10.0.0.2".into()), ..Default::default() };
+        let mut app = AppState::new(vec![host, HostEntry { name: "db".into(), ..Default::default() }], Settings::default());
+        with_env_var("PATH", "", || {
+            app.copy_hostname();
+            app.selected = 1;
+            app.copy_hostname();
+        });
+        assert_eq!(app.print_on_exit, ["10.0.0.1", "db"]);
+        assert!(app.status_message.unwrap().0.contains("db will be printed when you quit"));
+    }
+
+    #[test]
+    fn export_leaves_passwords_out_unless_asked() {
+        let host = |name: &str| HostEntry {
+            name: name.into(),
+            hostname: Some("10.0.0.1".into()),
+            password: Some("hunter2".into()),
+            password_in_keyring: true,
+            ..Default::default()
+        };
+        let mut app = AppState::new(vec![host("a"), host("b"), host("c")], Settings::default());
+        app.selected = 1;
+        assert_eq!(app.export_text(false), "Host b\n    HostName 10.0.0.1\n\n");
+        assert!(app.export_text(true).contains("# Password hunter2\n"));
+
+        app.marked = vec!["c".into(), "a".into()];
+        let text = app.export_text(false);
+        let names: Vec<_> = HostEntry::parse_ssh_config(&text).into_iter().map(|h| h.name).collect();
+        assert_eq!(names, ["c", "a"]);
+    }
+
+    #[test]
+    fn import_skips_duplicates_and_renames_clashes() {
+        let mut hosts = HostEntry::parse_ssh_config("Host web\n    HostName 10.0.0.1\n\nHost *\n    User me\n");
+        let imported = HostEntry::parse_ssh_config(
+            "Host web\n    HostName 10.0.0.1\n\nHost db\n    HostName 10.0.0.2\n\n\
+             Host web\n    HostName 10.9.9.9\n\nHost *\n    User other\n",
+        );
+        let summary = merge_imported(&mut hosts, imported);
+        assert_eq!(
+            summary,
+            ImportSummary {
+                added: vec!["db".into(), "web-copy".into()],
+                renamed: vec![("web".into(), "web-copy".into())],
+                skipped: vec!["web".into(), "*".into()],
+            }
+        );
+        let names: Vec<&str> = hosts.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(names, ["web", "*", "db", "web-copy"]);
+        assert_eq!(hosts[3].hostname.as_deref(), Some("10.9.9.9"));
+        assert!(summary.to_string().starts_with("Imported 2 host(s), skipped 2."));
+    }
+
+    #[test]
+    fn digits_pick_the_numbered_host_and_optionally_connect() {
+        let host = |name: &str, group: &str| HostEntry {
+            name: name.into(),
+            group: Some(group.into()),
+            ..Default::default()
+        };
+        let hosts = vec![host("web", "Front"), host("db", "Back"), host("cache", "Front")];
+        let mut app = AppState::new(hosts.clone(), Settings::default());
+        // Headers aren't numbered: 1 web, 2 cache, 3 db.
+        assert_eq!(app.quick_hosts(), [0, 2, 1]);
+        app.quick_select(3);
+        assert_eq!((app.selected, app.pending_launch.is_some()), (1, false));
+        app.quick_select(9);
+        assert_eq!(app.selected, 1);
+
+        let mut app = AppState::new(hosts, Settings::parse("digit_connects = true"));
+        app.quick_select(2);
+        assert_eq!(app.pending_launch.map(|l| l.index), Some(2));
+    }
+
+    #[test]
+    fn groups_become_collapsible_sections() {
+        let config = "Host loose\n\nHost web\n    # Group: production\n\nHost dev1\n    # Group staging\n\n\
+                      Host db\n    # Group: production\n";
+        let hosts = HostEntry::parse_ssh_config(config);
+        assert_eq!(hosts[1].group.as_deref(), Some("production"));
+        assert_eq!(hosts[2].group.as_deref(), Some("staging"));
+        assert!(serialize_hosts("", &hosts, true).contains("Host web\n    # Group: production\n"));
+
+        let mut app = AppState::new(hosts, Settings::default());
+        let header = |group: &str, count| ListRow::Header { group: group.into(), count };
+        assert_eq!(
+            app.list_rows(),
+            [
+                header("production", 2),
+                ListRow::Host(1),
+                ListRow::Host(3),
+                header("staging", 1),
+                ListRow::Host(2),
+                header(UNGROUPED, 1),
+                ListRow::Host(0),
+            ]
+        );
+        assert_eq!(app.visible_indices(), [1, 3, 2, 0]);
+
+        app.selected = 3;
+        app.collapse_selected_group();
+        assert_eq!(app.list_rows()[0], ListRow::Collapsed { group: "production".into(), count: 2, first: 1 });
+        assert_eq!(app.selected, 1);
+        assert!(app.selected_host().is_none(), "actions must not reach a folded-away host");
+        app.move_selection(1);
+        assert_eq!(app.selected, 2);
+        app.move_selection(-1);
+        app.expand_selected_group();
+        assert_eq!(app.selected_host().map(|h| h.name.as_str()), Some("web"));
+    }
+
+    #[test]
+    fn fuzzy_search_finds_subsequences_and_ranks_substrings_first() {
+        assert_eq!(fuzzy_match("prdweb", "prod-web-01").map(|(_, p)| p), Some(vec![0, 1, 3, 5, 6, 7]));
+        assert_eq!(fuzzy_match("web", "Prod-WEB-01").map(|(_, p)| p), Some(vec![5, 6, 7]));
+        assert_eq!(fuzzy_match("bew", "prod-web-01"), None);
+
+        let host = |name: &str, hostname: &str| HostEntry {
+            name: name.into(),
+            hostname: Some(hostname.into()),
+            ..Default::default()
+        };
+        let hosts = vec![
+            host("prod-web-01", "10.0.0.1"),
+            host("webby", "10.0.0.2"),
+            host("db", "web.internal"),
+            host("cache", "10.0.0.4"),
+        ];
+        let mut app = AppState::new(hosts, Settings::default());
+        app.search = Some("web".into());
+        // Substrings rank by where they start; the HostName counts too.
+        assert_eq!(app.visible_indices(), [1, 2, 0]);
+        app.search = Some("prdweb".into());
+        assert_eq!(app.visible_indices(), [0]);
+        assert!(app.hosts[2].search_match("web").unwrap().in_hostname);
+    }
+
+    #[test]
+    fn selection_wraps_unless_told_to_stop_at_the_ends() {
+        let hosts: Vec<HostEntry> = ["a", "b", "c"].iter().map(|n| HostEntry { name: n.to_string(), ..Default::default() }).collect();
+        let mut app = AppState::new(hosts.clone(), Settings::default());
+        app.move_selection(-1);
+        assert_eq!(app.selected, 2);
+
+        let mut app = AppState::new(hosts, Settings::parse("wrap_around = false\n"));
+        app.move_selection(-1);
+        assert_eq!(app.selected, 0);
+        app.move_selection(5);
+        assert_eq!(app.selected, 2);
+        app.move_selection(1);
+        assert_eq!(app.selected, 2);
+    }
+
+    #[test]
+    fn keychain_commands_quote_the_password() {
+        assert_eq!(security_quote("web"), "\"web\"");
+        assert_eq!(security_quote(r#"p "a\ss"#), r#""p \"a\\ss""#);
+    }
+
+    #[test]
+    fn keyring_setting_parses() {
+        assert!(Settings::parse("keyring = yes\n").keyring);
+        assert!(!Settings::parse("sort_on_save = true\n").keyring);
+    }
+
+    #[test]
+    fn sshpass_is_opt_in_and_keeps_the_password_off_the_command_line() {
+        let host = HostEntry { name: "box".into(), password: Some("hunter2".into()), ..Default::default() };
+        let mut app = AppState::new(vec![host], Settings::default());
+        app.connect_selected();
+        assert_eq!(app.pending_launch.unwrap().command, ["ssh", "-o", "ConnectTimeout=10", "box"]);
+
+        let command = sshpass_command(ssh_command(&app.hosts[0], &app.settings, &BTreeSet::new()));
+        assert_eq!(command, ["sshpass", "-e", "ssh", "-o", "ConnectTimeout=10", "box"]);
+        assert!(Settings::parse("sshpass = on\n").sshpass);
     }
 }